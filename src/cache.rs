@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::coalesce::SharedResponse;
+
+/// Model ids discovered from each provider, by `Provider::model_cache_key`, reused until they are older than the TTL
+#[derive(Default)]
+pub struct ModelListCache {
+    entries: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+    /// When discovery of each provider was last started, whether it succeeded or not
+    last_fetch: Mutex<HashMap<String, Instant>>,
+    /// Held while a provider's models are fetched, so concurrent requests wait for one fetch
+    fetching: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl ModelListCache {
+    /// Models of `provider` discovered less than `ttl` ago
+    pub fn get(&self, provider: &str, ttl: Duration) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(provider)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < ttl)
+            .map(|(_, models)| models.clone())
+    }
+
+    pub fn insert(&self, provider: String, models: Vec<String>) {
+        self.entries.lock().unwrap().insert(provider, (Instant::now(), models));
+    }
+
+    /// Models of `provider` stored at or after `since`, i.e. by a fetch that finished meanwhile
+    pub fn get_since(&self, provider: &str, since: Instant) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(provider)
+            .filter(|(fetched_at, _)| *fetched_at >= since)
+            .map(|(_, models)| models.clone())
+    }
+
+    /// Forget every discovered model list, e.g. once providers may have changed
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+        self.last_fetch.lock().unwrap().clear();
+    }
+
+    /// Lock to hold while fetching the models of `provider`
+    pub fn fetch_lock(&self, provider: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.fetching.lock().unwrap().entry(provider.to_string()).or_default().clone()
+    }
+
+    /// Whether `provider` may be fetched now, allowing at most one fetch every `interval`
+    pub fn claim_fetch(&self, provider: &str, interval: Duration) -> bool {
+        let mut last_fetch = self.last_fetch.lock().unwrap();
+        if last_fetch.get(provider).is_some_and(|last| last.elapsed() < interval) {
+            return false;
+        }
+        last_fetch.insert(provider.to_string(), Instant::now());
+        true
+    }
+}
+
+struct CacheEntry {
+    response: SharedResponse,
+    stored_at: Instant,
+    expires_at: Instant,
+}
+
+/// Successful responses kept for reuse by identical requests, by request key
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl ResponseCache {
+    /// The response stored under `key`, unless it has expired
+    pub fn get(&self, key: &str) -> Option<SharedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.response.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Store a response for `ttl`, making room by dropping expired entries and then the oldest one
+    pub fn insert(&self, key: String, response: SharedResponse, ttl: Duration, max_entries: usize) {
+        if max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if entries.len() >= max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= max_entries && !entries.contains_key(&key) {
+            let oldest = entries.iter().min_by_key(|(_, entry)| entry.stored_at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, CacheEntry { response, stored_at: now, expires_at: now + ttl });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_lists_expire_after_ttl() {
+        let cache = ModelListCache::default();
+        cache.insert("alpha".to_string(), vec!["m1".to_string()]);
+        assert_eq!(cache.get("alpha", Duration::from_secs(60)), Some(vec!["m1".to_string()]));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("alpha", Duration::from_millis(10)), None);
+        assert_eq!(cache.get("beta", Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn lists_stored_since_are_found() {
+        let cache = ModelListCache::default();
+        cache.insert("alpha".to_string(), vec!["m1".to_string()]);
+        let asked_at = Instant::now();
+        assert_eq!(cache.get_since("alpha", asked_at), None);
+        cache.insert("alpha".to_string(), vec!["m2".to_string()]);
+        assert_eq!(cache.get_since("alpha", asked_at), Some(vec!["m2".to_string()]));
+    }
+
+    #[test]
+    fn fetches_are_limited_per_provider() {
+        let cache = ModelListCache::default();
+        let interval = Duration::from_secs(60);
+        assert!(cache.claim_fetch("alpha", interval));
+        assert!(!cache.claim_fetch("alpha", interval));
+        assert!(cache.claim_fetch("beta", interval));
+        assert!(cache.claim_fetch("alpha", Duration::ZERO));
+    }
+
+    #[test]
+    fn providers_share_one_fetch_lock() {
+        let cache = ModelListCache::default();
+        let lock = cache.fetch_lock("alpha");
+        let _fetching = lock.try_lock().unwrap();
+        assert!(cache.fetch_lock("alpha").try_lock().is_err());
+        assert!(cache.fetch_lock("beta").try_lock().is_ok());
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+/// How long clients are asked to wait while a half-open breaker has no trial request left to give
+const PROBE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Circuit breaker settings, taken from the configuration
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerPolicy {
+    /// Consecutive failed attempts that open the breaker
+    pub threshold: u32,
+    /// How long the breaker stays open when first opened
+    pub cooldown: Duration,
+    /// Trial requests let through after the cooldown before the breaker closes; None closes it right away
+    pub probes: Option<u32>,
+    /// Longest cooldown reached by doubling it after failed trials
+    pub max_cooldown: Duration,
+}
+
+/// Where a breaker stands, as reported to admins and metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub const ALL: [BreakerState; 3] = [BreakerState::Closed, BreakerState::Open, BreakerState::HalfOpen];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Circuit breaker of one provider, opened by consecutive failures
+#[derive(Debug, Clone, Default)]
+pub struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// Cooldown of the current opening, grown by failed trials
+    cooldown: Option<Duration>,
+    /// Trial requests let through since the cooldown ended, and how many of them succeeded
+    probes_sent: u32,
+    probes_succeeded: u32,
+    last_probe_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// A successful attempt closes the breaker and resets the failure count
+    /// With half-open probes, the breaker only closes once every trial request has succeeded
+    pub fn record_success(&mut self, policy: &BreakerPolicy) {
+        self.consecutive_failures = 0;
+        if self.state(policy) == BreakerState::HalfOpen {
+            self.probes_succeeded += 1;
+            if self.probes_succeeded < policy.probes.unwrap_or(0) {
+                return;
+            }
+        }
+        *self = CircuitBreaker::default();
+    }
+
+    /// Count a failed attempt, opening the breaker once `threshold` is reached
+    /// A failed trial request reopens it right away, for twice the previous cooldown
+    pub fn record_failure(&mut self, policy: &BreakerPolicy) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let cooldown = match self.state(policy) {
+            BreakerState::HalfOpen => self.cooldown.unwrap_or(policy.cooldown)
+                .saturating_mul(2)
+                .min(policy.max_cooldown.max(policy.cooldown)),
+            BreakerState::Closed if self.consecutive_failures >= policy.threshold => policy.cooldown,
+            // Attempts that were already in flight when the breaker opened don't extend it
+            _ => return,
+        };
+        self.open_until = Some(Instant::now() + cooldown);
+        self.cooldown = Some(cooldown);
+        self.probes_sent = 0;
+        self.probes_succeeded = 0;
+        self.last_probe_at = None;
+    }
+
+    /// Take one of the half-open breaker's trial requests, false when none is left
+    pub fn claim_probe(&mut self, policy: &BreakerPolicy) -> bool {
+        if self.state(policy) != BreakerState::HalfOpen {
+            return true;
+        }
+        if !self.probes_left(policy) {
+            return false;
+        }
+        // Trials that never reported back are given up, so the breaker can't stay stuck
+        if self.probes_sent >= policy.probes.unwrap_or(0) {
+            self.probes_sent = self.probes_succeeded;
+        }
+        self.probes_sent += 1;
+        self.last_probe_at = Some(Instant::now());
+        true
+    }
+
+    /// Whether a half-open breaker can let another trial request through
+    /// Trials still unanswered one cooldown after the last one was sent no longer hold their place
+    fn probes_left(&self, policy: &BreakerPolicy) -> bool {
+        self.probes_sent < policy.probes.unwrap_or(0)
+            || self.last_probe_at.is_some_and(|at| at.elapsed() > self.cooldown.unwrap_or(policy.cooldown))
+    }
+
+    pub fn state(&self, policy: &BreakerPolicy) -> BreakerState {
+        match self.open_until {
+            Some(until) if until > Instant::now() => BreakerState::Open,
+            // Without probes, an expired breaker simply lets requests through again
+            Some(_) if policy.probes.is_some() => BreakerState::HalfOpen,
+            _ => BreakerState::Closed,
+        }
+    }
+
+    /// How long the breaker stays open, or None when requests may go through
+    pub fn open_for(&self, policy: &BreakerPolicy) -> Option<Duration> {
+        match self.state(policy) {
+            BreakerState::Open => self.open_until.map(|until| until.saturating_duration_since(Instant::now())),
+            BreakerState::HalfOpen if !self.probes_left(policy) => Some(PROBE_RETRY_AFTER),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: BreakerPolicy = BreakerPolicy {
+        threshold: 2,
+        cooldown: Duration::from_millis(50),
+        probes: Some(2),
+        max_cooldown: Duration::from_millis(500),
+    };
+
+    /// Breaker opened by failures and left until its cooldown has passed
+    fn half_open() -> CircuitBreaker {
+        let mut breaker = CircuitBreaker::default();
+        breaker.record_failure(&POLICY);
+        assert_eq!(breaker.state(&POLICY), BreakerState::Closed);
+        breaker.record_failure(&POLICY);
+        assert_eq!(breaker.state(&POLICY), BreakerState::Open);
+        assert!(breaker.open_for(&POLICY).is_some());
+        std::thread::sleep(POLICY.cooldown);
+        assert_eq!(breaker.state(&POLICY), BreakerState::HalfOpen);
+        breaker
+    }
+
+    #[test]
+    fn breakers_close_once_every_trial_succeeds() {
+        let mut breaker = half_open();
+        assert!(breaker.claim_probe(&POLICY));
+        assert!(breaker.claim_probe(&POLICY));
+        // Both trials are out, so further requests wait
+        assert!(!breaker.claim_probe(&POLICY));
+        assert_eq!(breaker.open_for(&POLICY), Some(PROBE_RETRY_AFTER));
+
+        breaker.record_success(&POLICY);
+        assert_eq!(breaker.state(&POLICY), BreakerState::HalfOpen);
+        breaker.record_success(&POLICY);
+        assert_eq!(breaker.state(&POLICY), BreakerState::Closed);
+        assert_eq!(breaker.open_for(&POLICY), None);
+    }
+
+    #[test]
+    fn failed_trials_reopen_for_a_longer_cooldown() {
+        let mut breaker = half_open();
+        assert!(breaker.claim_probe(&POLICY));
+        breaker.record_failure(&POLICY);
+        assert_eq!(breaker.state(&POLICY), BreakerState::Open);
+        assert!(breaker.open_for(&POLICY).unwrap() > POLICY.cooldown);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::http::{header::{HeaderName, HeaderValue}, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use tokio::sync::oneshot;
+
+type Waiters = Arc<Mutex<HashMap<String, Vec<oneshot::Sender<SharedResponse>>>>>;
+
+/// Buffered response handed to identical requests that arrived while it was in flight
+#[derive(Debug, Clone)]
+pub struct SharedResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+    /// Host of the provider that produced the response
+    pub provider: Option<String>,
+}
+
+impl SharedResponse {
+    /// Buffer a response so it can be sent to several clients
+    /// Returns the response rebuilt for its own client along with the shared copy
+    pub async fn capture(response: HttpResponse, provider: Option<String>) -> (HttpResponse, Option<SharedResponse>) {
+        let (response, body) = response.into_parts();
+        let Ok(body) = actix_web::body::to_bytes(body).await else {
+            return (HttpResponse::BadGateway().finish(), None);
+        };
+        let shared = SharedResponse {
+            status: response.status(),
+            headers: response.headers().iter().map(|(name, value)| (name.clone(), value.clone())).collect(),
+            body,
+            provider,
+        };
+        (shared.to_response(), Some(shared))
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.status);
+        for (name, value) in &self.headers {
+            builder.append_header((name.clone(), value.clone()));
+        }
+        builder.body(self.body.clone())
+    }
+}
+
+/// Identical requests in flight, by request key
+#[derive(Default)]
+pub struct Coalescer {
+    waiters: Waiters,
+}
+
+/// Role of a request among the identical requests in flight
+pub enum Flight {
+    /// No identical request is in flight, so this one goes upstream and shares its response
+    Leader(FlightGuard),
+    /// Receives the response of the identical request already in flight
+    /// The sender is dropped if that request fails or is cancelled
+    Follower(oneshot::Receiver<SharedResponse>),
+}
+
+impl Coalescer {
+    pub fn join(&self, key: String) -> Flight {
+        let mut waiters = self.waiters.lock().unwrap();
+        match waiters.get_mut(&key) {
+            Some(followers) => {
+                let (sender, receiver) = oneshot::channel();
+                followers.push(sender);
+                Flight::Follower(receiver)
+            }
+            None => {
+                waiters.insert(key.clone(), Vec::new());
+                Flight::Leader(FlightGuard { waiters: self.waiters.clone(), key })
+            }
+        }
+    }
+}
+
+/// Held by the leading request; dropping it without completing releases the followers empty-handed
+pub struct FlightGuard {
+    waiters: Waiters,
+    key: String,
+}
+
+impl FlightGuard {
+    /// Hand the leader's response to every follower
+    pub fn complete(self, response: &SharedResponse) {
+        let followers = self.waiters.lock().unwrap().remove(&self.key).unwrap_or_default();
+        for follower in followers {
+            let _ = follower.send(response.clone());
+        }
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.waiters.lock().unwrap().remove(&self.key);
+    }
+}
@@ -1,17 +1,606 @@
+use futures_util::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use crate::cache::ModelListCache;
+use crate::circuit::BreakerPolicy;
 
 /// Represents a model provider configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Provider {
+    /// Optional name used to refer to this provider in admin endpoints, defaults to its host
+    pub name: Option<String>,
     pub base_url: String,
+    #[serde(default)]
     pub api_key: String,
+    /// Optional keys for rotation, primary first; the others are tried when the active key gets a 401
+    /// If set, takes the place of `api_key`
+    #[serde(default)]
+    pub api_keys: Vec<String>,
     /// Optional static models configuration for this provider
     /// Can be either a simple string array or detailed ModelInfo objects
     /// If provided, these models will be used instead of fetching from the provider's /models endpoint
-    pub models: Option<Vec<String>>,
+    pub models: Option<Vec<StaticModel>>,
+    /// Optional prefix clients see this provider's models under, e.g. `openrouter` lists `gpt-4o` as `openrouter/gpt-4o`
+    pub alias_prefix: Option<String>,
+    /// Names clients see in place of some of this provider's models, e.g. `{ "gpt-4o-eu" = "gpt-4o" }`
+    /// An aliased model is listed only under its aliases, without `alias_prefix`
+    /// Settings keyed by model use these client-facing ids; only `models` lists the provider's own ids
+    #[serde(default)]
+    pub model_aliases: HashMap<String, String>,
+    /// Optional weight used to pick the primary provider for a model served by several providers
+    /// If no provider serving the model sets a weight, configuration order decides the primary
+    pub weight: Option<u32>,
+    /// Keep this provider in reserve: its models are discovered, but it is only tried once
+    /// every other provider serving the model is circuit-broken or has failed the request
+    #[serde(default)]
+    pub standby: bool,
+    /// Keep this provider out of normal selection and only try it after every other provider
+    /// for the model, standbys included, has failed or is unavailable; meant for a local fallback
+    #[serde(default)]
+    pub last_resort: bool,
+    /// Model sent to a `last_resort` provider in place of the requested one, letting it stand in for
+    /// models it doesn't serve, e.g. with a smaller local model
+    pub last_resort_model: Option<String>,
+    /// Optional priority used to order failover attempts once the primary provider has failed
+    /// Lower values are tried first; providers without it follow in configuration order
+    pub failover_priority: Option<u32>,
+    /// Optional policy for requests with `n > 1` when this provider does not support it
+    /// If not set, `n` is forwarded unchanged
+    pub unsupported_n: Option<UnsupportedNPolicy>,
+    /// Handling of the newer OpenAI `store` and `metadata` request fields, defaults to passing them through
+    #[serde(default)]
+    pub newer_fields: NewerFieldsPolicy,
+    /// Optional API version pinned on every request to this provider
+    /// If not set, no version header is sent
+    pub api_version: Option<String>,
+    /// Header carrying `api_version`, defaults to `OpenAI-Version`
+    pub api_version_header: Option<String>,
+    /// Rewrite the `model` field of responses back to the model name the client requested
+    #[serde(default)]
+    pub rewrite_response_model: bool,
+    /// Add an estimated `usage` object to non-streaming responses that lack one
+    #[serde(default)]
+    pub estimate_missing_usage: bool,
+    /// Remove the `model` field from forwarded requests, for providers that select the model by URL
+    #[serde(default)]
+    pub strip_model_field: bool,
+    /// Force JSON mode on every request forwarded to this provider
+    #[serde(default)]
+    pub force_json_mode: bool,
+    /// Translate `tools` and `tool_choice` into the legacy `functions` and `function_call`, and responses back
+    #[serde(default)]
+    pub legacy_functions: bool,
+    /// Headers sent with every completion request to this provider to enable its prompt caching,
+    /// e.g. `{ "anthropic-beta" = "prompt-caching-2024-07-31" }`
+    #[serde(default)]
+    pub prompt_cache_headers: HashMap<String, String>,
+    /// Add a `prompt_cache_key` hashed from the leading system messages, so requests sharing them hit the same upstream cache
+    #[serde(default)]
+    pub prompt_cache_key: bool,
+    /// Optional price per million tokens of each model served by this provider, by client-facing id, used by cost routing
+    #[serde(default)]
+    pub model_prices: HashMap<String, f64>,
+    /// Capabilities (e.g. `vision`) of each model served by this provider, by client-facing id, for models without static ModelInfo
+    #[serde(default)]
+    pub model_capabilities: HashMap<String, Vec<String>>,
+    /// Optional system prompt template prepended to chat messages sent to this provider,
+    /// for models without one in the top-level `system_prompts`
+    pub system_prompt: Option<String>,
+    /// Default request parameters (e.g. `temperature`) merged into forwarded bodies that omit them
+    #[serde(default)]
+    pub default_params: serde_json::Map<String, serde_json::Value>,
+    /// Nonstandard 2xx or 3xx statuses of this provider passed through as successful responses
+    /// Redirects with a listed 3xx status reach the client instead of being followed
+    #[serde(default)]
+    pub success_statuses: Vec<u16>,
+    /// Follow this provider's redirects, re-sending the request to the new location
+    /// If not set, a redirect fails the attempt
+    #[serde(default)]
+    pub follow_redirects: bool,
+    /// Which followed redirects keep the `Authorization` header, defaults to none of them
+    #[serde(default)]
+    pub redirect_auth: RedirectAuth,
+    /// Substrings that mark a non-streaming response body as a transient error worth failing over
+    #[serde(default)]
+    pub retry_on_body: Vec<String>,
+    /// Optional path to a PEM certificate trusted as an extra root, for providers behind a private CA
+    pub ca_cert_path: Option<String>,
+    /// Skip TLS certificate verification for this provider; only for development
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+    /// Base64 SHA-256 hashes of the accepted server certificate public keys (SPKI); any other certificate is rejected
+    #[serde(default)]
+    pub cert_pins: Vec<String>,
+    /// Where the provider reports token usage in its responses, defaults to the OpenAI `usage` fields
+    #[serde(default)]
+    pub usage_path: UsagePath,
+    /// Optional limit on request bodies forwarded to this provider, in bytes, overriding the global `max_request_bytes`
+    pub max_request_bytes: Option<usize>,
+    /// Optional limit on responses accepted from this provider, in bytes, overriding the global `max_response_bytes`
+    pub max_response_bytes: Option<usize>,
+    /// Optional cap on requests per second sent to this provider, enforced according to `max_rps_mode`
+    pub max_rps: Option<f64>,
+    /// Remove `event:` lines from the provider's SSE streams, leaving plain `data:` frames as OpenAI clients expect
+    #[serde(default)]
+    pub strip_sse_events: bool,
+    /// Rename the provider's SSE `event:` names, e.g. `{ "completion" = "message" }`
+    #[serde(default)]
+    pub sse_event_renames: HashMap<String, String>,
+    /// Streaming format of the provider's responses, `sse` (default) or `ndjson`
+    #[serde(default)]
+    pub stream_format: StreamFormat,
+    /// How forwarded request bodies are encoded, `json` (default) or `form`
+    #[serde(default)]
+    pub body_encoding: BodyEncoding,
+    /// HTTP client honouring the TLS options above, built when the configuration is loaded
+    #[serde(skip)]
+    http_client: Option<reqwest::Client>,
 }
 
+/// Header used for `api_version` when the provider doesn't name one
+const DEFAULT_API_VERSION_HEADER: &str = "OpenAI-Version";
+
+impl Provider {
+    /// Mechanism through which this provider exposes the client-facing `model` it is mapped to
+    pub fn route_source(&self, model: &str) -> RouteSource {
+        if self.upstream_model_id(model) != model {
+            RouteSource::Alias
+        } else if self.models.is_some() {
+            RouteSource::Static
+        } else {
+            RouteSource::Discovered
+        }
+    }
+
+    /// Name used to refer to this provider, its configured `name` or else its host and any explicit port
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            match reqwest::Url::parse(&self.base_url).ok().and_then(|url| url.port()) {
+                Some(port) => format!("{}:{}", self.host(), port),
+                None => self.host(),
+            }
+        })
+    }
+
+    /// Key of the provider's discovered models in the model cache, its name along with the base URL it lists them at
+    pub fn model_cache_key(&self) -> String {
+        format!("{} {}", self.name(), self.base_url)
+    }
+
+    /// Whether a status from this provider is a successful response, any 2xx or one of `success_statuses`
+    pub fn is_success_status(&self, status: u16) -> bool {
+        (200..300).contains(&status) || self.success_statuses.contains(&status)
+    }
+
+    /// Check a response body for any of the provider's `retry_on_body` substrings
+    pub fn matches_retry_body(&self, body: &[u8]) -> bool {
+        if self.retry_on_body.is_empty() {
+            return false;
+        }
+        let text = String::from_utf8_lossy(body);
+        self.retry_on_body.iter().any(|pattern| text.contains(pattern.as_str()))
+    }
+
+    /// Ids clients see for one of this provider's models: its aliases if it has any, otherwise the id behind any `alias_prefix`
+    pub fn client_model_ids(&self, model: &str) -> Vec<String> {
+        let mut aliases: Vec<String> = self.model_aliases.iter()
+            .filter(|(_, upstream)| *upstream == model)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        if !aliases.is_empty() {
+            aliases.sort();
+            return aliases;
+        }
+        match &self.alias_prefix {
+            Some(prefix) => vec![format!("{}/{}", prefix, model)],
+            None => vec![model.to_string()],
+        }
+    }
+
+    /// Id this provider knows a model by, from one of its `client_model_ids`
+    pub fn upstream_model_id<'a>(&'a self, model: &'a str) -> &'a str {
+        if let Some(upstream) = self.model_aliases.get(model) {
+            return upstream;
+        }
+        self.alias_prefix.as_deref()
+            .and_then(|prefix| model.strip_prefix(prefix)?.strip_prefix('/'))
+            .unwrap_or(model)
+    }
+
+    /// Configured price of a model on this provider, from its static ModelInfo or `model_prices`
+    pub fn price_for(&self, model: &str) -> Option<f64> {
+        let upstream_model = self.upstream_model_id(model);
+        let static_price = self.models.iter()
+            .flatten()
+            .find_map(|m| match m {
+                StaticModel::Info(info) if info.id == upstream_model => info.price,
+                _ => None,
+            });
+        static_price.or_else(|| self.model_prices.get(model).copied())
+    }
+
+    /// Declared capabilities of a model on this provider, from its static ModelInfo or `model_capabilities`
+    /// None when the provider declares nothing for the model
+    pub fn capabilities_for(&self, model: &str) -> Option<&[String]> {
+        let upstream_model = self.upstream_model_id(model);
+        let static_capabilities = self.models.iter()
+            .flatten()
+            .find_map(|m| match m {
+                StaticModel::Info(info) if info.id == upstream_model => info.capabilities.as_deref(),
+                _ => None,
+            });
+        static_capabilities.or_else(|| self.model_capabilities.get(model).map(|c| c.as_slice()))
+    }
+
+    /// Host of the provider's base URL, safe to show without exposing paths or keys
+    pub fn host(&self) -> String {
+        reqwest::Url::parse(&self.base_url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// HTTP client to reach this provider with
+    pub fn client(&self) -> reqwest::Client {
+        self.http_client.clone().unwrap_or_default()
+    }
+
+    /// Build the provider's HTTP client with its TLS settings
+    /// The client never follows redirects itself; `follow_redirects` does, deciding where the API key may go
+    fn init_http_client(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+        let ca_pem = match &self.ca_cert_path {
+            Some(path) => Some(std::fs::read(path)
+                .map_err(|e| format!("Failed to read ca_cert_path '{}' of provider {}: {}", path, self.name(), e))?),
+            None => None,
+        };
+        if let (Some(path), Some(pem)) = (&self.ca_cert_path, &ca_pem) {
+            let certificate = reqwest::Certificate::from_pem(pem)
+                .map_err(|e| format!("Invalid certificate in '{}' of provider {}: {}", path, self.name(), e))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        // Pinning needs its own verifier, which also takes over the options above
+        if !self.cert_pins.is_empty() {
+            let tls = crate::tls::pinned_config(&self.name(), &self.cert_pins, ca_pem.as_deref(), self.danger_accept_invalid_certs)?;
+            self.http_client = Some(builder.use_preconfigured_tls(tls).build()?);
+            return Ok(());
+        }
+        self.http_client = Some(builder.danger_accept_invalid_certs(self.danger_accept_invalid_certs).build()?);
+        Ok(())
+    }
+
+    /// Attach a forwarded request body in the provider's `body_encoding`
+    pub fn with_body(&self, request_builder: reqwest::RequestBuilder, body: &serde_json::Value) -> reqwest::RequestBuilder {
+        match self.body_encoding {
+            BodyEncoding::Json => request_builder.json(body),
+            BodyEncoding::Form => {
+                // Strings are sent as-is; numbers, booleans, arrays and objects as their JSON text
+                let fields: Vec<(&str, String)> = body.as_object()
+                    .into_iter()
+                    .flatten()
+                    .filter(|(_, value)| !value.is_null())
+                    .map(|(key, value)| match value {
+                        serde_json::Value::String(text) => (key.as_str(), text.clone()),
+                        other => (key.as_str(), other.to_string()),
+                    })
+                    .collect();
+                request_builder.form(&fields)
+            }
+        }
+    }
+
+    /// API keys in rotation order, `api_keys` when set, else `api_key`
+    pub fn keys(&self) -> Vec<&str> {
+        if self.api_keys.is_empty() {
+            vec![self.api_key.as_str()]
+        } else {
+            self.api_keys.iter().map(|key| key.as_str()).collect()
+        }
+    }
+
+    /// Send a request built by `build`, moving on to the provider's next key each time one gets a 401
+    pub async fn send_with_key_fallback(
+        &self,
+        build: impl Fn() -> reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let keys = self.keys();
+        let mut response = self.apply_headers(build(), keys[0]).send().await?;
+        for key in &keys[1..] {
+            if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+                break;
+            }
+            response = self.apply_headers(build(), key).send().await?;
+        }
+        Ok(response)
+    }
+
+    /// Where a redirect from this provider leads when `follow_redirects` is set, and whether the API key may go along
+    /// None when the response isn't a redirect to follow
+    pub fn redirect_target(&self, response: &reqwest::Response) -> Option<(reqwest::Url, bool)> {
+        let status = response.status();
+        if !self.follow_redirects || !status.is_redirection() || self.is_success_status(status.as_u16()) {
+            return None;
+        }
+        let location = response.headers().get(reqwest::header::LOCATION)?.to_str().ok()?;
+        let target = response.url().join(location).ok()?;
+        if !matches!(target.scheme(), "http" | "https") {
+            return None;
+        }
+        let from = response.url();
+        let same_host = target.scheme() == from.scheme()
+            && target.host_str() == from.host_str()
+            && target.port_or_known_default() == from.port_or_known_default();
+        let keeps_key = match self.redirect_auth {
+            RedirectAuth::None => false,
+            RedirectAuth::SameHost => same_host,
+            RedirectAuth::Any => true,
+        };
+        Some((target, keeps_key))
+    }
+
+    /// GET `url` with key fallback, following redirects when `follow_redirects` is set
+    pub async fn get_following_redirects(&self, url: &str) -> reqwest::Result<reqwest::Response> {
+        let client = self.client();
+        let mut response = self.send_with_key_fallback(|| client.get(url)).await?;
+        for _ in 0..MAX_REDIRECTS {
+            let Some((target, keeps_key)) = self.redirect_target(&response) else {
+                break;
+            };
+            response = if keeps_key {
+                self.send_with_key_fallback(|| client.get(target.clone())).await?
+            } else {
+                self.apply_headers(client.get(target), "").send().await?
+            };
+        }
+        Ok(response)
+    }
+
+    /// Add the headers every request to this provider carries (authorization with `key`, API version)
+    pub fn apply_headers(&self, mut request_builder: reqwest::RequestBuilder, key: &str) -> reqwest::RequestBuilder {
+        // Add authorization header if API key is provided
+        if !key.is_empty() {
+            request_builder = request_builder.header("Authorization", format!("Bearer {}", key));
+        }
+
+        // Pin the API version if configured
+        if let Some(version) = &self.api_version {
+            let header = self.api_version_header.as_deref().unwrap_or(DEFAULT_API_VERSION_HEADER);
+            request_builder = request_builder.header(header, version);
+        }
+
+        request_builder
+    }
+}
+
+/// Encoding of request bodies forwarded to a provider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyEncoding {
+    /// `application/json`, the OpenAI default
+    #[default]
+    Json,
+    /// `application/x-www-form-urlencoded`, for gateways that only accept forms
+    Form,
+}
+
+/// Wire format of a provider's streaming responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFormat {
+    /// Server-sent events, passed through as-is
+    #[default]
+    Sse,
+    /// Newline-delimited JSON, translated into server-sent events for clients
+    Ndjson,
+}
+
+/// Dot-paths of the token counts in a provider's responses, e.g. `usage.prompt_tokens`
+/// Numeric segments index into arrays
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsagePath {
+    #[serde(default = "default_prompt_tokens_path")]
+    pub prompt: String,
+    #[serde(default = "default_completion_tokens_path")]
+    pub completion: String,
+}
+
+fn default_prompt_tokens_path() -> String {
+    "usage.prompt_tokens".to_string()
+}
+
+fn default_completion_tokens_path() -> String {
+    "usage.completion_tokens".to_string()
+}
+
+impl Default for UsagePath {
+    fn default() -> Self {
+        UsagePath {
+            prompt: default_prompt_tokens_path(),
+            completion: default_completion_tokens_path(),
+        }
+    }
+}
+
+/// What happens to a request that would exceed a provider's `max_rps`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaxRpsMode {
+    /// Wait until the provider has capacity again
+    #[default]
+    Delay,
+    /// Move on to the next provider serving the model, waiting only when none is left
+    Failover,
+}
+
+/// How the primary provider is chosen among those serving a model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutingMode {
+    /// Configuration order, or `weight` when set
+    #[default]
+    Priority,
+    /// Cheapest provider by configured price, falling back to priority when prices are unknown
+    Cost,
+    /// Best score combining priority and recent latency, weighted by `blend_priority_weight` and `blend_latency_weight`
+    Blend,
+}
+
+/// Request attributes that send a model's requests to a preferred provider ahead of normal routing
+/// Every condition set must hold for the rule to match
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingRule {
+    /// Upstream model the rule applies to, or every model if not set
+    pub model: Option<String>,
+    /// Match requests with (or without) images or other non-text content
+    pub has_images: Option<bool>,
+    /// Match requests with (or without) tools
+    pub has_tools: Option<bool>,
+    /// Match requests estimated at this many tokens or more, prompt plus requested completion
+    pub min_tokens: Option<u64>,
+    /// Match requests estimated at fewer tokens than this
+    pub max_tokens: Option<u64>,
+    /// Name of the provider tried first for matching requests
+    pub provider: String,
+}
+
+/// A mechanism that can route a model id to providers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteSource {
+    /// A model in a provider's static `models` list
+    Static,
+    /// `<provider name>/<model id>` with `prefixed_model_ids`
+    Prefix,
+    /// A provider's `model_aliases` or an id under its `alias_prefix`
+    Alias,
+    /// The id as listed by the providers' `/models`
+    Discovered,
+}
+
+/// How a model id is routed: by which mechanism, to which providers, under which upstream id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelRoute<'a> {
+    pub source: RouteSource,
+    /// Only this provider may serve the request
+    pub provider: Option<&'a str>,
+    /// Model id sent to the provider
+    pub upstream_model: &'a str,
+}
+
+/// Handling of more models than `max_mapped_models` allows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Keep the first models in provider priority order and drop the rest
+    #[default]
+    Truncate,
+    /// Refuse the configuration at load, or fail routing once discovery exceeds the cap
+    Error,
+}
+
+/// A statically configured model, either just its id or a detailed ModelInfo object
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StaticModel {
+    Id(String),
+    Info(ModelInfo),
+}
+
+/// Detailed static model configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    /// Optional context window size in tokens, surfaced in /v1/models
+    pub context_window: Option<u64>,
+    /// Optional price per million tokens, used by cost routing
+    pub price: Option<f64>,
+    /// Optional capabilities of the model, e.g. `["vision"]`, used to route multimodal requests
+    pub capabilities: Option<Vec<String>>,
+}
+
+impl StaticModel {
+    /// Model id as exposed to clients
+    pub fn id(&self) -> &str {
+        match self {
+            StaticModel::Id(id) => id,
+            StaticModel::Info(info) => &info.id,
+        }
+    }
+
+    /// Format as a standard OpenAI model object
+    pub fn to_model_json(&self) -> serde_json::Value {
+        let mut model_json = serde_json::json!({
+            "id": self.id(),
+            "object": "model",
+            "created": null,
+            "owned_by": null
+        });
+        if let StaticModel::Info(ModelInfo { context_window: Some(context_window), .. }) = self {
+            model_json["context_window"] = serde_json::json!(context_window);
+        }
+        model_json
+    }
+}
+
+/// Newer OpenAI request fields that some providers refuse
+pub const NEWER_FIELDS: &[&str] = &["store", "metadata"];
+
+/// How to handle `NEWER_FIELDS` in requests to a provider that may not understand them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NewerFieldsPolicy {
+    /// Forward the fields unchanged
+    #[default]
+    Pass,
+    /// Remove the fields before forwarding
+    Strip,
+    /// Refuse requests carrying the fields with a structured error
+    Reject,
+}
+
+/// Followed redirects of a provider that keep its API key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectAuth {
+    /// The key is never sent to a redirect target
+    #[default]
+    None,
+    /// Only targets with the same scheme, host and port get the key
+    SameHost,
+    /// Every target gets the key, including other hosts
+    Any,
+}
+
+/// How to handle a request asking for several choices from a provider that only returns one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedNPolicy {
+    /// Forward the request with `n = 1` and note the downgrade in a response header
+    Downgrade,
+    /// Refuse the request with a structured error
+    Reject,
+}
+
+/// What to do when a provider's stream closes before producing any content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyStreamPolicy {
+    /// End the stream with a terminal SSE error event
+    Error,
+    /// Try the next provider, since nothing has reached the client yet; the last one ends with the error event
+    Failover,
+}
+
+/// Client identity passed upstream in the `user` field of forwarded requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardUser {
+    /// Redacted id of the client's API key
+    KeyId,
+    /// Hash of the client's IP address
+    ClientIp,
+}
 
 /// Main configuration structure containing all providers
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,58 +608,480 @@ pub struct Config {
     /// Optional API key for the unified server
     /// If not set, the server will not require authentication
     pub server_api_key: Option<String>,
+    /// Optional TCP host to listen on, defaults to 0.0.0.0
+    pub host: Option<String>,
+    /// Optional TCP port to listen on, defaults to 8080
+    pub port: Option<u16>,
+    /// Optional Unix domain socket path to listen on instead of TCP
+    pub unix_socket: Option<String>,
+    /// Optional permissions for the Unix socket file, e.g. 0o660
+    pub unix_socket_mode: Option<u32>,
+    /// Optional API key for the `/admin/` endpoints
+    /// If not set, the admin endpoints are disabled
+    pub admin_api_key: Option<String>,
     /// List of model providers
     pub providers: Vec<Provider>,
+    /// Optional timeout in seconds for non-streaming requests to a provider
+    pub request_timeout_secs: Option<u64>,
+    /// Optional limit in seconds on the total duration of a streaming request
+    /// If exceeded, the stream ends with an SSE error event instead of being cut off
+    pub stream_timeout_secs: Option<u64>,
+    /// Optional number of seconds without upstream data after which a `: keep-alive` SSE comment is sent
+    /// to streaming clients, so proxies don't close idle connections; if not set, no heartbeats are sent
+    pub stream_heartbeat_secs: Option<u64>,
+    /// Report every failed provider attempt when all providers for a model fail
+    /// Off by default since it exposes provider hosts to clients
+    #[serde(default)]
+    pub verbose_errors: bool,
+    /// Upstream statuses that move the request on to the next provider, defaults to 429, 500, 502, 503 and 504
+    pub failover_statuses: Option<Vec<u16>>,
+    /// Optional cap on the providers tried after the first one for a single request
+    /// If not set, every provider serving the model may be tried
+    pub max_retries: Option<usize>,
+    /// List the hosts of providers that failed to enumerate in an `x_warnings` array on /v1/models
+    /// Off by default since it exposes provider hosts to clients
+    #[serde(default)]
+    pub model_list_warnings: bool,
+    /// Refuse chat completions requests with top-level fields outside the OpenAI schema, to catch client typos
+    #[serde(default)]
+    pub strict_fields: bool,
+    /// Extra top-level fields accepted by `strict_fields`, for provider-specific parameters
+    #[serde(default)]
+    pub extra_known_fields: Vec<String>,
+    /// Continue the client's W3C `traceparent` on forwarded requests, or start a new trace when it sent none
+    #[serde(default)]
+    pub trace_propagation: bool,
+    /// Optional allow-list of `X-Cost-Center` values, enabling the cost center label on usage metrics and the request log
+    pub cost_centers: Option<Vec<String>>,
+    /// Refuse requests without a cost center from `cost_centers` with 400
+    #[serde(default)]
+    pub require_cost_center: bool,
+    /// Optional handling of streams that close without producing any content
+    /// If not set, an empty stream is passed through as a successful one
+    pub empty_stream: Option<EmptyStreamPolicy>,
+    /// Optional size in bytes up to which successful non-streaming JSON responses are parsed before being returned
+    /// Malformed ones fail over like a failed attempt; larger responses pass through unchecked
+    pub verify_json_max_bytes: Option<usize>,
+    /// Share one upstream call between identical non-streaming requests in flight at the same time
+    #[serde(default)]
+    pub coalesce_requests: bool,
+    /// Models whose requests always get their own upstream call, even with `coalesce_requests`
+    #[serde(default)]
+    pub no_coalesce_models: Vec<String>,
+    /// Optional time in seconds successful non-streaming responses are cached for identical requests
+    /// If not set, responses are not cached
+    pub response_cache_ttl_secs: Option<u64>,
+    /// Most responses the cache holds, defaults to 1000
+    pub response_cache_max_entries: Option<usize>,
+    /// Request headers whose values are part of the cache and coalescing key, so clients differing in them never share a response
+    #[serde(default)]
+    pub cache_vary: Vec<String>,
+    /// Content type of responses whose provider sends none, by endpoint path (`audio/speech`)
+    /// Endpoints not listed fall back to the built-in defaults
+    #[serde(default)]
+    pub default_content_types: HashMap<String, String>,
+    /// Known context window sizes by client-facing model id, applied to models that don't report one
+    #[serde(default)]
+    pub context_windows: HashMap<String, u64>,
+    /// Refuse requests whose estimated tokens don't fit the model's known context window with 400, instead of forwarding them
+    #[serde(default)]
+    pub enforce_context_windows: bool,
+    /// Give models in /v1/models that report no `created` timestamp, or 0, a default one
+    #[serde(default)]
+    pub normalize_created: bool,
+    /// Optional Unix timestamp used as the default `created`
+    /// If not set, the gateway's start time is used
+    pub default_created: Option<u64>,
+    /// `created` timestamps by client-facing model id, used instead of `default_created` for those models
+    #[serde(default)]
+    pub model_created: HashMap<String, u64>,
+    /// Unix timestamp at which the configuration was loaded, the gateway's start time
+    #[serde(skip)]
+    started_at: u64,
+    /// Optional list of client-facing model ids the gateway routes and lists at all
+    /// If not set, all discovered models are allowed
+    pub allowed_models: Option<Vec<String>>,
+    /// Optional limit on forwarded request bodies, in bytes, for providers without their own `max_request_bytes`
+    /// Chat completions bodies are never accepted beyond 2 MiB or the largest configured limit, whichever is higher
+    pub max_request_bytes: Option<usize>,
+    /// Optional limit on provider responses, in bytes, for providers without their own `max_response_bytes`
+    pub max_response_bytes: Option<usize>,
+    /// Optional limit on the combined size of all request headers, in bytes
+    pub max_header_bytes: Option<usize>,
+    /// Optional limit on the length of any single request header value, in bytes
+    pub max_header_value_bytes: Option<usize>,
+    /// Optional limit on the requests in flight from a single client IP
+    /// Requests beyond it are rejected with 429
+    pub max_connections_per_ip: Option<usize>,
+    /// Client networks exempt from `max_connections_per_ip`, in CIDR notation (`10.0.0.0/8`, `::1/128`)
+    #[serde(default)]
+    pub trusted_cidrs: Vec<String>,
+    /// System prompt templates prepended to chat messages, by client-facing model id; they take precedence over provider `system_prompt`s
+    /// Templates may use `{{date}}`, `{{model}}`, `{{cost_center}}` and `{{client}}`
+    #[serde(default)]
+    pub system_prompts: HashMap<String, String>,
+    /// Client-facing model ids whose requests are forced into JSON mode (`response_format: {type: "json_object"}`)
+    #[serde(default)]
+    pub json_mode_models: Vec<String>,
+    /// Client-facing model ids whose forwarded requests always (`true`) or never (`false`) stream, whatever the client asked for
+    /// Responses are converted back into the form the client expects
+    #[serde(default)]
+    pub force_stream: HashMap<String, bool>,
+    /// Keep a `response_format` the client already set instead of overriding it with JSON mode
+    #[serde(default)]
+    pub json_mode_respect_client: bool,
+    /// Number of recent requests kept for `/admin/requests`, defaults to 100
+    pub request_log_size: Option<usize>,
+    /// Seconds of recent requests that `/admin/models` success rates cover, defaults to 300
+    pub model_stats_window_secs: Option<u64>,
+    /// How the primary provider is chosen, defaults to priority
+    #[serde(default)]
+    pub routing: RoutingMode,
+    /// Rules picking the primary provider from request attributes, checked in order before `routing`
+    #[serde(default)]
+    pub routing_rules: Vec<RoutingRule>,
+    /// Optional cap on the number of distinct models routed, to bound the mapping for huge catalogs
+    pub max_mapped_models: Option<usize>,
+    /// What happens when providers list more models than `max_mapped_models`, defaults to keeping the highest priority ones
+    #[serde(default)]
+    pub max_mapped_models_policy: OverflowPolicy,
+    /// Optional time in seconds the models discovered from each provider are reused for routing, defaults to 300
+    /// 0 discovers them again on every request
+    pub model_cache_ttl_secs: Option<u64>,
+    /// Influence of configuration order on blend routing, defaults to 1.0
+    pub blend_priority_weight: Option<f64>,
+    /// Influence of recent latency on blend routing, defaults to 1.0
+    pub blend_latency_weight: Option<f64>,
+    /// Add `X-Upstream-Attempts` and `X-Failover-Used` headers to proxied responses
+    #[serde(default)]
+    pub attempt_headers: bool,
+    /// Optional limit on how many providers are queried at once during model discovery, defaults to 8
+    pub model_refresh_concurrency: Option<usize>,
+    /// Check that every provider answers before applying a reloaded configuration
+    #[serde(default)]
+    pub reload_probe_providers: bool,
+    /// Optional interval in seconds between health checks of standby providers, defaults to 30
+    /// 0 turns the checks off
+    pub standby_probe_interval_secs: Option<u64>,
+    /// Stream request bodies straight to the provider when the client names the model in `X-Model`
+    /// Such requests skip body rewriting and failover, since the body is never buffered
+    #[serde(default)]
+    pub stream_request_bodies: bool,
+    /// Requests per minute allowed for each listed model, across all clients
+    #[serde(default)]
+    pub model_rate_limits: HashMap<String, u32>,
+    /// Optional client identity set as the forwarded `user` field when the client didn't send one
+    pub forward_user: Option<ForwardUser>,
+    /// List every model as `<provider name>/<model id>` and route such ids to that provider only
+    #[serde(default)]
+    pub prefixed_model_ids: bool,
+    /// Order in which routing mechanisms claim a model id matched by several of them, defaults to static, prefix, alias then discovered
+    /// Mechanisms left out follow in their default order
+    #[serde(default)]
+    pub route_precedence: Vec<RouteSource>,
+    /// Refuse model ids matched by several routing mechanisms instead of following `route_precedence`;
+    /// static catalogs are checked at load, discovered ones per request
+    #[serde(default)]
+    pub reject_ambiguous_models: bool,
+    /// Optional number of consecutive failed attempts that takes a provider out of routing for a cooldown
+    /// If not set, providers are never circuit-broken
+    pub circuit_breaker_threshold: Option<u32>,
+    /// Seconds a circuit-broken provider stays out of routing, defaults to 30
+    pub circuit_breaker_cooldown_secs: Option<u64>,
+    /// Optional number of trial requests let through once the cooldown has passed (half-open)
+    /// If they all succeed the breaker closes, a failed one reopens it for twice as long;
+    /// if not set, the breaker closes as soon as the cooldown has passed
+    pub circuit_breaker_half_open_probes: Option<u32>,
+    /// Longest cooldown reached after repeatedly failed trial requests, defaults to 300
+    pub circuit_breaker_max_cooldown_secs: Option<u64>,
+    /// Answer 503 with `Retry-After` instead of 404 when a known model has no healthy provider right now
+    #[serde(default)]
+    pub unavailable_as_503: bool,
+    /// Answer 429 with the soonest of the providers' `Retry-After` hints when every provider tried was rate limited
+    #[serde(default)]
+    pub aggregate_retry_after: bool,
+    /// Optional number of seconds to keep retrying model discovery at startup until every provider answers
+    /// If not set, the server starts without waiting for providers
+    pub startup_discovery_timeout_secs: Option<u64>,
+    /// Let clients set the timeout of their request in seconds with an `X-Request-Timeout` header
+    #[serde(default)]
+    pub allow_timeout_override: bool,
+    /// Only honor `X-Request-Timeout` from requests authenticated with the admin API key
+    #[serde(default)]
+    pub timeout_override_admin_only: bool,
+    /// Longest timeout `X-Request-Timeout` may set, larger values are clamped to it; defaults to 600
+    pub max_timeout_override_secs: Option<u64>,
+    /// Let clients override body parameters with `X-Override-<Param>` headers
+    #[serde(default)]
+    pub allow_param_overrides: bool,
+    /// Parameters that `X-Override-<Param>` headers may set, e.g. `temperature`
+    #[serde(default)]
+    pub overridable_params: Vec<String>,
+    /// Scale provider weights down as providers accumulate recent errors, and back up as they recover
+    #[serde(default)]
+    pub health_weighting: bool,
+    /// Optional smoothing of the per-provider error rate, the weight of each new outcome (0-1), defaults to 0.1
+    pub health_decay: Option<f64>,
+    /// Export histograms of request and response body sizes per model
+    #[serde(default)]
+    pub size_metrics: bool,
+    /// Start in maintenance mode, answering routable endpoints with 503 until turned off
+    #[serde(default)]
+    pub maintenance_mode: bool,
+    /// `Retry-After` seconds sent with maintenance mode responses, defaults to 60
+    pub maintenance_retry_after_secs: Option<u64>,
+    /// How requests over a provider's `max_rps` are handled, `delay` (default) or `failover`
+    #[serde(default)]
+    pub max_rps_mode: MaxRpsMode,
+    /// Optional share (0.0-1.0) of successful requests whose per-request logs are printed
+    /// Errors and authentication failures are always logged; if not set, every request is logged
+    pub log_sample_rate: Option<f64>,
+    /// Print the JSON body of each forwarded request, for debugging, following `log_sample_rate`
+    #[serde(default)]
+    pub log_request_bodies: bool,
+    /// Dot-paths of request fields replaced with `[redacted]` wherever request bodies are logged
+    /// A segment applied to an array applies to each element; defaults to message content, prompts and inputs
+    pub log_redact_fields: Option<Vec<String>>,
+}
+
+/// Where the server listens for connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp((String, u16)),
+    Unix(String),
 }
 
+/// Default upstream statuses that move the request on to the next provider
+const DEFAULT_FAILOVER_STATUSES: &[u16] = &[429, 500, 502, 503, 504];
+
+/// Default time in seconds discovered models are reused for routing
+const DEFAULT_MODEL_CACHE_TTL_SECS: u64 = 300;
+
+/// Shortest time between two fetches of one provider's models while caching them,
+/// bounding early refreshes for unknown models and retries of providers that failed discovery
+const MIN_MODEL_REFETCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// File the configuration is loaded from, relative to the working directory
+pub const CONFIG_PATH: &str = "config.toml";
+
+/// Default time in seconds between health checks of standby providers
+const DEFAULT_STANDBY_PROBE_INTERVAL_SECS: u64 = 30;
+
+/// Default number of providers queried at once during model discovery
+const DEFAULT_MODEL_REFRESH_CONCURRENCY: usize = 8;
+
+/// Longest pause between startup discovery attempts
+const MAX_STARTUP_DISCOVERY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Default weight of each new outcome in a provider's smoothed error rate
+const DEFAULT_HEALTH_DECAY: f64 = 0.1;
+
+/// Most dropped model names printed when `max_mapped_models` is exceeded
+const MAX_LOGGED_DROPPED_MODELS: usize = 20;
+
+/// Default weight of both priority and latency in blend routing
+const DEFAULT_BLEND_WEIGHT: f64 = 1.0;
+
+/// Default `Retry-After` seconds of maintenance mode responses
+const DEFAULT_MAINTENANCE_RETRY_AFTER_SECS: u64 = 60;
+
+/// Default seconds a circuit-broken provider stays out of routing
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECS: u64 = 30;
+
+/// Most redirects followed for one request to a provider with `follow_redirects`
+pub const MAX_REDIRECTS: usize = 5;
+
+/// Default longest timeout clients may set with `X-Request-Timeout`
+const DEFAULT_MAX_TIMEOUT_OVERRIDE_SECS: u64 = 600;
+
+/// Request fields redacted from logged bodies when `log_redact_fields` is not set
+const DEFAULT_LOG_REDACT_FIELDS: &[&str] = &["messages.content", "prompt", "input"];
+
+/// Default number of responses the response cache holds
+const DEFAULT_RESPONSE_CACHE_MAX_ENTRIES: usize = 1000;
+
+/// Default limit in seconds on cooldowns grown by failed half-open trials
+const DEFAULT_CIRCUIT_BREAKER_MAX_COOLDOWN_SECS: u64 = 300;
+
+/// Default limit on JSON request bodies, matching actix-web's own default
+const DEFAULT_JSON_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Default limit on the combined size of all request headers
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 32 * 1024;
+/// Default limit on the length of a single request header value
+pub const DEFAULT_MAX_HEADER_VALUE_BYTES: usize = 8 * 1024;
+
 impl Config {
     /// Load configuration from config.toml file
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_content = std::fs::read_to_string("config.toml")?;
-        let config: Config = toml::from_str(&config_content)?;
+        let mut config = Self::load_from(CONFIG_PATH)?;
+        config.started_at = crate::state::unix_timestamp();
         Ok(config)
     }
 
-    /// Get model to provider mapping with priority handling
-    pub async fn get_model_mapping(&self) -> Result<HashMap<String, Provider>, Box<dyn std::error::Error>> {
-        let mut mapping = HashMap::new();
-        let mut seen_models = std::collections::HashSet::new();
+    /// Parse and validate the configuration at `path`, building every provider's HTTP client
+    /// Nothing is returned unless the whole file is valid
+    pub fn load_from(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse and validate a configuration, building every provider's HTTP client
+    pub fn from_toml(config_content: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config: Config = toml::from_str(config_content)?;
+        config.validate()?;
+        for provider in &mut config.providers {
+            provider.init_http_client()?;
+        }
+        Ok(config)
+    }
+
+    /// Keep the start time of the configuration being replaced, which `created` timestamps default to
+    pub fn inherit_start_time(&mut self, previous: &Config) {
+        self.started_at = previous.started_at;
+    }
+
+    /// Providers that didn't answer a `GET /models` at all, with the reason
+    /// Any HTTP response counts as reachable; only connection failures and timeouts are reported
+    pub async fn unreachable_providers(&self) -> Vec<String> {
+        let outcomes: Vec<_> = stream::iter(&self.providers)
+            .map(|provider| async move {
+                let url = format!("{}/models", provider.base_url.trim_end_matches('/'));
+                provider.get_following_redirects(&url).await.err()
+                    .map(|e| format!("provider {} at {} is unreachable: {}", provider.name(), provider.base_url, e))
+            })
+            .buffered(self.model_refresh_concurrency())
+            .collect()
+            .await;
+        outcomes.into_iter().flatten().collect()
+    }
+
+    /// Get model to providers mapping with priority handling
+    /// Each model maps to every provider serving it, in configuration order
+    /// Providers discovered within `model_cache_ttl_secs` are taken from `cache` unless `refresh` is set;
+    /// while caching, each provider is fetched at most once per `MIN_MODEL_REFETCH_INTERVAL`
+    pub async fn get_model_mapping(
+        &self,
+        cache: &ModelListCache,
+        refresh: bool,
+    ) -> Result<HashMap<String, Vec<Provider>>, Box<dyn std::error::Error>> {
+        let ttl = self.model_cache_ttl();
+        let mut mapping: HashMap<String, Vec<Provider>> = HashMap::new();
+        let fetched: Vec<_> = stream::iter(&self.providers)
+            .map(|provider| async move {
+                // Static models are known without asking, so only discovered ones are cached
+                let (Some(ttl), None) = (ttl, &provider.models) else {
+                    return self.fetch_models_from_provider(provider).await;
+                };
+                let name = provider.model_cache_key();
+                if !refresh && let Some(models) = cache.get(&name, ttl) {
+                    return Ok(models);
+                }
+
+                // Requests arriving during a fetch wait for it and share its result
+                let asked_at = std::time::Instant::now();
+                let lock = cache.fetch_lock(&name);
+                let _fetching = lock.lock().await;
+                if let Some(models) = cache.get_since(&name, asked_at) {
+                    return Ok(models);
+                }
+                // Failed discoveries come back empty and aren't cached, so they are retried once the interval has passed
+                if !cache.claim_fetch(&name, MIN_MODEL_REFETCH_INTERVAL) {
+                    return Ok(cache.get(&name, ttl).unwrap_or_default());
+                }
+                let models = self.fetch_models_from_provider(provider).await;
+                if let Ok(models) = &models
+                    && !models.is_empty()
+                {
+                    cache.insert(name, models.clone());
+                }
+                models
+            })
+            .buffered(self.model_refresh_concurrency())
+            .collect()
+            .await;
 
         // Process providers in order (top to bottom priority)
-        for provider in &self.providers {
-            let models = self.fetch_models_from_provider(provider).await?;
-            for model in models {
-                // Only add model if we haven't seen it before (priority logic)
-                if !seen_models.contains(&model) {
-                    mapping.insert(model.clone(), provider.clone());
-                    seen_models.insert(model);
+        let mut dropped = std::collections::BTreeSet::new();
+        for (provider, models) in self.providers.iter().zip(fetched) {
+            let models = models?;
+            let mut seen_models = std::collections::HashSet::new();
+            for model in models.iter().flat_map(|model| provider.client_model_ids(model)) {
+                // A provider listing the same model twice is only added once
+                if !seen_models.insert(model.clone()) {
+                    continue;
                 }
+                // Past the cap only models already mapped from a higher priority provider gain providers
+                let at_cap = self.max_mapped_models.is_some_and(|max| mapping.len() >= max);
+                if at_cap && !mapping.contains_key(&model) {
+                    dropped.insert(model);
+                    continue;
+                }
+                mapping.entry(model).or_default().push(provider.clone());
             }
         }
+
+        if !dropped.is_empty() {
+            let max = self.max_mapped_models.unwrap_or_default();
+            let shown = dropped.iter().take(MAX_LOGGED_DROPPED_MODELS).cloned().collect::<Vec<_>>().join(", ");
+            let more = dropped.len().saturating_sub(MAX_LOGGED_DROPPED_MODELS);
+            let suffix = if more > 0 { format!(" and {} more", more) } else { String::new() };
+            if self.max_mapped_models_policy == OverflowPolicy::Error {
+                return Err(format!("Providers list more than max_mapped_models ({}) models; over the cap: {}{}", max, shown, suffix).into());
+            }
+            eprintln!("Warning: Model mapping capped at {} models, dropped {}: {}{}", max, dropped.len(), shown, suffix);
+        }
         Ok(mapping)
     }
 
+    /// Retry discovery with backoff until every provider lists at least one model or `timeout` passes
+    /// Returns the providers still without models when giving up
+    pub async fn wait_for_discovery(&self, timeout: std::time::Duration) -> Vec<String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut backoff = std::time::Duration::from_secs(1);
+        let mut attempt = 1;
+        loop {
+            let fetched: Vec<_> = stream::iter(&self.providers)
+                .map(|provider| self.fetch_models_from_provider(provider))
+                .buffered(self.model_refresh_concurrency())
+                .collect()
+                .await;
+            let pending: Vec<String> = self.providers.iter()
+                .zip(fetched)
+                .filter(|(_, models)| models.as_ref().map_or(true, |models| models.is_empty()))
+                .map(|(provider, _)| provider.name())
+                .collect();
+
+            if pending.is_empty() {
+                println!("Model discovery succeeded for all providers (attempt {})", attempt);
+                return pending;
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return pending;
+            }
+            println!(
+                "Waiting for providers without models: {} (attempt {}, retrying in {}s)",
+                pending.join(", "), attempt, backoff.as_secs()
+            );
+            tokio::time::sleep(backoff.min(deadline - now)).await;
+            backoff = (backoff * 2).min(MAX_STARTUP_DISCOVERY_BACKOFF);
+            attempt += 1;
+        }
+    }
+
     /// Fetch model names from a specific provider
     /// If static models are configured, use them; otherwise fetch from provider's /models endpoint
     pub async fn fetch_models_from_provider(&self, provider: &Provider) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         // If static models are configured, use them
         if let Some(static_models) = &provider.models {
             println!("Using static models configuration for provider: {}", provider.base_url);
-            return Ok(static_models.clone());
+            return Ok(static_models.iter().map(|model| model.id().to_string()).collect());
         }
 
         // Otherwise, fetch from provider's /models endpoint
-        let client = reqwest::Client::new();
         let url = format!("{}/models", provider.base_url.trim_end_matches('/'));
 
-        let mut request_builder = client.get(&url);
-
-        // Add authorization header if API key is provided
-        if !provider.api_key.is_empty() {
-            request_builder = request_builder.header("Authorization", format!("Bearer {}", provider.api_key));
-        }
-
-        match request_builder.send().await {
+        match provider.get_following_redirects(&url).await {
             Ok(response) => {
                 if !response.status().is_success() {
                     eprintln!("Warning: Failed to fetch models from {}: {}", provider.base_url, response.status());
@@ -105,64 +1116,146 @@ impl Config {
         }
     }
 
+    /// Health-check every standby provider, so a standby is known to be ready before the primaries fail
+    /// Discovered models are kept in the cache and each outcome feeds the provider's error rate and circuit breaker
+    pub async fn probe_standby_providers(&self, state: &crate::state::AppState) {
+        let standbys: Vec<&Provider> = self.providers.iter().filter(|p| p.standby).collect();
+        let outcomes: Vec<bool> = stream::iter(standbys.iter().copied())
+            .map(|provider| async move {
+                if provider.models.is_some() {
+                    // Static models need no discovery; any answer below 500 shows the provider is up
+                    let url = format!("{}/models", provider.base_url.trim_end_matches('/'));
+                    return provider.get_following_redirects(&url).await
+                        .is_ok_and(|response| !response.status().is_server_error());
+                }
+                let models = self.fetch_models_from_provider(provider).await.unwrap_or_default();
+                if models.is_empty() {
+                    return false;
+                }
+                if self.model_cache_ttl().is_some() {
+                    state.model_cache.insert(provider.model_cache_key(), models);
+                }
+                true
+            })
+            .buffered(self.model_refresh_concurrency())
+            .collect()
+            .await;
+
+        for (provider, healthy) in standbys.iter().zip(outcomes) {
+            if !healthy {
+                eprintln!("Warning: Standby provider {} failed its health check", provider.name());
+            }
+            state.record_provider_outcome(self, &provider.name(), healthy);
+        }
+    }
+
+    /// Time between health checks of standby providers, None when there are none or the checks are off
+    pub fn standby_probe_interval(&self) -> Option<std::time::Duration> {
+        if !self.providers.iter().any(|p| p.standby) {
+            return None;
+        }
+        Some(self.standby_probe_interval_secs.unwrap_or(DEFAULT_STANDBY_PROBE_INTERVAL_SECS))
+            .filter(|secs| *secs > 0)
+            .map(std::time::Duration::from_secs)
+    }
 
-    /// Get all models with raw provider data
-    pub async fn get_all_raw_models(&self) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    /// Get all models with raw provider data, along with the hosts of providers that failed to enumerate
+    pub async fn get_all_raw_models(&self) -> Result<(Vec<serde_json::Value>, Vec<String>), Box<dyn std::error::Error>> {
         let mut all_models = Vec::new();
+        let mut failed_hosts = Vec::new();
         let mut seen_models = std::collections::HashSet::new();
+        let fetched: Vec<_> = stream::iter(&self.providers)
+            .map(|provider| self.fetch_raw_models_from_provider(provider))
+            .buffered(self.model_refresh_concurrency())
+            .collect()
+            .await;
 
         // Process providers in order (top to bottom priority)
-        for provider in &self.providers {
-            let models = self.fetch_raw_models_from_provider(provider).await?;
+        for (provider, models) in self.providers.iter().zip(fetched) {
+            let Ok(models) = models else {
+                failed_hosts.push(provider.host());
+                continue;
+            };
             for model in models {
-                if let Some(model_id) = model.get("id").and_then(|id| id.as_str()) {
+                let Some(upstream_id) = model.get("id").and_then(|id| id.as_str()).map(|id| id.to_string()) else {
+                    continue;
+                };
+                for model_id in provider.client_model_ids(&upstream_id) {
+                    if !self.is_model_allowed(&model_id) {
+                        continue;
+                    }
+                    let mut model = model.clone();
+                    model["id"] = serde_json::json!(model_id);
+                    let mut model = self.with_created(self.with_context_window(model));
+
+                    // Prefixed ids are unique per provider, so nothing is hidden behind a higher priority provider
+                    let listed_id = if self.prefixed_model_ids {
+                        let prefixed = format!("{}/{}", provider.name(), model_id);
+                        model["id"] = serde_json::json!(prefixed);
+                        prefixed
+                    } else {
+                        model_id
+                    };
+
                     // Only add model if we haven't seen it before (priority logic)
-                    if !seen_models.contains(model_id) {
-                        all_models.push(model.clone());
-                        seen_models.insert(model_id.to_string());
+                    if seen_models.insert(listed_id) {
+                        all_models.push(model);
                     }
                 }
             }
         }
 
-        Ok(all_models)
+        Ok((all_models, failed_hosts))
+    }
+
+    /// Fill in `context_window` from the configured table when the model doesn't report one
+    fn with_context_window(&self, mut model: serde_json::Value) -> serde_json::Value {
+        if model.get("context_window").is_some() {
+            return model;
+        }
+        let known = model.get("id")
+            .and_then(|id| id.as_str())
+            .and_then(|id| self.context_windows.get(id))
+            .copied();
+        if let Some(context_window) = known {
+            model["context_window"] = serde_json::json!(context_window);
+        }
+        model
+    }
+
+    /// Replace a missing or zero `created` with the model's configured timestamp, when `normalize_created` is enabled
+    fn with_created(&self, mut model: serde_json::Value) -> serde_json::Value {
+        if !self.normalize_created || model.get("created").and_then(|c| c.as_u64()).is_some_and(|created| created > 0) {
+            return model;
+        }
+        let created = model.get("id")
+            .and_then(|id| id.as_str())
+            .and_then(|id| self.model_created.get(id))
+            .copied()
+            .or(self.default_created)
+            .unwrap_or(self.started_at);
+        model["created"] = serde_json::json!(created);
+        model
     }
 
     /// Fetch raw model objects from a specific provider
     /// If static models are configured, use them; otherwise fetch from provider's /models endpoint
+    /// Errors when the provider could not be enumerated
     pub async fn fetch_raw_models_from_provider(&self, provider: &Provider) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
         // If static models are configured, use them
         if let Some(static_models) = &provider.models {
             println!("Using static models configuration for provider: {}", provider.base_url);
-            let mut models = Vec::new();
-            for model_id in static_models {
-                let model_json = serde_json::json!({
-                    "id": model_id,
-                    "object": "model",
-                    "created": null,
-                    "owned_by": null
-                });
-                models.push(model_json);
-            }
-            return Ok(models);
+            return Ok(static_models.iter().map(|model| model.to_model_json()).collect());
         }
 
         // Otherwise, fetch from provider's /models endpoint
-        let client = reqwest::Client::new();
         let url = format!("{}/models", provider.base_url.trim_end_matches('/'));
 
-        let mut request_builder = client.get(&url);
-
-        // Add authorization header if API key is provided
-        if !provider.api_key.is_empty() {
-            request_builder = request_builder.header("Authorization", format!("Bearer {}", provider.api_key));
-        }
-
-        match request_builder.send().await {
+        match provider.get_following_redirects(&url).await {
             Ok(response) => {
                 if !response.status().is_success() {
                     eprintln!("Warning: Failed to fetch models from {}: {}", provider.base_url, response.status());
-                    return Ok(Vec::new()); // Return empty list instead of error
+                    return Err(format!("status {}", response.status()).into());
                 }
 
                 match response.json::<serde_json::Value>().await {
@@ -180,24 +1273,555 @@ impl Config {
                     }
                     Err(e) => {
                         eprintln!("Warning: Failed to parse models response from {}: {}", provider.base_url, e);
-                        Ok(Vec::new()) // Return empty list instead of error
+                        Err(e.into())
                     }
                 }
             }
             Err(e) => {
                 eprintln!("Warning: Failed to connect to {}: {}", provider.base_url, e);
-                Ok(Vec::new()) // Return empty list instead of error
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Routing mechanisms in the order they claim a model id, `route_precedence` first
+    pub fn route_precedence(&self) -> Vec<RouteSource> {
+        let mut order = self.route_precedence.clone();
+        for source in [RouteSource::Static, RouteSource::Prefix, RouteSource::Alias, RouteSource::Discovered] {
+            if !order.contains(&source) {
+                order.push(source);
+            }
+        }
+        order
+    }
+
+    /// Every way `model` can be routed, best first by `route_precedence`
+    /// `listed_by` are the providers the full id is mapped to, whether listed, aliased or discovered
+    pub fn model_routes<'a>(&self, model: &'a str, listed_by: &[Provider]) -> Vec<ModelRoute<'a>> {
+        self.route_precedence().into_iter()
+            .filter_map(|source| match source {
+                RouteSource::Prefix => self.split_prefixed_model(model).map(|(name, id)| ModelRoute {
+                    source,
+                    provider: Some(name),
+                    upstream_model: id,
+                }),
+                _ => listed_by.iter().any(|provider| provider.route_source(model) == source).then_some(ModelRoute {
+                    source,
+                    provider: None,
+                    upstream_model: model,
+                }),
+            })
+            .collect()
+    }
+
+    /// Split a provider-prefixed model id into the provider name and that provider's model id
+    /// None unless `prefixed_model_ids` is enabled and the prefix names a configured provider
+    pub fn split_prefixed_model<'a>(&self, model: &'a str) -> Option<(&'a str, &'a str)> {
+        if !self.prefixed_model_ids {
+            return None;
+        }
+        let (name, id) = model.split_once('/')?;
+        self.providers.iter().any(|p| p.name() == name).then_some((name, id))
+    }
+
+    /// Index of another provider that exposes the `alias` of provider `index` too, through its prefix,
+    /// its static models or its own aliases; each pair of aliases is only reported once
+    fn alias_collision(&self, index: usize, alias: &str) -> Option<usize> {
+        self.providers.iter().enumerate()
+            .filter(|(j, _)| *j != index)
+            .find(|(j, other)| {
+                let prefixed = other.alias_prefix.as_deref()
+                    .is_some_and(|prefix| alias.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/')));
+                let listed = other.models.iter().flatten()
+                    .any(|model| other.client_model_ids(model.id()).iter().any(|id| id == alias));
+                let aliased = *j > index && other.model_aliases.contains_key(alias);
+                prefixed || listed || aliased
+            })
+            .map(|(j, _)| j)
+    }
+
+    /// Check the model against the gateway-wide allow-list
+    pub fn is_model_allowed(&self, model: &str) -> bool {
+        match &self.allowed_models {
+            Some(allowed) => allowed.iter().any(|m| m == model),
+            None => true,
+        }
+    }
+
+    /// Copy of this configuration restricted to the providers accepted by `keep`
+    pub fn with_providers(&self, keep: impl Fn(&Provider) -> bool) -> Config {
+        let mut config = self.clone();
+        config.providers.retain(|provider| keep(provider));
+        config
+    }
+
+    /// Check the whole configuration before any of it is used
+    /// Every problem found is reported together, so a bad file is never partially applied
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+
+        let mut names = std::collections::HashSet::new();
+        for (i, provider) in self.providers.iter().enumerate() {
+            match reqwest::Url::parse(&provider.base_url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => {}
+                Ok(url) => problems.push(format!(
+                    "provider {} has unsupported base_url '{}' (expected an http or https URL, got scheme '{}')",
+                    i + 1, provider.base_url, url.scheme()
+                )),
+                Err(e) => problems.push(format!("provider {} has invalid base_url '{}': {}", i + 1, provider.base_url, e)),
+            }
+            if provider.alias_prefix.as_deref().is_some_and(|prefix| prefix.is_empty() || prefix.contains('/')) {
+                problems.push(format!("provider {} has an alias_prefix that is empty or contains '/'", i + 1));
+            }
+            for (alias, upstream) in &provider.model_aliases {
+                if alias.is_empty() || upstream.is_empty() {
+                    problems.push(format!("provider {} has a model alias with an empty name", i + 1));
+                    continue;
+                }
+                if let Some(j) = self.alias_collision(i, alias) {
+                    problems.push(format!("model alias '{}' of provider {} is also a model id of provider {}", alias, i + 1, j + 1));
+                }
             }
+            if provider.last_resort_model.is_some() && !provider.last_resort {
+                problems.push(format!("provider {} sets last_resort_model without last_resort", i + 1));
+            }
+            for status in &provider.success_statuses {
+                if !(200..400).contains(status) {
+                    problems.push(format!("provider {} lists success status {} (expected a 2xx or 3xx status)", i + 1, status));
+                }
+            }
+            for name in provider.system_prompt.as_deref().map(crate::template::unknown_variables).unwrap_or_default() {
+                problems.push(format!("system_prompt of provider {} uses unknown variable '{{{{{}}}}}'", i + 1, name));
+            }
+            for name in provider.prompt_cache_headers.keys() {
+                if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    problems.push(format!("provider {} has prompt_cache_headers entry '{}', which is not a valid header name", i + 1, name));
+                }
+            }
+            for pin in &provider.cert_pins {
+                if crate::tls::parse_pin(pin).is_none() {
+                    problems.push(format!("provider {} has invalid cert_pins entry '{}' (expected a base64 SHA-256 hash)", i + 1, pin));
+                }
+            }
+            // Admin endpoints and per-provider runtime state go by name, so names must be unique, defaults included
+            let name = provider.name();
+            if !names.insert(name.clone()) {
+                match provider.name {
+                    Some(_) => problems.push(format!("provider {} reuses the name '{}'", i + 1, name)),
+                    None => problems.push(format!("provider {} has the same default name '{}' as another provider; set a name", i + 1, name)),
+                }
+            }
+        }
+
+        for status in self.failover_statuses.iter().flatten() {
+            if !(400..600).contains(status) {
+                problems.push(format!("failover_statuses lists {} (expected a 4xx or 5xx status)", status));
+            }
+        }
+
+        for name in &self.cache_vary {
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!("cache_vary lists '{}', which is not a valid header name", name));
+            }
+        }
+
+        let mut precedence = std::collections::HashSet::new();
+        for source in &self.route_precedence {
+            if !precedence.insert(source) {
+                problems.push(format!("route_precedence lists {:?} more than once", source));
+            }
+        }
+
+        for (i, rule) in self.routing_rules.iter().enumerate() {
+            if !self.providers.iter().any(|p| p.name() == rule.provider) {
+                problems.push(format!("routing rule {} names unknown provider '{}'", i + 1, rule.provider));
+            }
+        }
+
+        // Static ids that also read as provider-prefixed ids would be routed ambiguously
+        if self.reject_ambiguous_models {
+            for provider in &self.providers {
+                for model in provider.models.iter().flatten() {
+                    if self.model_routes(model.id(), std::slice::from_ref(provider)).len() > 1 {
+                        problems.push(format!(
+                            "static model '{}' of provider {} is also a provider-prefixed id (reject_ambiguous_models)",
+                            model.id(), provider.name()
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Static catalogs are known now, so an oversized one is caught before serving
+        if let (Some(max), OverflowPolicy::Error) = (self.max_mapped_models, self.max_mapped_models_policy) {
+            let static_models: std::collections::HashSet<&str> = self.providers.iter()
+                .flat_map(|provider| provider.models.iter().flatten())
+                .map(|model| model.id())
+                .collect();
+            if static_models.len() > max {
+                problems.push(format!("static models list {} distinct models, more than max_mapped_models ({})", static_models.len(), max));
+            }
+        }
+
+        for (model, template) in &self.system_prompts {
+            for name in crate::template::unknown_variables(template) {
+                problems.push(format!("system prompt of model '{}' uses unknown variable '{{{{{}}}}}'", model, name));
+            }
+        }
+
+        if self.circuit_breaker_half_open_probes == Some(0) {
+            problems.push("circuit_breaker_half_open_probes must be at least 1".to_string());
+        }
+
+        for cidr in &self.trusted_cidrs {
+            if parse_cidr(cidr).is_none() {
+                problems.push(format!("trusted_cidrs entry '{}' is not a valid CIDR", cidr));
+            }
+        }
+
+        if let Err(e) = self.listen_address() {
+            problems.push(e);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  - {}", problems.join("\n  - ")))
+        }
+    }
+
+    /// Resolve where the server listens
+    /// Setting both `unix_socket` and a TCP `host`/`port` is ambiguous and rejected
+    pub fn listen_address(&self) -> Result<ListenAddress, String> {
+        match &self.unix_socket {
+            Some(_) if self.host.is_some() || self.port.is_some() => Err(
+                "Both unix_socket and host/port are configured; set only one of them".to_string()
+            ),
+            Some(path) => Ok(ListenAddress::Unix(path.clone())),
+            None => Ok(ListenAddress::Tcp((
+                self.host.clone().unwrap_or_else(|| "0.0.0.0".to_string()),
+                self.port.unwrap_or(8080),
+            ))),
+        }
+    }
+
+    /// Names of the optional behaviors enabled by this configuration
+    /// Only says which features are on, never keys or provider details
+    pub fn active_features(&self) -> Vec<&'static str> {
+        let mut features = vec!["streaming"];
+        if self.server_api_key.is_some() {
+            features.push("auth");
+        }
+        if self.admin_api_key.is_some() {
+            features.push("admin");
+        }
+        if self.routing == RoutingMode::Cost {
+            features.push("cost_routing");
+        }
+        if self.routing == RoutingMode::Blend {
+            features.push("blend_routing");
+        }
+        if self.providers.iter().any(|p| p.weight.is_some()) {
+            features.push("weighted_routing");
+        }
+        if !self.routing_rules.is_empty() {
+            features.push("routing_rules");
+        }
+        if self.providers.len() > 1 {
+            features.push("failover");
+        }
+        if self.providers.iter().any(|p| p.standby) {
+            features.push("standby_providers");
+        }
+        if self.providers.iter().any(|p| p.last_resort) {
+            features.push("last_resort_providers");
+        }
+        if self.request_timeout_secs.is_some() || self.stream_timeout_secs.is_some() {
+            features.push("timeouts");
+        }
+        if self.stream_heartbeat_secs.is_some() {
+            features.push("stream_heartbeats");
+        }
+        if self.verbose_errors {
+            features.push("verbose_errors");
+        }
+        if self.attempt_headers {
+            features.push("attempt_headers");
+        }
+        if self.allowed_models.is_some() {
+            features.push("allowed_models");
+        }
+        if !self.json_mode_models.is_empty() || self.providers.iter().any(|p| p.force_json_mode) {
+            features.push("json_mode");
+        }
+        if self.providers.iter().any(|p| !p.prompt_cache_headers.is_empty() || p.prompt_cache_key) {
+            features.push("prompt_cache_hints");
+        }
+        if self.providers.iter().any(|p| p.legacy_functions) {
+            features.push("legacy_functions");
+        }
+        if !self.model_rate_limits.is_empty() {
+            features.push("model_rate_limits");
+        }
+        if self.circuit_breaker_threshold.is_some() {
+            features.push("circuit_breaker");
+        }
+        if self.health_weighting {
+            features.push("health_weighting");
+        }
+        if self.forward_user.is_some() {
+            features.push("forward_user");
+        }
+        if self.allow_timeout_override {
+            features.push("timeout_override");
+        }
+        if self.allow_param_overrides && !self.overridable_params.is_empty() {
+            features.push("param_overrides");
+        }
+        if self.providers.iter().any(|p| p.max_rps.is_some()) {
+            features.push("max_rps");
+        }
+        if self.max_request_bytes.is_some() || self.max_response_bytes.is_some()
+            || self.providers.iter().any(|p| p.max_request_bytes.is_some() || p.max_response_bytes.is_some())
+        {
+            features.push("size_limits");
+        }
+        if self.has_system_prompts() {
+            features.push("system_prompts");
+        }
+        if self.providers.iter().any(|p| !p.cert_pins.is_empty()) {
+            features.push("cert_pinning");
+        }
+        if self.strict_fields {
+            features.push("strict_fields");
+        }
+        if self.trace_propagation {
+            features.push("trace_propagation");
+        }
+        if self.cost_centers.is_some() {
+            features.push("cost_centers");
+        }
+        if self.empty_stream.is_some() {
+            features.push("empty_stream_handling");
+        }
+        if self.aggregate_retry_after {
+            features.push("aggregate_retry_after");
+        }
+        if self.verify_json_max_bytes.is_some() {
+            features.push("json_verification");
+        }
+        if self.providers.iter().any(|p| p.newer_fields != NewerFieldsPolicy::Pass) {
+            features.push("newer_fields");
+        }
+        if !self.force_stream.is_empty() {
+            features.push("force_stream");
+        }
+        if self.enforce_context_windows {
+            features.push("context_window_enforcement");
+        }
+        if self.normalize_created {
+            features.push("normalize_created");
+        }
+        if self.providers.iter().any(|p| p.follow_redirects) {
+            features.push("follow_redirects");
+        }
+        if self.response_cache_ttl().is_some() {
+            features.push("response_cache");
+        }
+        if self.coalesce_requests {
+            features.push("coalescing");
+        }
+        if self.max_connections_per_ip.is_some() {
+            features.push("connection_limits");
+        }
+        if self.model_list_warnings {
+            features.push("model_list_warnings");
+        }
+        if self.prefixed_model_ids {
+            features.push("prefixed_model_ids");
+        }
+        if self.providers.iter().any(|p| p.alias_prefix.is_some() || !p.model_aliases.is_empty()) {
+            features.push("model_aliases");
+        }
+        if self.startup_discovery_timeout_secs.is_some() {
+            features.push("startup_discovery");
+        }
+        if self.size_metrics {
+            features.push("size_metrics");
+        }
+        if self.stream_request_bodies {
+            features.push("stream_request_bodies");
+        }
+        if self.request_log_size != Some(0) {
+            features.push("request_log");
+        }
+        if self.model_cache_ttl().is_some() {
+            features.push("model_cache");
+        }
+        if self.reload_probe_providers {
+            features.push("reload_probe");
+        }
+        if self.log_request_bodies {
+            features.push("request_body_logging");
         }
+        features
+    }
+
+    /// Number of providers queried at once during model discovery
+    pub fn model_refresh_concurrency(&self) -> usize {
+        self.model_refresh_concurrency.unwrap_or(DEFAULT_MODEL_REFRESH_CONCURRENCY).max(1)
+    }
+
+    /// How long a circuit-broken provider stays out of routing
+    pub fn circuit_breaker_cooldown(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.circuit_breaker_cooldown_secs.unwrap_or(DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECS))
+    }
+
+    /// How long responses are cached, if the response cache is enabled
+    pub fn response_cache_ttl(&self) -> Option<std::time::Duration> {
+        self.response_cache_ttl_secs.filter(|secs| *secs > 0).map(std::time::Duration::from_secs)
+    }
+
+    /// Upstream statuses that move the request on to the next provider
+    pub fn failover_statuses(&self) -> &[u16] {
+        self.failover_statuses.as_deref().unwrap_or(DEFAULT_FAILOVER_STATUSES)
+    }
+
+    /// How long discovered models are reused for routing, None when they are discovered on every request
+    pub fn model_cache_ttl(&self) -> Option<std::time::Duration> {
+        Some(self.model_cache_ttl_secs.unwrap_or(DEFAULT_MODEL_CACHE_TTL_SECS))
+            .filter(|secs| *secs > 0)
+            .map(std::time::Duration::from_secs)
+    }
+
+    pub fn response_cache_max_entries(&self) -> usize {
+        self.response_cache_max_entries.unwrap_or(DEFAULT_RESPONSE_CACHE_MAX_ENTRIES)
+    }
+
+    pub fn max_timeout_override_secs(&self) -> u64 {
+        self.max_timeout_override_secs.unwrap_or(DEFAULT_MAX_TIMEOUT_OVERRIDE_SECS)
+    }
+
+    /// Circuit breaker settings, None when the breaker is disabled
+    pub fn circuit_breaker_policy(&self) -> Option<BreakerPolicy> {
+        Some(BreakerPolicy {
+            threshold: self.circuit_breaker_threshold?,
+            cooldown: self.circuit_breaker_cooldown(),
+            probes: self.circuit_breaker_half_open_probes,
+            max_cooldown: std::time::Duration::from_secs(
+                self.circuit_breaker_max_cooldown_secs.unwrap_or(DEFAULT_CIRCUIT_BREAKER_MAX_COOLDOWN_SECS),
+            ),
+        })
+    }
+
+    pub fn health_decay(&self) -> f64 {
+        self.health_decay.unwrap_or(DEFAULT_HEALTH_DECAY).clamp(0.0, 1.0)
+    }
+
+    /// Weights of priority and latency in blend routing, never negative
+    pub fn blend_weights(&self) -> (f64, f64) {
+        (
+            self.blend_priority_weight.unwrap_or(DEFAULT_BLEND_WEIGHT).max(0.0),
+            self.blend_latency_weight.unwrap_or(DEFAULT_BLEND_WEIGHT).max(0.0),
+        )
+    }
+
+    /// Context window of a model on a provider, from its static ModelInfo or the `context_windows` table
+    pub fn context_window_for(&self, provider: &Provider, model: &str) -> Option<u64> {
+        let upstream_model = provider.upstream_model_id(model);
+        let static_window = provider.models.iter()
+            .flatten()
+            .find_map(|m| match m {
+                StaticModel::Info(info) if info.id == upstream_model => info.context_window,
+                _ => None,
+            });
+        static_window.or_else(|| self.context_windows.get(model).copied())
+    }
+
+    /// Size limit on request bodies forwarded to a provider
+    pub fn max_request_bytes_for(&self, provider: &Provider) -> Option<usize> {
+        provider.max_request_bytes.or(self.max_request_bytes)
+    }
+
+    /// Size limit on responses accepted from a provider
+    pub fn max_response_bytes_for(&self, provider: &Provider) -> Option<usize> {
+        provider.max_response_bytes.or(self.max_response_bytes)
+    }
+
+    /// Largest JSON request body accepted, so that no provider limit is cut short by the extractor
+    pub fn json_body_limit(&self) -> usize {
+        self.providers.iter()
+            .filter_map(|provider| provider.max_request_bytes)
+            .chain(self.max_request_bytes)
+            .fold(DEFAULT_JSON_BODY_LIMIT, usize::max)
+    }
+
+    /// Whether any system prompt template is configured, for a model or a provider
+    pub fn has_system_prompts(&self) -> bool {
+        !self.system_prompts.is_empty() || self.providers.iter().any(|p| p.system_prompt.is_some())
+    }
+
+    /// Interval of SSE heartbeats on streamed responses, if enabled
+    pub fn stream_heartbeat(&self) -> Option<std::time::Duration> {
+        self.stream_heartbeat_secs.filter(|secs| *secs > 0).map(std::time::Duration::from_secs)
+    }
+
+    pub fn maintenance_retry_after_secs(&self) -> u64 {
+        self.maintenance_retry_after_secs.unwrap_or(DEFAULT_MAINTENANCE_RETRY_AFTER_SECS)
+    }
+
+    /// A request body as it may be logged, with the `log_redact_fields` replaced
+    pub fn redacted_for_log(&self, body: &serde_json::Value) -> serde_json::Value {
+        let mut body = body.clone();
+        match &self.log_redact_fields {
+            Some(fields) => fields.iter().for_each(|path| redact_path(&mut body, path)),
+            None => DEFAULT_LOG_REDACT_FIELDS.iter().for_each(|path| redact_path(&mut body, path)),
+        }
+        body
+    }
+
+    /// Decide whether to print the per-request logs of a successful request
+    pub fn sample_log(&self) -> bool {
+        match self.log_sample_rate {
+            Some(rate) => rand::random::<f64>() < rate,
+            None => true,
+        }
+    }
+
+    /// Limit on the combined size of all request headers
+    pub fn max_header_bytes(&self) -> usize {
+        self.max_header_bytes.unwrap_or(DEFAULT_MAX_HEADER_BYTES)
+    }
+
+    /// Limit on the length of a single request header value
+    pub fn max_header_value_bytes(&self) -> usize {
+        self.max_header_value_bytes.unwrap_or(DEFAULT_MAX_HEADER_VALUE_BYTES)
+    }
+
+    /// Content type to send when the provider's response to `path` has none
+    pub fn default_content_type(&self, path: &str) -> &str {
+        match self.default_content_types.get(path) {
+            Some(content_type) => content_type,
+            None if path == "audio/speech" => "audio/mpeg",
+            None => "application/json",
+        }
+    }
+
+    /// Whether a client IP falls in one of the `trusted_cidrs`
+    pub fn is_trusted_ip(&self, ip: IpAddr) -> bool {
+        self.trusted_cidrs.iter()
+            .filter_map(|cidr| parse_cidr(cidr))
+            .any(|(network, prefix)| cidr_contains(network, prefix, ip))
     }
 
     /// Validate the provided API key against the configured server API key
     /// Returns true if authentication is disabled or if the key matches
+    /// The admin API key is accepted wherever the server API key is
     pub fn validate_api_key(&self, provided_key: &str) -> bool {
         match &self.server_api_key {
             Some(configured_key) => {
                 // If server API key is configured, validate against it
-                provided_key == configured_key
+                provided_key == configured_key || self.validate_admin_key(provided_key)
             }
             None => {
                 // If no server API key is configured, allow all requests (development mode)
@@ -205,4 +1829,338 @@ impl Config {
             }
         }
     }
+
+    /// Validate the provided API key against the configured admin API key
+    /// Always false when no admin API key is configured
+    pub fn validate_admin_key(&self, provided_key: &str) -> bool {
+        match &self.admin_api_key {
+            Some(configured_key) => provided_key == configured_key,
+            None => false,
+        }
+    }
+}
+
+/// Parse `address/prefix`, or a bare address as a single-host network
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u32)> {
+    let (address, prefix) = match cidr.split_once('/') {
+        Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, Some(prefix.parse::<u32>().ok()?)),
+        None => (cidr.parse::<IpAddr>().ok()?, None),
+    };
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    (prefix <= max).then_some((address, prefix))
+}
+
+/// Whether `ip` is inside the network `network/prefix`; IPv4-mapped IPv6 clients match IPv4 networks
+fn cidr_contains(network: IpAddr, prefix: u32, ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Replace the value at a dot-path with `[redacted]`, descending into every element of arrays on the way
+fn redact_path(value: &mut serde_json::Value, path: &str) {
+    if let Some(items) = value.as_array_mut() {
+        items.iter_mut().for_each(|item| redact_path(item, path));
+        return;
+    }
+    let (field, rest) = match path.split_once('.') {
+        Some((field, rest)) => (field, Some(rest)),
+        None => (path, None),
+    };
+    let Some(child) = value.get_mut(field) else {
+        return;
+    };
+    match rest {
+        Some(rest) => redact_path(child, rest),
+        None => *child = serde_json::json!("[redacted]"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{model_list, MockProvider};
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).expect("test config should parse")
+    }
+
+    #[test]
+    fn alias_prefix_is_stripped_for_upstream() {
+        let config = config(r#"
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+            alias_prefix = "openrouter"
+            model_aliases = { "gpt-4o-eu" = "gpt-4o" }
+        "#);
+        let provider = &config.providers[0];
+        assert_eq!(provider.upstream_model_id("openrouter/llama"), "llama");
+        assert_eq!(provider.upstream_model_id("gpt-4o-eu"), "gpt-4o");
+        assert_eq!(provider.upstream_model_id("openrouterllama"), "openrouterllama");
+        assert_eq!(provider.client_model_ids("llama"), vec!["openrouter/llama"]);
+        assert_eq!(provider.client_model_ids("gpt-4o"), vec!["gpt-4o-eu"]);
+    }
+
+    #[test]
+    fn prices_use_client_ids() {
+        let config = config(r#"
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+            alias_prefix = "eu"
+            models = [{ id = "big", price = 2.0 }]
+            model_prices = { "eu/small" = 0.5 }
+        "#);
+        let provider = &config.providers[0];
+        assert_eq!(provider.price_for("eu/big"), Some(2.0));
+        assert_eq!(provider.price_for("eu/small"), Some(0.5));
+        assert_eq!(provider.price_for("small"), None);
+    }
+
+    #[test]
+    fn rejects_empty_and_colliding_aliases() {
+        let empty = config(r#"
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+            model_aliases = { "" = "gpt-4o" }
+        "#);
+        assert!(empty.validate().unwrap_err().contains("empty name"));
+
+        let colliding = config(r#"
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+            model_aliases = { "gpt-4o" = "gpt-4o-2024" }
+            [[providers]]
+            base_url = "http://b/v1"
+            api_key = "k"
+            models = ["gpt-4o"]
+        "#);
+        assert!(colliding.validate().unwrap_err().contains("model alias 'gpt-4o' of provider 1"));
+
+        let prefixed = config(r#"
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+            model_aliases = { "or/gpt-4o" = "gpt-4o" }
+            [[providers]]
+            base_url = "http://b/v1"
+            api_key = "k"
+            alias_prefix = "or"
+        "#);
+        assert!(prefixed.validate().is_err());
+
+        let twice = config(r#"
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+            model_aliases = { "fast" = "a" }
+            [[providers]]
+            base_url = "http://b/v1"
+            api_key = "k"
+            model_aliases = { "fast" = "b" }
+        "#);
+        let problems = twice.validate().unwrap_err();
+        assert_eq!(problems.matches("model alias 'fast'").count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn discovery_queries_at_most_the_configured_providers_at_once() {
+        let arrivals: std::sync::Arc<std::sync::Mutex<Vec<std::time::Instant>>> = Default::default();
+        let mut providers = String::new();
+        let mut mocks = Vec::new();
+        for i in 0..4 {
+            let arrivals = arrivals.clone();
+            let mock = MockProvider::start_delayed(std::time::Duration::from_millis(200), move |_, _| {
+                arrivals.lock().unwrap().push(std::time::Instant::now());
+                model_list(&[serde_json::json!({"id": format!("m{}", i)})])
+            }).await;
+            providers.push_str(&format!("[[providers]]\nbase_url = \"{}\"\n", mock.url));
+            mocks.push(mock);
+        }
+        let config = Config::from_toml(&format!("model_refresh_concurrency = 2\nmodel_cache_ttl_secs = 0\n{}", providers)).unwrap();
+
+        let mapping = config.get_model_mapping(&ModelListCache::default(), false).await.unwrap();
+        assert_eq!(mapping.len(), 4);
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        // A third fetch only starts once one of the first two has finished
+        assert!(arrivals[2] - arrivals[0] >= std::time::Duration::from_millis(150));
+        assert!(arrivals[3] - arrivals[1] >= std::time::Duration::from_millis(150));
+    }
+
+    #[actix_web::test]
+    async fn startup_discovery_waits_for_late_providers() {
+        let late = MockProvider::start(|_, count| match count {
+            0 => crate::testing::error(503, "starting"),
+            _ => model_list(&[serde_json::json!({"id": "m1"})]),
+        }).await;
+        let config = Config::from_toml(&format!("[[providers]]\nname = \"late\"\nbase_url = \"{}\"", late.url)).unwrap();
+        let pending = config.wait_for_discovery(std::time::Duration::from_secs(5)).await;
+        assert!(pending.is_empty());
+        assert_eq!(late.hits("/v1/models"), 2);
+
+        let down = Config::from_toml("[[providers]]\nname = \"down\"\nbase_url = \"http://127.0.0.1:1/v1\"").unwrap();
+        let pending = down.wait_for_discovery(std::time::Duration::from_millis(500)).await;
+        assert_eq!(pending, ["down"]);
+    }
+
+    #[actix_web::test]
+    async fn providers_on_one_host_keep_their_own_models() {
+        let config = config(r#"
+            [[providers]]
+            base_url = "http://127.0.0.1:1/v1"
+            models = ["m1"]
+            [[providers]]
+            base_url = "http://127.0.0.1:2/v1"
+            models = ["m2"]
+        "#);
+        let cache = ModelListCache::default();
+        for _ in 0..2 {
+            let mapping = config.get_model_mapping(&cache, false).await.unwrap();
+            assert_eq!(mapping["m1"][0].base_url, "http://127.0.0.1:1/v1");
+            assert_eq!(mapping["m2"][0].base_url, "http://127.0.0.1:2/v1");
+        }
+        assert_ne!(config.providers[0].model_cache_key(), config.providers[1].model_cache_key());
+    }
+
+    #[test]
+    fn default_names_include_the_port_and_must_be_unique() {
+        let config = config(r#"
+            [[providers]]
+            base_url = "http://localhost:8000/v1"
+            [[providers]]
+            base_url = "http://localhost:8001/v1"
+            [[providers]]
+            base_url = "https://api.example.com/v1"
+        "#);
+        let names: Vec<String> = config.providers.iter().map(|provider| provider.name()).collect();
+        assert_eq!(names, ["localhost:8000", "localhost:8001", "api.example.com"]);
+
+        let error = Config::from_toml(r#"
+            [[providers]]
+            base_url = "http://localhost:8000/v1"
+            [[providers]]
+            base_url = "http://localhost:8000/other/v1"
+        "#).unwrap_err();
+        assert!(error.to_string().contains("same default name 'localhost:8000'"), "{}", error);
+    }
+
+    #[actix_web::test]
+    async fn failed_standby_health_checks_open_the_breaker() {
+        let config = config(r#"
+            circuit_breaker_threshold = 1
+            [[providers]]
+            name = "standby"
+            base_url = "http://127.0.0.1:1/v1"
+            api_key = "k"
+            standby = true
+        "#);
+        let state = crate::state::AppState::new(&config);
+        config.probe_standby_providers(&state).await;
+        assert!(state.circuit_open_for("standby").is_some());
+    }
+
+    #[test]
+    fn standby_checks_need_standby_providers() {
+        let primary_only = config(r#"
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+        "#);
+        assert_eq!(primary_only.standby_probe_interval(), None);
+        let checks_off = config(r#"
+            standby_probe_interval_secs = 0
+            [[providers]]
+            base_url = "http://a/v1"
+            api_key = "k"
+            standby = true
+        "#);
+        assert_eq!(checks_off.standby_probe_interval(), None);
+    }
+
+    const CAPPED: &str = r#"
+        max_mapped_models = 3
+        [[providers]]
+        name = "a"
+        base_url = "http://a/v1"
+        models = ["a1", "shared", "a2"]
+        [[providers]]
+        name = "b"
+        base_url = "http://b/v1"
+        models = ["shared", "b1", "b2"]
+    "#;
+
+    #[actix_web::test]
+    async fn model_mapping_keeps_the_highest_priority_models_up_to_the_cap() {
+        let config = Config::from_toml(CAPPED).unwrap();
+        let mapping = config.get_model_mapping(&ModelListCache::default(), false).await.unwrap();
+        let mut models: Vec<&str> = mapping.keys().map(|model| model.as_str()).collect();
+        models.sort();
+        assert_eq!(models, ["a1", "a2", "shared"]);
+        // A model mapped before the cap was hit still gains lower priority providers
+        let providers: Vec<String> = mapping["shared"].iter().map(|provider| provider.name()).collect();
+        assert_eq!(providers, ["a", "b"]);
+    }
+
+    #[test]
+    fn static_catalogs_over_the_cap_are_rejected_with_the_error_policy() {
+        let error = Config::from_toml(&format!("max_mapped_models_policy = \"error\"\n{}", CAPPED)).unwrap_err();
+        assert!(error.to_string().contains("static models list 5 distinct models, more than max_mapped_models (3)"), "{}", error);
+    }
+
+    #[test]
+    fn ambiguous_static_models_are_rejected_at_load() {
+        let error = Config::from_toml(r#"
+            prefixed_model_ids = true
+            reject_ambiguous_models = true
+            [[providers]]
+            name = "meta"
+            base_url = "http://meta/v1"
+            models = ["llama-3"]
+            [[providers]]
+            name = "other"
+            base_url = "http://other/v1"
+            models = ["meta/llama-3"]
+        "#).unwrap_err();
+        assert!(error.to_string().contains("static model 'meta/llama-3' of provider other is also a provider-prefixed id"), "{}", error);
+    }
+
+    #[test]
+    fn redacted_for_log_replaces_the_configured_fields() {
+        let body = serde_json::json!({
+            "model": "m1",
+            "messages": [
+                {"role": "system", "content": "secret-system"},
+                {"role": "user", "content": [{"type": "text", "text": "secret-part"}]}
+            ],
+            "metadata": {"user": "secret-user", "team": "search"}
+        });
+
+        let logged = config("providers = []").redacted_for_log(&body).to_string();
+        assert!(!logged.contains("secret-system") && !logged.contains("secret-part"), "{}", logged);
+        assert!(logged.contains("\"role\":\"system\"") && logged.contains("secret-user"), "{}", logged);
+
+        let logged = config(r#"
+            log_redact_fields = ["metadata.user", "messages.content"]
+            providers = []
+        "#).redacted_for_log(&body).to_string();
+        assert!(["secret-system", "secret-part", "secret-user"].iter().all(|secret| !logged.contains(secret)), "{}", logged);
+        assert!(logged.contains("search"), "{}", logged);
+    }
 }
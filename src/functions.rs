@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Map, Value};
+
+/// Rewrite a request using `tools` into the legacy `functions` form
+/// Tools other than functions have no legacy equivalent and are dropped
+pub fn to_legacy_request(body: &mut Value) {
+    let Some(fields) = body.as_object_mut() else {
+        return;
+    };
+
+    if let Some(tools) = fields.remove("tools") {
+        let functions: Vec<Value> = tools.as_array().into_iter().flatten()
+            .filter(|tool| tool.get("type").and_then(|t| t.as_str()).is_none_or(|t| t == "function"))
+            .filter_map(|tool| tool.get("function").cloned())
+            .collect();
+        if !functions.is_empty() {
+            fields.insert("functions".to_string(), Value::Array(functions));
+        }
+    }
+
+    if let Some(choice) = fields.remove("tool_choice") {
+        let function_call = match choice.as_str() {
+            // Legacy functions can't be required, letting the model decide is closest
+            Some("required") => Some(json!("auto")),
+            Some(_) => Some(choice),
+            None => choice.pointer("/function/name").map(|name| json!({"name": name})),
+        };
+        if let Some(function_call) = function_call {
+            fields.insert("function_call".to_string(), function_call);
+        }
+    }
+    fields.remove("parallel_tool_calls");
+
+    if let Some(messages) = fields.get_mut("messages").and_then(|m| m.as_array_mut()) {
+        *messages = legacy_messages(std::mem::take(messages));
+    }
+}
+
+/// Turn assistant tool calls into one `function_call` message each, and tool results into `function` messages
+fn legacy_messages(messages: Vec<Value>) -> Vec<Value> {
+    let mut names = HashMap::new();
+    let mut legacy = Vec::with_capacity(messages.len());
+    for mut message in messages {
+        let Some(fields) = message.as_object_mut() else {
+            legacy.push(message);
+            continue;
+        };
+        let role = fields.get("role").and_then(|r| r.as_str()).map(str::to_string);
+        match role.as_deref() {
+            Some("assistant") if fields.contains_key("tool_calls") => {
+                let calls = fields.remove("tool_calls");
+                let calls: Vec<&Value> = calls.as_ref().and_then(|c| c.as_array()).into_iter().flatten()
+                    .filter(|call| call.get("function").is_some())
+                    .collect();
+                if calls.is_empty() {
+                    legacy.push(message);
+                    continue;
+                }
+                // Only one call fits in a legacy message, so parallel calls become consecutive messages
+                for (i, call) in calls.iter().enumerate() {
+                    if let (Some(id), Some(name)) = (call.get("id").and_then(|id| id.as_str()), call.pointer("/function/name")) {
+                        names.insert(id.to_string(), name.clone());
+                    }
+                    let mut part = if i == 0 { fields.clone() } else { Map::new() };
+                    part.insert("role".to_string(), json!("assistant"));
+                    part.entry("content").or_insert(Value::Null);
+                    part.insert("function_call".to_string(), call["function"].clone());
+                    legacy.push(Value::Object(part));
+                }
+            }
+            Some("tool") => {
+                let name = fields.remove("tool_call_id").and_then(|id| id.as_str().and_then(|id| names.get(id)).cloned());
+                fields.insert("role".to_string(), json!("function"));
+                fields.insert("name".to_string(), name.unwrap_or_else(|| json!("")));
+                legacy.push(message);
+            }
+            _ => legacy.push(message),
+        }
+    }
+    legacy
+}
+
+/// Rewrite the legacy `function_call` of each choice of a response into `tool_calls`
+/// Returns whether anything changed
+pub fn from_legacy_response(response: &mut Value) -> bool {
+    from_legacy_choices(response, "message")
+}
+
+/// Rewrite the legacy `function_call` deltas of a streamed chunk into `tool_calls` deltas
+/// Returns whether anything changed
+pub fn from_legacy_chunk(chunk: &mut Value) -> bool {
+    from_legacy_choices(chunk, "delta")
+}
+
+fn from_legacy_choices(json: &mut Value, field: &str) -> bool {
+    let Some(choices) = json.get_mut("choices").and_then(|c| c.as_array_mut()) else {
+        return false;
+    };
+    let mut changed = false;
+    for choice in choices {
+        if choice.get("finish_reason").and_then(|r| r.as_str()) == Some("function_call") {
+            choice["finish_reason"] = json!("tool_calls");
+            changed = true;
+        }
+        let Some(call) = choice.get_mut(field).and_then(|m| m.as_object_mut()).and_then(|m| m.remove("function_call")) else {
+            continue;
+        };
+        let mut tool_call = Map::new();
+        if field == "delta" {
+            tool_call.insert("index".to_string(), json!(0));
+        }
+        // Streams carry the id and type only in the chunk naming the function
+        if field == "message" || call.get("name").is_some() {
+            tool_call.insert("id".to_string(), json!(tool_call_id()));
+            tool_call.insert("type".to_string(), json!("function"));
+        }
+        tool_call.insert("function".to_string(), call);
+        choice[field]["tool_calls"] = json!([tool_call]);
+        changed = true;
+    }
+    changed
+}
+
+/// Id for a tool call translated from a legacy function call, in OpenAI's `call_` form
+fn tool_call_id() -> String {
+    let suffix: String = (0..24).map(|_| char::from(b'a' + rand::random_range(0..26u8))).collect();
+    format!("call_{}", suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weather() -> Value {
+        json!({"name": "get_weather", "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}})
+    }
+
+    #[test]
+    fn tool_definitions_round_trip_through_legacy_functions() {
+        let mut request = json!({
+            "model": "m1",
+            "messages": [
+                {"role": "user", "content": "weather in Paris?"},
+                {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}}
+                ]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "sunny"}
+            ],
+            "tools": [{"type": "function", "function": weather()}, {"type": "code_interpreter"}],
+            "tool_choice": {"type": "function", "function": {"name": "get_weather"}},
+            "parallel_tool_calls": false
+        });
+        to_legacy_request(&mut request);
+        assert_eq!(request, json!({
+            "model": "m1",
+            "messages": [
+                {"role": "user", "content": "weather in Paris?"},
+                {"role": "assistant", "content": null, "function_call": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}},
+                {"role": "function", "content": "sunny", "name": "get_weather"}
+            ],
+            "functions": [weather()],
+            "function_call": {"name": "get_weather"}
+        }));
+
+        let mut response = json!({"choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": null, "function_call": {"name": "get_weather", "arguments": "{}"}},
+            "finish_reason": "function_call"
+        }]});
+        assert!(from_legacy_response(&mut response));
+        let choice = &response["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        let call = &choice["message"]["tool_calls"][0];
+        assert!(call["id"].as_str().unwrap().starts_with("call_"));
+        assert_eq!(call["type"], "function");
+        assert_eq!(call["function"], json!({"name": "get_weather", "arguments": "{}"}));
+        assert!(choice["message"].get("function_call").is_none());
+    }
+
+    #[test]
+    fn streamed_function_calls_become_tool_call_deltas() {
+        let mut first = json!({"choices": [{"index": 0, "delta": {"function_call": {"name": "get_weather", "arguments": ""}}}]});
+        let mut rest = json!({"choices": [{"index": 0, "delta": {"function_call": {"arguments": "{}"}}}]});
+        assert!(from_legacy_chunk(&mut first));
+        assert!(from_legacy_chunk(&mut rest));
+        assert_eq!(first["choices"][0]["delta"]["tool_calls"][0]["type"], "function");
+        // Only the chunk naming the function carries the id
+        assert_eq!(rest["choices"][0]["delta"]["tool_calls"], json!([{"index": 0, "function": {"arguments": "{}"}}]));
+
+        let mut plain = json!({"choices": [{"index": 0, "delta": {"content": "hi"}}]});
+        assert!(!from_legacy_chunk(&mut plain));
+    }
+}
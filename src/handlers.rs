@@ -1,18 +1,71 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use serde::Deserialize;
 use serde_json::{json, Value};
+use crate::circuit::BreakerState;
 use crate::config::Config;
+use crate::proxy;
+use crate::reload::LiveConfig;
+use crate::state::AppState;
+
+/// Optional cursor pagination of GET /v1/models
+#[derive(Debug, Deserialize)]
+pub struct ModelsQuery {
+    /// Maximum number of models to return
+    limit: Option<usize>,
+    /// Return models listed after the model with this id
+    after: Option<String>,
+    /// Only return models whose `owned_by` field matches
+    owned_by: Option<String>,
+    /// Only return models served by the provider with this name
+    provider: Option<String>,
+}
 
 /// Handler for GET /v1/models endpoint
 /// Returns all available models from all providers with raw provider data
 pub async fn models_endpoint(
-    config: web::Data<Config>,
+    query: web::Query<ModelsQuery>,
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
+    let config = config.current();
+    if state.in_maintenance() {
+        return Ok(proxy::maintenance_unavailable(&config));
+    }
+
+    let mut config = state.routable_config(&config);
+    if let Some(name) = &query.provider {
+        config = std::borrow::Cow::Owned(config.with_providers(|provider| provider.name() == *name));
+    }
     match config.get_all_raw_models().await {
-        Ok(all_models) => {
-            let response = json!({
+        Ok((mut all_models, failed_hosts)) => {
+            if let Some(owner) = &query.owned_by {
+                all_models.retain(|model| model.get("owned_by").and_then(|o| o.as_str()) == Some(owner.as_str()));
+            }
+
+            // Without a cursor or limit the page is the full list
+            let start = match &query.after {
+                Some(after) => all_models.iter()
+                    .position(|model| model.get("id").and_then(|id| id.as_str()) == Some(after.as_str()))
+                    .map_or(all_models.len(), |i| i + 1),
+                None => 0,
+            };
+            let remaining = &all_models[start..];
+            let page = &remaining[..query.limit.unwrap_or(remaining.len()).min(remaining.len())];
+            let id_of = |model: Option<&Value>| model.and_then(|m| m.get("id")).cloned().unwrap_or(Value::Null);
+
+            let mut response = json!({
                 "object": "list",
-                "data": all_models
+                "data": page,
+                "first_id": id_of(page.first()),
+                "last_id": id_of(page.last()),
+                "has_more": page.len() < remaining.len()
             });
+            // The catalog may be partial, so say which providers are missing from it
+            if config.model_list_warnings && !failed_hosts.is_empty() {
+                response["x_warnings"] = json!(failed_hosts.iter()
+                    .map(|host| format!("Failed to list models from provider {}", host))
+                    .collect::<Vec<_>>());
+            }
 
             Ok(HttpResponse::Ok().json(response))
         }
@@ -28,58 +81,538 @@ pub async fn models_endpoint(
     }
 }
 
+/// Handler for GET /version endpoint
+/// Returns the build version and the optional features enabled by the configuration
+pub async fn version_endpoint(
+    config: web::Data<LiveConfig>,
+) -> Result<HttpResponse> {
+    let config = config.current();
+    Ok(HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("GIT_HASH"),
+        "features": config.active_features()
+    })))
+}
+
 /// Handler for POST /v1/chat/completions endpoint
 /// Forwards chat completion requests to the appropriate provider based on model name
 pub async fn chat_completions(
-    req: web::Json<Value>,
-    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<Value>,
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    // Extract model name from request
-    let model = req.get("model")
-        .and_then(|m| m.as_str())
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("Missing model field"))?;
+    let config = config.current();
+    let config = state.routable_config(&config);
+    proxy::forward_request(&req, &config, &state, "chat/completions", body.into_inner()).await
+}
 
-    // Get model to provider mapping
-    let model_mapping = config.get_model_mapping().await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Failed to get model mapping: {}", e)))?;
+/// Handler for POST /v1/chat/completions with the model named in the `X-Model` header
+/// Streams the request body to the provider without buffering it, when `stream_request_bodies` is enabled
+pub async fn chat_completions_streamed_body(
+    req: HttpRequest,
+    payload: web::Payload,
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let config = config.current();
+    let config = state.routable_config(&config);
+    proxy::forward_payload(&req, &config, &state, "chat/completions", payload).await
+}
 
-    // Find the provider for the requested model
-    let provider = model_mapping.get(model)
-        .ok_or_else(|| actix_web::error::ErrorNotFound(format!("Model '{}' not found", model)))?;
+/// Handler for POST /v1/embeddings
+/// Routed by model like chat completions, with the `input` passed through untouched
+pub async fn embeddings(
+    req: HttpRequest,
+    body: web::Json<Value>,
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let config = config.current();
+    let config = state.routable_config(&config);
+    proxy::forward_request(&req, &config, &state, "embeddings", body.into_inner()).await
+}
 
-    // Create HTTP client and forward request
-    let client = reqwest::Client::new();
-    let url = format!("{}/chat/completions", provider.base_url.trim_end_matches('/'));
+/// Default handler for requests matching no route
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse> {
+    Ok(HttpResponse::NotFound().json(json!({
+        "error": {
+            "message": format!("Unknown request URL: {} {}", req.method(), req.path()),
+            "type": "invalid_request_error",
+            "code": "unknown_url"
+        }
+    })))
+}
 
-    let mut request_builder = client.post(&url).json(&req.into_inner());
+/// Handler for GET /health endpoint
+/// Liveness check, ok whenever the server is running, including in maintenance mode
+pub async fn health_endpoint() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(json!({"status": "ok"})))
+}
 
-    // Add authorization header if API key is provided
-    if !provider.api_key.is_empty() {
-        request_builder = request_builder.header("Authorization", format!("Bearer {}", provider.api_key));
+/// Handler for GET /metrics endpoint
+/// Returns request counts, latencies and provider weights in the Prometheus text format
+pub async fn metrics_endpoint(
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let config = config.current();
+    // Effective weights are computed now so they reflect the latest provider health
+    let mut gauges: Vec<_> = config.providers.iter()
+        .map(|provider| {
+            let weight = provider.weight.unwrap_or(1) as f64 * state.health_factor(&config, &provider.name());
+            ("gateway_provider_effective_weight", vec![("provider", provider.name())], weight)
+        })
+        .collect();
+    // One series per breaker state, set to 1 for the state the breaker is in
+    for provider in &config.providers {
+        let Some(current) = state.circuit_state(&provider.name()) else {
+            continue;
+        };
+        for breaker_state in BreakerState::ALL {
+            let labels = vec![("provider", provider.name()), ("state", breaker_state.as_str().to_string())];
+            gauges.push(("gateway_circuit_breaker_state", labels, f64::from(u8::from(breaker_state == current))));
+        }
     }
 
-    // Send request and return response
-    match request_builder.send().await {
-        Ok(response) => {
-            let status = response.status();
-            let body = response.bytes().await.unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render(&gauges)))
+}
 
-            // Convert reqwest status to actix status
-            let actix_status = actix_web::http::StatusCode::from_u16(status.as_u16())
-                .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+/// Handler for GET /admin/requests endpoint
+/// Returns the most recent proxied requests, newest first
+pub async fn admin_requests(
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(json!({
+        "object": "list",
+        "data": state.recent_requests()
+    })))
+}
 
-            Ok(HttpResponse::build(actix_status)
-                .content_type("application/json")
-                .body(body))
-        }
-        Err(e) => {
-            eprintln!("Error forwarding request: {}", e);
-            Ok(HttpResponse::InternalServerError().json(json!({
-                "error": {
-                    "message": format!("Failed to forward request: {}", e),
-                    "type": "internal_error"
-                }
-            })))
+/// Body of POST /admin/maintenance
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceToggle {
+    enabled: bool,
+}
+
+/// Handler for POST /admin/maintenance endpoint
+/// Turns maintenance mode on or off; routable endpoints answer 503 while it is on
+pub async fn admin_set_maintenance(
+    body: web::Json<MaintenanceToggle>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    state.set_maintenance(body.enabled);
+    println!("Admin: Maintenance mode {}", if body.enabled { "enabled" } else { "disabled" });
+    Ok(HttpResponse::Ok().json(json!({
+        "maintenance": body.enabled
+    })))
+}
+
+/// Handler for GET /admin/providers endpoint
+/// Returns the runtime status of every configured provider
+pub async fn admin_providers(
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let config = config.current();
+    let providers: Vec<Value> = config.providers.iter()
+        .map(|provider| {
+            let name = provider.name();
+            json!({
+                "name": name,
+                "host": provider.host(),
+                "enabled": state.is_provider_enabled(&name),
+                "circuit_open": state.circuit_open_for(&name).is_some(),
+                "circuit_state": state.circuit_state(&name).map(|breaker| breaker.as_str()),
+                "max_rps": provider.max_rps,
+                "current_rps": state.provider_rate(&name)
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "object": "list",
+        "data": providers
+    })))
+}
+
+/// Handler for GET /admin/models endpoint
+/// Returns the success rate each model had for clients over the stats window, whichever providers served it
+pub async fn admin_models(
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let models: Vec<Value> = state.model_outcomes().into_iter()
+        .map(|(model, successes, failures)| {
+            json!({
+                "model": model,
+                "requests": successes + failures,
+                "successes": successes,
+                "failures": failures,
+                "success_rate": successes as f64 / (successes + failures) as f64
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "object": "list",
+        "window_secs": state.model_stats_window().as_secs(),
+        "data": models
+    })))
+}
+
+/// Handler for POST /admin/providers/{name}/disable endpoint
+/// Takes the provider out of routing and the model catalog until it is enabled again
+pub async fn admin_disable_provider(
+    name: web::Path<String>,
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let config = config.current();
+    set_provider_enabled(&name, false, &config, &state)
+}
+
+/// Handler for POST /admin/providers/{name}/enable endpoint
+/// Puts a disabled provider back into rotation
+pub async fn admin_enable_provider(
+    name: web::Path<String>,
+    config: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let config = config.current();
+    set_provider_enabled(&name, true, &config, &state)
+}
+
+fn set_provider_enabled(name: &str, enabled: bool, config: &Config, state: &AppState) -> Result<HttpResponse> {
+    if !config.providers.iter().any(|provider| provider.name() == name) {
+        return Ok(HttpResponse::NotFound().json(json!({
+            "error": {
+                "message": format!("Provider '{}' not found", name),
+                "type": "invalid_request_error"
+            }
+        })));
+    }
+
+    state.set_provider_enabled(name, enabled);
+    println!("Admin: Provider {} {}", name, if enabled { "enabled" } else { "disabled" });
+    Ok(HttpResponse::Ok().json(json!({
+        "provider": name,
+        "enabled": enabled
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::testing::{error, model_list, ok_completion, Gateway, MockProvider};
+
+    /// Body of `GET path` on the gateway
+    async fn get_json(gateway: &Gateway, path: &str) -> Value {
+        gateway.get(path).send().await.unwrap().json().await.unwrap()
+    }
+
+    /// The listed model with `id`
+    fn listed<'a>(models: &'a Value, id: &str) -> &'a Value {
+        models["data"].as_array().unwrap().iter().find(|model| model["id"] == id).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn context_windows_fill_static_and_discovered_models() {
+        let discovered = MockProvider::start(|_, _| model_list(&[
+            json!({"id": "d1", "object": "model"}),
+            json!({"id": "d2", "object": "model", "context_window": 999}),
+        ])).await;
+        let gateway = Gateway::start(&format!(r#"
+            context_windows = {{ "s2" = 100, "d1" = 200, "d2" = 300 }}
+            [[providers]]
+            base_url = "http://127.0.0.1:1/v1"
+            models = [{{ id = "s1", context_window = 4096 }}, "s2"]
+            [[providers]]
+            base_url = "{}"
+        "#, discovered.url)).await;
+
+        let models = get_json(&gateway, "/v1/models").await;
+        assert_eq!(listed(&models, "s1")["context_window"], 4096);
+        assert_eq!(listed(&models, "s2")["context_window"], 100);
+        assert_eq!(listed(&models, "d1")["context_window"], 200);
+        assert_eq!(listed(&models, "d2")["context_window"], 999);
+    }
+
+    #[actix_web::test]
+    async fn allowed_models_filter_the_catalog_and_routing() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            allowed_models = ["m1"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1", "m2"]
+        "#, upstream.url)).await;
+
+        let models = get_json(&gateway, "/v1/models").await;
+        let ids: Vec<&Value> = models["data"].as_array().unwrap().iter().map(|model| &model["id"]).collect();
+        assert_eq!(ids, ["m1"]);
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        let refused = gateway.chat("m2").await;
+        assert_eq!(refused.status(), 404);
+        let body: Value = refused.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "model_not_found");
+        assert_eq!(upstream.hits("/v1/chat/completions"), 1);
+    }
+
+    #[actix_web::test]
+    async fn prefixed_ids_are_listed_and_routed_per_provider() {
+        let alpha = MockProvider::start(|_, _| ok_completion("m1", "alpha")).await;
+        let beta = MockProvider::start(|_, _| ok_completion("m1", "beta")).await;
+        let gateway = Gateway::start(&format!(r#"
+            prefixed_model_ids = true
+            [[providers]]
+            name = "alpha"
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            name = "beta"
+            base_url = "{}"
+            models = ["m1"]
+        "#, alpha.url, beta.url)).await;
+
+        let models = get_json(&gateway, "/v1/models").await;
+        let mut ids: Vec<&str> = models["data"].as_array().unwrap().iter().filter_map(|model| model["id"].as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, ["alpha/m1", "beta/m1"]);
+
+        let listed_id = "beta/m1";
+        assert_eq!(gateway.chat(listed_id).await.status(), 200);
+        assert_eq!(alpha.hits("/v1/chat/completions"), 0);
+        assert_eq!(beta.last().json()["model"], "m1");
+    }
+
+    /// Gateway whose one provider lists the static models `m1` to `m3`
+    async fn three_models() -> Gateway {
+        Gateway::start(r#"
+            [[providers]]
+            name = "alpha"
+            base_url = "http://127.0.0.1:1/v1"
+            models = ["m1", "m2", "m3"]
+        "#).await
+    }
+
+    #[actix_web::test]
+    async fn model_lists_carry_pagination_metadata() {
+        let gateway = three_models().await;
+
+        let all = get_json(&gateway, "/v1/models").await;
+        assert_eq!(all["object"], "list");
+        assert_eq!(all["first_id"], "m1");
+        assert_eq!(all["last_id"], "m3");
+        assert_eq!(all["has_more"], false);
+
+        let page = get_json(&gateway, "/v1/models?limit=1&after=m1").await;
+        assert_eq!(page["data"].as_array().unwrap().len(), 1);
+        assert_eq!(page["first_id"], "m2");
+        assert_eq!(page["last_id"], "m2");
+        assert_eq!(page["has_more"], true);
+
+        let past_the_end = get_json(&gateway, "/v1/models?after=m3").await;
+        assert_eq!(past_the_end["data"], json!([]));
+        assert_eq!(past_the_end["first_id"], Value::Null);
+        assert_eq!(past_the_end["has_more"], false);
+    }
+
+    /// Text of the gateway's `/metrics` endpoint
+    async fn metrics(gateway: &Gateway) -> String {
+        gateway.get("/metrics").send().await.unwrap().text().await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn request_and_response_sizes_are_observed() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            size_metrics = true
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        let metrics = metrics(&gateway).await;
+        assert!(metrics.contains(r#"gateway_request_size_bytes_count{model="m1"} 1"#), "{}", metrics);
+        assert!(metrics.contains(r#"gateway_response_size_bytes_count{model="m1"} 1"#), "{}", metrics);
+        assert!(!metrics.contains(r#"gateway_response_size_bytes_sum{model="m1"} 0"#));
+    }
+
+    #[actix_web::test]
+    async fn maintenance_mode_refuses_routable_endpoints_only() {
+        let gateway = Gateway::start(r#"
+            admin_api_key = "admin"
+            maintenance_retry_after_secs = 120
+            [[providers]]
+            base_url = "http://127.0.0.1:1/v1"
+            models = ["m1"]
+        "#).await;
+        let set_maintenance = |enabled: bool| gateway.post("/admin/maintenance", json!({"enabled": enabled}))
+            .header("authorization", "Bearer admin")
+            .send();
+        assert!(set_maintenance(true).await.unwrap().status().is_success());
+
+        let chat = gateway.chat("m1").await;
+        assert_eq!(chat.status(), 503);
+        assert_eq!(chat.headers()["retry-after"], "120");
+        assert_eq!(gateway.get("/v1/models").send().await.unwrap().status(), 503);
+        let embeddings = gateway.post("/v1/embeddings", json!({"model": "m1", "input": "hi"})).send().await.unwrap();
+        assert_eq!(embeddings.status(), 503);
+        assert_eq!(gateway.get("/health").send().await.unwrap().status(), 200);
+
+        assert!(set_maintenance(false).await.unwrap().status().is_success());
+        assert_eq!(gateway.get("/v1/models").send().await.unwrap().status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn failed_providers_are_listed_in_warnings() {
+        let working = MockProvider::start(|_, _| model_list(&[json!({"id": "m1", "object": "model"})])).await;
+        let failing = MockProvider::start(|_, _| error(500, "down")).await;
+        let gateway = Gateway::start(&format!(r#"
+            model_list_warnings = true
+            [[providers]]
+            base_url = "{}"
+            [[providers]]
+            base_url = "{}"
+        "#, working.url, failing.url)).await;
+
+        let models = get_json(&gateway, "/v1/models").await;
+        assert_eq!(listed(&models, "m1")["id"], "m1");
+        assert_eq!(models["x_warnings"], json!(["Failed to list models from provider 127.0.0.1"]));
+    }
+
+    #[actix_web::test]
+    async fn the_catalog_filters_by_provider_and_owner() {
+        let groq = MockProvider::start(|_, _| model_list(&[
+            json!({"id": "a1", "object": "model", "owned_by": "meta"}),
+            json!({"id": "a2", "object": "model", "owned_by": "mistral"}),
+        ])).await;
+        let other = MockProvider::start(|_, _| model_list(&[json!({"id": "b1", "object": "model", "owned_by": "meta"})])).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            name = "groq"
+            base_url = "{}"
+            [[providers]]
+            name = "other"
+            base_url = "{}"
+        "#, groq.url, other.url)).await;
+
+        let ids = |models: Value| -> Vec<String> {
+            let mut ids: Vec<String> = models["data"].as_array().unwrap().iter().map(|model| model["id"].as_str().unwrap().to_string()).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(get_json(&gateway, "/v1/models?provider=groq").await), ["a1", "a2"]);
+        assert_eq!(ids(get_json(&gateway, "/v1/models?owned_by=meta").await), ["a1", "b1"]);
+        assert!(ids(get_json(&gateway, "/v1/models?provider=unknown").await).is_empty());
+        assert!(ids(get_json(&gateway, "/v1/models?owned_by=nobody").await).is_empty());
+        assert_eq!(ids(get_json(&gateway, "/v1/models").await).len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn missing_and_zero_timestamps_get_the_default() {
+        let upstream = MockProvider::start(|_, _| model_list(&[
+            json!({"id": "valid", "object": "model", "created": 1650000000}),
+            json!({"id": "zero", "object": "model", "created": 0}),
+            json!({"id": "missing", "object": "model"}),
+            json!({"id": "configured", "object": "model", "created": 0}),
+        ])).await;
+        let gateway = Gateway::start(&format!(r#"
+            normalize_created = true
+            default_created = 1700000000
+            model_created = {{ "configured" = 1713398400 }}
+            [[providers]]
+            base_url = "{}"
+        "#, upstream.url)).await;
+
+        let models = get_json(&gateway, "/v1/models").await;
+        assert_eq!(listed(&models, "valid")["created"], 1650000000);
+        assert_eq!(listed(&models, "zero")["created"], 1700000000);
+        assert_eq!(listed(&models, "missing")["created"], 1700000000);
+        assert_eq!(listed(&models, "configured")["created"], 1713398400);
+    }
+
+    #[actix_web::test]
+    async fn model_success_rates_reflect_mixed_outcomes() {
+        let upstream = MockProvider::start(|_, count| match count % 4 {
+            3 => error(500, "broken"),
+            _ => ok_completion("m1", "hi"),
+        }).await;
+        let gateway = Gateway::start(&format!(r#"
+            admin_api_key = "admin"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1", "m2"]
+        "#, upstream.url)).await;
+        for model in ["m1", "m1", "m1", "m1", "m2"] {
+            gateway.chat(model).await;
         }
+
+        let stats: Value = gateway.get("/admin/models").bearer_auth("admin").send().await.unwrap().json().await.unwrap();
+        let m1 = listed_model(&stats, "m1");
+        assert_eq!((m1["requests"].as_u64(), m1["failures"].as_u64()), (Some(4), Some(1)));
+        assert_eq!(m1["success_rate"], 0.75);
+        assert_eq!(listed_model(&stats, "m2")["success_rate"], 1.0);
     }
-}
\ No newline at end of file
+
+    /// The admin stats entry of `model`
+    fn listed_model<'a>(stats: &'a Value, model: &str) -> &'a Value {
+        stats["data"].as_array().unwrap().iter().find(|entry| entry["model"] == model).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn head_models_answers_without_a_body_or_authentication() {
+        let gateway = Gateway::start(r#"
+            server_api_key = "secret"
+            [[providers]]
+            base_url = "http://127.0.0.1:1/v1"
+            models = ["m1"]
+        "#).await;
+        let listing = gateway.get("/v1/models").send().await.unwrap().bytes().await.unwrap();
+
+        let response = reqwest::Client::new().head(format!("{}/v1/models", gateway.url)).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("application/json"));
+        assert_eq!(response.headers()["content-length"], listing.len().to_string().as_str());
+        assert!(response.bytes().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn unnamed_providers_on_one_host_keep_their_own_state() {
+        let down = MockProvider::start(|_, _| error(503, "down")).await;
+        let healthy = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            admin_api_key = "admin"
+            circuit_breaker_threshold = 1
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, down.url, healthy.url)).await;
+        let name = |provider: &MockProvider| provider.url.trim_start_matches("http://").trim_end_matches("/v1").to_string();
+        let status = || async {
+            let providers: Value = gateway.get("/admin/providers").bearer_auth("admin").send().await.unwrap().json().await.unwrap();
+            let entry = |name: String| providers["data"].as_array().unwrap().iter()
+                .find(|provider| provider["name"] == name.as_str())
+                .map(|provider| (provider["enabled"].clone(), provider["circuit_open"].clone()))
+                .unwrap();
+            (entry(name(&down)), entry(name(&healthy)))
+        };
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        assert_eq!(status().await, ((json!(true), json!(true)), (json!(true), json!(false))));
+
+        let disable = gateway.post(&format!("/admin/providers/{}/disable", name(&down)), json!({}))
+            .bearer_auth("admin")
+            .send().await.unwrap();
+        assert!(disable.status().is_success());
+        assert_eq!(status().await, ((json!(false), json!(true)), (json!(true), json!(false))));
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+    }
+}
@@ -1,11 +1,32 @@
 // Module declarations
+mod cache;
+mod circuit;
+mod coalesce;
 mod config;
+mod functions;
 mod handlers;
+mod metrics;
 mod middleware;
+mod proxy;
+mod ratelimit;
+mod reload;
+mod routing;
+mod state;
+mod stats;
+mod streaming;
+mod template;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod tokens;
 
-use actix_web::{web, App, HttpServer, middleware::Logger};
-use config::Config;
-use middleware::ApiKeyAuth;
+use actix_web::{guard, web, App, HttpServer, middleware::ErrorHandlers};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use config::{Config, ListenAddress};
+use middleware::{ApiKeyAuth, ConnectionLimits, HeaderLimits};
+use reload::LiveConfig;
+use state::AppState;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -22,22 +43,128 @@ async fn main() -> std::io::Result<()> {
         Some(_) => println!("🔒 API key authentication: ENABLED"),
         None => println!("🔓 API key authentication: DISABLED (development mode)"),
     }
+    if config.admin_api_key.is_some() {
+        println!("🛠  Admin endpoints: ENABLED");
+    }
+    if config.maintenance_mode {
+        println!("🚧 Maintenance mode: ON (routable endpoints answer 503)");
+    }
     
     println!("Configured providers:");
     for (i, provider) in config.providers.iter().enumerate() {
-        println!("  {}. {} (priority: {})", i + 1, provider.base_url, i + 1);
+        println!("  {}. {} [{}] (priority: {})", i + 1, provider.base_url, provider.name(), i + 1);
+        if provider.danger_accept_invalid_certs {
+            eprintln!("⚠️  WARNING: TLS certificate verification is DISABLED for provider {}; do not use this in production", provider.name());
+        }
+    }
+
+    // Give providers that start alongside the gateway a chance to come up
+    if let Some(secs) = config.startup_discovery_timeout_secs {
+        let pending = config.wait_for_discovery(std::time::Duration::from_secs(secs)).await;
+        if !pending.is_empty() {
+            eprintln!("Warning: Starting without models from {} after {}s", pending.join(", "), secs);
+        }
     }
 
+    // Work out where to listen before the config moves into the server
+    let listen = config.listen_address()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let unix_socket_mode = config.unix_socket_mode;
+    let stream_request_bodies = config.stream_request_bodies;
+    let json_body_limit = config.json_body_limit();
+
+    // Runtime state is shared by all workers
+    let state = web::Data::new(AppState::new(&config));
+    let standby_probe_interval = config.standby_probe_interval();
+    let live = web::Data::new(LiveConfig::new(config));
+
+    // Keep standby providers warm so they are ready the moment the primaries fail
+    if let Some(interval) = standby_probe_interval {
+        let live = live.clone();
+        let state = state.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                live.current().probe_standby_providers(&state).await;
+            }
+        });
+    }
+
+    // SIGHUP applies an edited config.toml without dropping connections
+    #[cfg(unix)]
+    actix_web::rt::spawn(reload::reload_on_sighup(live.clone(), state.clone()));
+
     // Create and run HTTP server
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(config.clone()))
-            .wrap(Logger::default())
-            .wrap(ApiKeyAuth) // Add API key authentication middleware
-            .route("/v1/models", web::get().to(handlers::models_endpoint))
-            .route("/v1/chat/completions", web::post().to(handlers::chat_completions))
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
-}
\ No newline at end of file
+    let server = HttpServer::new(move || app(live.clone(), state.clone(), json_body_limit, stream_request_bodies));
+
+    let server = match listen {
+        ListenAddress::Tcp((host, port)) => {
+            println!("Listening on {}:{}", host, port);
+            server.bind((host, port))?
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            use std::os::unix::fs::PermissionsExt;
+
+            // A socket file left behind by a previous run would make the bind fail
+            if std::fs::metadata(&path).is_ok() {
+                std::fs::remove_file(&path)?;
+            }
+            let server = server.bind_uds(&path)?;
+            if let Some(mode) = unix_socket_mode {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            }
+            println!("Listening on unix:{}", path);
+            server
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => {
+            let _ = unix_socket_mode;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix_socket is only supported on Unix platforms",
+            ));
+        }
+    };
+
+    server.run().await
+}
+
+/// The gateway application with every route and middleware, built for each worker
+fn app(
+    live: web::Data<LiveConfig>,
+    state: web::Data<AppState>,
+    json_body_limit: usize,
+    stream_request_bodies: bool,
+) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>> {
+    App::new()
+        .app_data(live)
+        .app_data(state)
+        .app_data(web::JsonConfig::default().limit(json_body_limit))
+        .wrap(ApiKeyAuth) // Add API key authentication middleware
+        .wrap(HeaderLimits) // Reject oversized headers before authentication
+        .wrap(ConnectionLimits) // Cap the requests in flight from each client IP
+        .wrap(ErrorHandlers::new().default_handler(middleware::openai_error_body)) // Every error is OpenAI-style JSON
+        .route("/v1/models", web::get().to(handlers::models_endpoint))
+        // Availability probes get the same headers with the body left out
+        .route("/v1/models", web::head().to(handlers::models_endpoint))
+        // Requests naming their model in a header can skip body buffering when enabled
+        .route("/v1/chat/completions", web::post()
+            .guard(guard::fn_guard(move |ctx| {
+                stream_request_bodies && ctx.head().headers().contains_key(proxy::MODEL_HEADER)
+            }))
+            .to(handlers::chat_completions_streamed_body))
+        .route("/v1/chat/completions", web::post().to(handlers::chat_completions))
+        .route("/v1/embeddings", web::post().to(handlers::embeddings))
+        .route("/version", web::get().to(handlers::version_endpoint))
+        .route("/health", web::get().to(handlers::health_endpoint))
+        .route("/metrics", web::get().to(handlers::metrics_endpoint))
+        .route("/admin/requests", web::get().to(handlers::admin_requests))
+        .route("/admin/providers", web::get().to(handlers::admin_providers))
+        .route("/admin/models", web::get().to(handlers::admin_models))
+        .route("/admin/maintenance", web::post().to(handlers::admin_set_maintenance))
+        .route("/admin/providers/{name}/disable", web::post().to(handlers::admin_disable_provider))
+        .route("/admin/providers/{name}/enable", web::post().to(handlers::admin_enable_provider))
+        .default_service(web::to(handlers::not_found))
+}
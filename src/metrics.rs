@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Bucket bounds in seconds for request latency histograms
+pub const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Bucket bounds in bytes for request and response size histograms
+pub const SIZE_BUCKETS: &[f64] = &[256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0];
+
+/// Label names and values of one series, in a fixed order
+pub type Labels = Vec<(&'static str, String)>;
+
+/// In-memory metrics registry rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<&'static str, BTreeMap<Labels, u64>>>,
+    histograms: Mutex<BTreeMap<&'static str, BTreeMap<Labels, Histogram>>>,
+}
+
+/// Cumulative-on-render histogram with fixed bucket bounds
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    /// Add one to a counter series
+    pub fn increment(&self, name: &'static str, labels: Labels) {
+        self.add(name, labels, 1);
+    }
+
+    /// Add `amount` to a counter series
+    pub fn add(&self, name: &'static str, labels: Labels, amount: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry(name).or_default().entry(labels).or_default() += amount;
+    }
+
+    /// Record an observation in a histogram series with the given bucket bounds
+    pub fn observe(&self, name: &'static str, bounds: &'static [f64], labels: Labels, value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(name).or_default().entry(labels).or_insert_with(|| Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        });
+        if let Some(i) = histogram.bounds.iter().position(|bound| value <= *bound) {
+            histogram.counts[i] += 1;
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    /// Render every recorded series, followed by `gauges` computed by the caller
+    pub fn render(&self, gauges: &[(&'static str, Labels, f64)]) -> String {
+        let mut out = String::new();
+
+        for (name, series) in self.counters.lock().unwrap().iter() {
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (labels, value) in series {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+            }
+        }
+
+        for (name, series) in self.histograms.lock().unwrap().iter() {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (labels, histogram) in series {
+                let mut cumulative = 0;
+                for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+                    cumulative += count;
+                    let le = bound.to_string();
+                    let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some(&le)), cumulative);
+                }
+                let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), histogram.count);
+                let _ = writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), histogram.sum);
+                let _ = writeln!(out, "{}_count{} {}", name, format_labels(labels, None), histogram.count);
+            }
+        }
+
+        let mut last_gauge = None;
+        for (name, labels, value) in gauges {
+            if last_gauge != Some(*name) {
+                let _ = writeln!(out, "# TYPE {} gauge", name);
+                last_gauge = Some(*name);
+            }
+            let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+        }
+
+        out
+    }
+}
+
+/// `{name="value",...}` with an optional trailing `le` label, or nothing without labels
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut parts: Vec<String> = labels.iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{}\"", le));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -1,12 +1,103 @@
-use std::future::{ready, Ready};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
 
 use actix_web::{
+    body::{BodySize, MessageBody},
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, web,
-    http::header::AUTHORIZATION,
+    Error, HttpResponse, web::{self, Bytes},
+    http::{header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE}, StatusCode},
+    middleware::ErrorHandlerResponse,
 };
 use futures_util::future::LocalBoxFuture;
-use crate::config::Config;
+use serde_json::json;
+use crate::reload::LiveConfig;
+use crate::state::{AppState, ConnectionGuard};
+
+/// Replace framework error responses (unparseable bodies, unknown methods, ...) with OpenAI-style JSON
+/// Responses that already carry a JSON or upstream body are left alone
+pub fn openai_error_body<B: MessageBody>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let is_json = res.response().headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let is_empty = matches!(res.response().body().size(), BodySize::None | BodySize::Sized(0));
+    if is_json || (res.response().error().is_none() && !is_empty) {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+
+    let status = res.status();
+    let message = res.response().error()
+        .map(|e| e.to_string())
+        .unwrap_or_else(|| status.canonical_reason().unwrap_or("Error").to_string());
+    let error_type = match status {
+        StatusCode::UNAUTHORIZED => "authentication_error",
+        StatusCode::FORBIDDEN => "permission_error",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+        status if status.is_server_error() => "internal_error",
+        _ => "invalid_request_error",
+    };
+
+    let (req, res) = res.into_parts();
+    let mut response = HttpResponse::build(status);
+    for (name, value) in res.headers().iter().filter(|(name, _)| *name != CONTENT_TYPE) {
+        response.append_header((name.clone(), value.clone()));
+    }
+    let response = response.json(json!({
+        "error": {
+            "message": message,
+            "type": error_type
+        }
+    }));
+    Ok(ErrorHandlerResponse::Response(ServiceResponse::new(req, response).map_into_right_body()))
+}
+
+/// Redacted identifier of the client's API key, safe to log and expose to admins
+/// Keeps only the first and last four characters of the key from the `Authorization` header
+pub fn client_key_id(headers: &HeaderMap) -> Option<String> {
+    let key = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")?;
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 8 {
+        return Some("***".to_string());
+    }
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    Some(format!("{}...{}", head, tail))
+}
+
+/// Lines of a request's log trace to print: failures in full, successes only when sampled
+fn printed_trace(trace: Vec<String>, sampled: bool, failed: bool) -> Vec<String> {
+    if failed || sampled { trace } else { Vec::new() }
+}
+
+fn print_trace(lines: Vec<String>) {
+    for line in &lines {
+        println!("{}", line);
+    }
+    #[cfg(test)]
+    tests::PRINTED.lock().unwrap().extend(lines);
+}
+
+/// Await the service's response, then print the trace ending in the request's access log line
+/// Error responses are printed in full; successes only when sampled
+fn logged<B: 'static>(
+    response: impl Future<Output = Result<ServiceResponse<B>, Error>> + 'static,
+    mut trace: Vec<String>,
+    request_line: String,
+    sampled: bool,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>> {
+    let started = Instant::now();
+    Box::pin(async move {
+        let res = response.await;
+        let status = match &res {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        trace.push(format!("Access: {} {} {:.6}s", request_line, status.as_u16(), started.elapsed().as_secs_f64()));
+        print_trace(printed_trace(trace, sampled, status.is_client_error() || status.is_server_error()));
+        res
+    })
+}
 
 pub struct ApiKeyAuth;
 
@@ -48,77 +139,180 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         // Get the configuration from app data
-        let config = req.app_data::<web::Data<Config>>().map(|data| data.as_ref().clone());
+        let config = req.app_data::<web::Data<LiveConfig>>().map(|live| live.current());
         let path = req.path().to_string();
+
+        // Successful requests are only logged when sampled, access line included; failures are always logged in full
+        let sampled = config.as_ref().is_none_or(|config| config.sample_log());
+        let mut trace = vec![format!("Middleware: Processing request to {}", path)];
+        let flush = |trace: Vec<String>, failed: bool| print_trace(printed_trace(trace, sampled, failed));
+        let peer = req.peer_addr().map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+        let request_line = format!("{} \"{} {}\"", peer, req.method(), path);
         
-        println!("Middleware: Processing request to {}", path);
-        
-        // Skip authentication for /v1/models endpoint (optional)
-        if path == "/v1/models" {
-            println!("Middleware: Skipping authentication for /v1/models");
-            let fut = self.service.call(req);
-            return Box::pin(async move {
-                let res = fut.await?;
-                Ok(res)
-            });
+        // Skip authentication for /v1/models, /version and /health endpoints (optional)
+        if path == "/v1/models" || path == "/version" || path == "/health" {
+            trace.push(format!("Middleware: Skipping authentication for {}", path));
+            return logged(self.service.call(req), trace, request_line, sampled);
         }
 
         // If no config is provided, skip authentication (for development)
         if config.is_none() {
-            println!("Middleware: No config found, skipping authentication");
-            let fut = self.service.call(req);
-            return Box::pin(async move {
-                let res = fut.await?;
-                Ok(res)
-            });
+            trace.push("Middleware: No config found, skipping authentication".to_string());
+            // Running without authentication is worth seeing on every request
+            return logged(self.service.call(req), trace, request_line, true);
         }
 
         let config = config.unwrap();
-        println!("Middleware: Config found, server_api_key: {:?}", config.server_api_key);
+        trace.push(format!("Middleware: Config found, server_api_key configured: {}", config.server_api_key.is_some()));
+
+        // Admin endpoints only accept the admin API key
+        let is_admin_path = path.starts_with("/admin/");
         
+        // Refuse to guess which of several Authorization headers to trust
+        if req.headers().get_all(AUTHORIZATION).count() > 1 {
+            trace.push("Middleware: Multiple auth headers found, returning 400".to_string());
+            flush(trace, true);
+            let message = "Multiple Authorization headers are not allowed";
+            let response = HttpResponse::BadRequest().json(serde_json::json!({
+                "error": {
+                    "message": message,
+                    "type": "invalid_request_error",
+                    "code": "duplicate_authorization_header"
+                }
+            }));
+            return Box::pin(async move {
+                Err(actix_web::error::InternalError::from_response(message, response).into())
+            });
+        }
+
         // Extract API key from Authorization header
         let auth_header = req.headers().get(AUTHORIZATION).cloned();
+        // Keys are only ever logged redacted
+        let key_id = client_key_id(req.headers()).unwrap_or_default();
         
         let api_key_valid = match auth_header {
             Some(header_value) => {
                 if let Ok(auth_str) = header_value.to_str() {
-                    println!("Middleware: Found auth header: {}", auth_str);
                     // Check for "Bearer " prefix
-                    if auth_str.starts_with("Bearer ") {
-                        let provided_key = &auth_str[7..]; // Remove "Bearer " prefix
-                        println!("Middleware: Extracted API key: {}", provided_key);
-                        let is_valid = config.validate_api_key(provided_key);
-                        println!("Middleware: API key validation result: {}", is_valid);
+                    if let Some(provided_key) = auth_str.strip_prefix("Bearer ") {
+                        trace.push(format!("Middleware: Extracted API key: {}", key_id));
+                        let is_valid = if is_admin_path {
+                            config.validate_admin_key(provided_key)
+                        } else {
+                            config.validate_api_key(provided_key)
+                        };
+                        trace.push(format!("Middleware: API key validation result: {}", is_valid));
                         is_valid
                     } else {
-                        println!("Middleware: No Bearer prefix found");
+                        trace.push("Middleware: No Bearer prefix found".to_string());
                         false
                     }
                 } else {
-                    println!("Middleware: Invalid auth header format");
+                    trace.push("Middleware: Invalid auth header format".to_string());
                     false
                 }
             }
             None => {
-                println!("Middleware: No auth header found");
+                trace.push("Middleware: No auth header found".to_string());
                 false
             }
         };
 
         if !api_key_valid {
-            println!("Middleware: Authentication failed, returning 401");
+            trace.push("Middleware: Authentication failed, returning 401".to_string());
+            flush(trace, true);
             // Return 401 Unauthorized if API key is invalid
+            let message = "Invalid API key";
+            let response = HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": {
+                    "message": message,
+                    "type": "authentication_error",
+                    "code": "invalid_api_key"
+                }
+            }));
             return Box::pin(async move {
-                Err(actix_web::error::ErrorUnauthorized(serde_json::json!({
-                    "error": {
-                        "message": "Invalid API key",
-                        "type": "authentication_error"
-                    }
-                })))
+                Err(actix_web::error::InternalError::from_response(message, response).into())
+            });
+        }
+
+        trace.push("Middleware: Authentication successful, proceeding to service".to_string());
+        logged(self.service.call(req), trace, request_line, sampled)
+    }
+}
+
+/// Rejects requests whose headers exceed the configured size limits
+/// Runs before authentication so oversized values never reach header parsing
+pub struct HeaderLimits;
+
+impl<S, B> Transform<S, ServiceRequest> for HeaderLimits
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = HeaderLimitsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HeaderLimitsMiddleware { service }))
+    }
+}
+
+pub struct HeaderLimitsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for HeaderLimitsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let (max_total, max_value) = match req.app_data::<web::Data<LiveConfig>>().map(|live| live.current()) {
+            Some(config) => (config.max_header_bytes(), config.max_header_value_bytes()),
+            None => (crate::config::DEFAULT_MAX_HEADER_BYTES, crate::config::DEFAULT_MAX_HEADER_VALUE_BYTES),
+        };
+
+        let mut total = 0;
+        let mut oversized = None;
+        for (name, value) in req.headers().iter() {
+            total += name.as_str().len() + value.len();
+            if value.len() > max_value {
+                oversized = Some(name.as_str().to_string());
+                break;
+            }
+        }
+
+        let message = match oversized {
+            Some(name) => Some(format!("Header '{}' exceeds the maximum value length of {} bytes", name, max_value)),
+            None if total > max_total => Some(format!("Request headers exceed the maximum total size of {} bytes", max_total)),
+            None => None,
+        };
+
+        if let Some(message) = message {
+            println!("Middleware: Rejecting request to {}: {}", req.path(), message);
+            let response = HttpResponse::build(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE).json(serde_json::json!({
+                "error": {
+                    "message": message,
+                    "type": "invalid_request_error",
+                    "code": "request_header_fields_too_large"
+                }
+            }));
+            return Box::pin(async move {
+                Err(actix_web::error::InternalError::from_response(message, response).into())
             });
         }
 
-        println!("Middleware: Authentication successful, proceeding to service");
         let fut = self.service.call(req);
         Box::pin(async move {
             let res = fut.await?;
@@ -126,3 +320,264 @@ where
         })
     }
 }
+
+/// Limits the requests in flight from each client IP to `max_connections_per_ip`
+/// Runs before everything else so rejected clients cost as little as possible
+pub struct ConnectionLimits;
+
+impl<S, B> Transform<S, ServiceRequest> for ConnectionLimits
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Response = ServiceResponse<GuardedBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ConnectionLimitsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ConnectionLimitsMiddleware { service }))
+    }
+}
+
+pub struct ConnectionLimitsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ConnectionLimitsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Response = ServiceResponse<GuardedBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let config = req.app_data::<web::Data<LiveConfig>>().map(|live| live.current());
+        let state = req.app_data::<web::Data<AppState>>();
+        // Unix socket clients have no IP to count against
+        let ip = req.peer_addr().map(|addr| addr.ip());
+
+        let mut guard = None;
+        if let (Some(config), Some(state), Some(ip)) = (config, state, ip)
+            && let Some(max) = config.max_connections_per_ip
+            && !config.is_trusted_ip(ip)
+        {
+            match state.acquire_connection(ip, max) {
+                Some(acquired) => guard = Some(acquired),
+                None => {
+                    println!("Middleware: Rejecting request to {} from {}: {} requests already in flight", req.path(), ip, max);
+                    let message = format!("Too many concurrent requests from this client (limit {})", max);
+                    let response = HttpResponse::TooManyRequests().json(serde_json::json!({
+                        "error": {
+                            "message": message,
+                            "type": "rate_limit_error",
+                            "code": "too_many_connections"
+                        }
+                    }));
+                    return Box::pin(async move {
+                        Err(actix_web::error::InternalError::from_response(message, response).into())
+                    });
+                }
+            }
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            // Streamed responses keep the slot until their body has been sent
+            Ok(res.map_body(|_, body| GuardedBody { body, _guard: guard }))
+        })
+    }
+}
+
+/// Response body that holds its client's connection slot until it is dropped
+pub struct GuardedBody<B> {
+    body: B,
+    _guard: Option<ConnectionGuard>,
+}
+
+impl<B: MessageBody + Unpin> MessageBody for GuardedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use std::sync::Mutex;
+    use crate::testing::{ok_completion, Gateway, MockProvider};
+
+    /// Every trace line the middleware printed, across the gateways of all tests
+    pub static PRINTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    const LIMITS: &str = r#"
+        max_header_bytes = 2048
+        max_header_value_bytes = 512
+        providers = []
+    "#;
+
+    /// Error code of a refused response
+    async fn code(response: reqwest::Response) -> Value {
+        let body: Value = response.json().await.unwrap();
+        body["error"]["code"].clone()
+    }
+
+    #[actix_web::test]
+    async fn oversized_header_values_are_refused() {
+        let gateway = Gateway::start(LIMITS).await;
+        let response = gateway.get("/health").header("x-padding", "a".repeat(600)).send().await.unwrap();
+        assert_eq!(response.status(), 431);
+        assert_eq!(code(response).await, "request_header_fields_too_large");
+
+        // An oversized Authorization header is refused before authentication looks at it
+        let response = gateway.get("/v1/chat/completions")
+            .header("authorization", format!("Bearer {}", "k".repeat(600)))
+            .send().await.unwrap();
+        assert_eq!(response.status(), 431);
+    }
+
+    #[actix_web::test]
+    async fn oversized_header_totals_are_refused() {
+        let gateway = Gateway::start(LIMITS).await;
+        let mut request = gateway.get("/health");
+        for i in 0..5 {
+            request = request.header(format!("x-padding-{}", i), "a".repeat(500));
+        }
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status(), 431);
+        assert_eq!(code(response).await, "request_header_fields_too_large");
+
+        let response = gateway.get("/health").header("x-padding", "a".repeat(500)).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn duplicate_authorization_headers_are_refused() {
+        let gateway = Gateway::start(r#"
+            server_api_key = "secret"
+            providers = []
+        "#).await;
+        let response = gateway.post("/v1/chat/completions", serde_json::json!({"model": "m1"}))
+            .header("authorization", "Bearer secret")
+            .header("authorization", "Bearer other")
+            .send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(code(response).await, "duplicate_authorization_header");
+
+        // One valid header gets past authentication, to a model nobody serves
+        let response = gateway.post("/v1/chat/completions", serde_json::json!({"model": "m1"}))
+            .header("authorization", "Bearer secret")
+            .send().await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn every_error_is_openai_shaped_json() {
+        let gateway = Gateway::start("providers = []").await;
+
+        let unknown = gateway.post("/v1/foo", serde_json::json!({})).send().await.unwrap();
+        assert_eq!(unknown.status(), 404);
+        let body: Value = unknown.json().await.unwrap();
+        assert_eq!(body["error"]["message"], "Unknown request URL: POST /v1/foo");
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["code"], "unknown_url");
+
+        // Extractor errors carry no body of their own until the error handler adds one
+        let malformed = gateway.post("/v1/chat/completions", serde_json::json!({}))
+            .header("content-type", "application/json")
+            .body("{not json")
+            .send().await.unwrap();
+        assert_eq!(malformed.status(), 400);
+        let body: Value = malformed.json().await.unwrap();
+        assert!(body["error"]["message"].is_string());
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+
+        let gateway = Gateway::start("server_api_key = \"server-key\"\nproviders = []").await;
+        let unauthorized = gateway.chat("m1").await;
+        assert_eq!(unauthorized.status(), 401);
+        assert!(unauthorized.headers()["content-type"].to_str().unwrap().starts_with("application/json"));
+        let body: Value = unauthorized.json().await.unwrap();
+        assert_eq!(body["error"]["message"], "Invalid API key");
+        assert_eq!(body["error"]["type"], "authentication_error");
+        assert_eq!(body["error"]["code"], "invalid_api_key");
+    }
+
+    #[actix_web::test]
+    async fn failures_are_logged_even_when_sampled_out() {
+        let gateway = Gateway::start(r#"
+            admin_api_key = "admin"
+            log_sample_rate = 0.0
+            [[providers]]
+            name = "sampled-out-present"
+            base_url = "http://127.0.0.1:1/v1"
+            models = ["m1"]
+        "#).await;
+        let enable = |name: &str| gateway.post(&format!("/admin/providers/{}/enable", name), serde_json::json!({}))
+            .bearer_auth("admin")
+            .send();
+        // Paths unique to this test, as other tests print traces concurrently
+        let printed = |path: &str| PRINTED.lock().unwrap().iter()
+            .filter(|line| line.contains(path))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        assert_eq!(enable("sampled-out-present").await.unwrap().status(), 200);
+        assert!(printed("sampled-out-present").is_empty());
+
+        assert_eq!(enable("sampled-out-missing").await.unwrap().status(), 404);
+        let trace = printed("sampled-out-missing");
+        assert_eq!(trace.first().map(String::as_str), Some("Middleware: Processing request to /admin/providers/sampled-out-missing/enable"));
+        assert!(trace.iter().any(|line| line.starts_with("Access: 127.0.0.1 \"POST /admin/providers/sampled-out-missing/enable\" 404 ")), "{:?}", trace);
+    }
+
+    /// Gateway capped at one request in flight per client IP, before a provider that takes 300 ms to answer
+    async fn one_connection_per_ip(upstream: &MockProvider, settings: &str) -> Gateway {
+        Gateway::start(&format!(r#"
+            max_connections_per_ip = 1
+            {}
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, settings, upstream.url)).await
+    }
+
+    #[actix_web::test]
+    async fn requests_over_the_per_ip_limit_are_refused() {
+        let upstream = MockProvider::start_delayed(std::time::Duration::from_millis(300), |_, _| ok_completion("m1", "hi")).await;
+        let gateway = one_connection_per_ip(&upstream, "").await;
+
+        let (first, second) = futures_util::join!(gateway.chat("m1"), gateway.chat("m1"));
+        let mut statuses = [first.status().as_u16(), second.status().as_u16()];
+        statuses.sort();
+        assert_eq!(statuses, [200, 429]);
+        let refused = if first.status() == 429 { first } else { second };
+        assert_eq!(code(refused).await, "too_many_connections");
+
+        // The slot is released once the response has been sent
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn trusted_networks_are_not_limited() {
+        let upstream = MockProvider::start_delayed(std::time::Duration::from_millis(300), |_, _| ok_completion("m1", "hi")).await;
+        let gateway = one_connection_per_ip(&upstream, r#"trusted_cidrs = ["127.0.0.0/8"]"#).await;
+
+        let (first, second) = futures_util::join!(gateway.chat("m1"), gateway.chat("m1"));
+        assert_eq!(first.status(), 200);
+        assert_eq!(second.status(), 200);
+    }
+}
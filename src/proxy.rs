@@ -0,0 +1,3038 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use actix_web::{error::InternalError, http::header::AUTHORIZATION, web::{self, Bytes}, HttpRequest, HttpResponse, Result};
+use futures_util::{stream, StreamExt};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+use crate::coalesce::{Flight, SharedResponse};
+use crate::config::{
+    Config, EmptyStreamPolicy, ForwardUser, MaxRpsMode, NewerFieldsPolicy, Provider, RouteSource, StreamFormat, UnsupportedNPolicy,
+    MAX_REDIRECTS, NEWER_FIELDS,
+};
+use crate::metrics::{LATENCY_BUCKETS, SIZE_BUCKETS};
+use crate::middleware::client_key_id;
+use crate::state::{unix_timestamp, AppState, RequestLogEntry};
+use crate::streaming::{self, StreamOptions};
+use crate::template::{self, PromptVariables};
+use crate::{functions, routing, tokens};
+
+/// Response header noting the `n` the client asked for when it was lowered to 1
+const N_DOWNGRADED_HEADER: &str = "X-N-Downgraded-From";
+
+/// Response header with the number of upstream requests made, when `attempt_headers` is enabled
+const UPSTREAM_ATTEMPTS_HEADER: &str = "X-Upstream-Attempts";
+
+/// Response header saying whether a provider other than the primary answered
+const FAILOVER_USED_HEADER: &str = "X-Failover-Used";
+
+/// Prefix of request headers overriding a body parameter, e.g. `X-Override-Temperature`
+const OVERRIDE_HEADER_PREFIX: &str = "x-override-";
+
+/// Histogram of request body sizes, when `size_metrics` is enabled
+const REQUEST_SIZE_METRIC: &str = "gateway_request_size_bytes";
+
+/// Histogram of response body sizes, when `size_metrics` is enabled
+const RESPONSE_SIZE_METRIC: &str = "gateway_response_size_bytes";
+
+/// Histogram of the time from sending a streaming request upstream to its first content
+const TTFT_METRIC: &str = "gateway_time_to_first_token_seconds";
+
+/// Request header naming the model when the body is streamed through unparsed
+pub const MODEL_HEADER: &str = "X-Model";
+
+/// Request header forcing a fresh upstream call when `coalesce_requests` is enabled
+const NO_COALESCE_HEADER: &str = "X-No-Coalesce";
+
+/// Counter of coalescing decisions, labelled `coalesced`, `unique` or `bypassed`
+const COALESCE_METRIC: &str = "gateway_coalesce_total";
+
+/// Response header telling whether a cacheable response came from the response cache, `HIT` or `MISS`
+const CACHE_HEADER: &str = "X-Cache";
+
+/// Counter of response cache lookups, labelled `hit`, `miss` or `bypassed`
+const CACHE_METRIC: &str = "gateway_cache_total";
+
+/// Request header naming providers to leave out for one request, honored for the admin API key only
+const EXCLUDE_PROVIDERS_HEADER: &str = "X-Exclude-Providers";
+
+/// Request header setting the timeout of one request in seconds, when `allow_timeout_override` is enabled
+const REQUEST_TIMEOUT_HEADER: &str = "X-Request-Timeout";
+
+/// Response header with the timeout actually used, when `X-Request-Timeout` asked for more than the maximum
+const TIMEOUT_CLAMPED_HEADER: &str = "X-Request-Timeout-Clamped";
+
+/// Request header attributing a request to a team for accounting, checked against `cost_centers`
+const COST_CENTER_HEADER: &str = "X-Cost-Center";
+
+/// Label value of cost centers outside `cost_centers` when they are not refused
+const OTHER_COST_CENTER: &str = "other";
+
+/// Label value of requests without a cost center when one is not required
+const NO_COST_CENTER: &str = "none";
+
+/// Top-level fields of an OpenAI chat completions request, accepted by `strict_fields`
+const KNOWN_CHAT_FIELDS: &[&str] = &[
+    "model", "messages", "audio", "frequency_penalty", "function_call", "functions", "logit_bias",
+    "logprobs", "max_completion_tokens", "max_tokens", "metadata", "modalities", "n",
+    "parallel_tool_calls", "prediction", "presence_penalty", "reasoning_effort", "response_format",
+    "seed", "service_tier", "stop", "store", "stream", "stream_options", "temperature", "tool_choice",
+    "tools", "top_logprobs", "top_p", "user", "web_search_options",
+];
+
+/// `Retry-After` of requests refused while a half-open provider's trial requests are in flight
+const HALF_OPEN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Number of body chunks buffered between the client and the provider on the streamed body path
+const PAYLOAD_CHANNEL_CHUNKS: usize = 8;
+
+/// Incoming W3C trace context header, continued on forwarded requests when `trace_propagation` is enabled
+const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Vendor trace state accompanying `traceparent`, passed upstream unchanged
+const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Values taken from the client request that follow it down to every provider attempt
+#[derive(Debug, Default)]
+struct RequestContext {
+    /// Team the request is attributed to, when `cost_centers` is configured
+    cost_center: Option<String>,
+    /// Headers added to every request sent upstream for this client request
+    upstream_headers: Vec<(&'static str, String)>,
+    /// Redacted id of the client's API key
+    client: Option<String>,
+    /// Timeout in seconds asked for with `X-Request-Timeout`, replacing the configured one
+    timeout_secs: Option<u64>,
+}
+
+/// Request body adjusted for one provider, plus headers describing the adjustments
+struct PreparedRequest {
+    body: Value,
+    response_headers: Vec<(&'static str, String)>,
+}
+
+/// Why an attempt against a provider produced no response for the client
+enum AttemptFailure {
+    /// The request cannot be sent to this provider as-is
+    Rejected(HttpResponse),
+    /// The provider could not be reached
+    Connection(reqwest::Error),
+    /// The streaming deadline passed before the provider responded
+    Timeout,
+    /// The provider was at its `max_rps`
+    Throttled,
+    /// The provider's breaker is half-open with no trial request left
+    Unavailable,
+    /// The provider's response was over its size limit
+    ResponseTooLarge(usize),
+    /// The provider redirected the request to this location without it being followed
+    Redirected(String),
+    /// The provider's successful response was unusable, for this reason
+    InvalidResponse(&'static str),
+    /// The provider answered with an error response
+    Upstream(UpstreamError),
+}
+
+/// Upstream headers kept on an error response passed back to the client
+const KEPT_ERROR_HEADERS: [reqwest::header::HeaderName; 2] = [CONTENT_TYPE, reqwest::header::RETRY_AFTER];
+
+/// Error response of a provider that was failed over, kept to answer with if no other provider succeeds
+struct UpstreamError {
+    status: reqwest::StatusCode,
+    headers: reqwest::header::HeaderMap,
+    body: Bytes,
+}
+
+impl UpstreamError {
+    /// Read the error response, giving up on its body at the deadline or past the size limit
+    async fn read(response: reqwest::Response, max: Option<usize>, deadline: Option<Instant>) -> Self {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, read_body(response, max)).await.ok().flatten(),
+            None => read_body(response, max).await,
+        };
+        UpstreamError { status, headers, body: body.unwrap_or_default() }
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let status = actix_web::http::StatusCode::from_u16(self.status.as_u16())
+            .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY);
+        let mut builder = HttpResponse::build(status);
+        for name in KEPT_ERROR_HEADERS {
+            if let Some(value) = self.headers.get(&name).and_then(|v| v.to_str().ok()) {
+                builder.insert_header((name.as_str(), value));
+            }
+        }
+        builder.body(self.body)
+    }
+}
+
+/// Resolve the providers serving the request's model and forward the JSON body to `path`
+/// Every forwarded request is recorded in the recent request log
+pub async fn forward_request(
+    req: &HttpRequest,
+    config: &Config,
+    state: &AppState,
+    path: &str,
+    mut body: Value,
+) -> Result<HttpResponse> {
+    if state.in_maintenance() {
+        return Ok(maintenance_unavailable(config));
+    }
+    let routed = without_excluded_providers(req, config);
+    let config: &Config = &routed;
+
+    // Extract model name from request
+    let model = body.get("model")
+        .and_then(|m| m.as_str())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Missing model field"))?
+        .to_string();
+    if let Some(observe) = size_observer(config, state, REQUEST_SIZE_METRIC, &model) {
+        // The body was parsed already; its declared length is the size received
+        let size = req.headers().get(actix_web::http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| body.to_string().len() as u64);
+        observe(size);
+    }
+    let timeout = timeout_override(req, config)?;
+    let context = RequestContext {
+        cost_center: cost_center(req, config)?,
+        upstream_headers: trace_headers(req, config),
+        client: client_key_id(req.headers()),
+        timeout_secs: timeout.map(|timeout| timeout.secs),
+    };
+    if path == "chat/completions" {
+        check_known_fields(config, &body)?;
+    }
+    apply_param_overrides(req, config, &mut body)?;
+    inject_user(req, config, &mut body);
+    if config.log_request_bodies && config.sample_log() {
+        println!("{}", request_body_log_line(config, path, &body));
+    }
+
+    let started = Instant::now();
+    let mut attempted_provider = None;
+    let result = forward_cached(req, config, state, path, &model, &context, body, &mut attempted_provider).await;
+    record_request(req, state, model, context.cost_center, attempted_provider, started, &result);
+    result.map(|response| with_clamp_note(response, timeout))
+}
+
+/// Log line printed for a request body with `log_request_bodies`, its `log_redact_fields` replaced
+fn request_body_log_line(config: &Config, path: &str, body: &Value) -> String {
+    format!("Request body for {}: {}", path, config.redacted_for_log(body))
+}
+
+/// Forward a raw request body to `path` as it arrives, without buffering or parsing it
+/// The model comes from the `X-Model` header, and only the primary provider is tried
+/// since a streamed body can't be replayed for failover
+pub async fn forward_payload(
+    req: &HttpRequest,
+    config: &Config,
+    state: &AppState,
+    path: &str,
+    mut payload: web::Payload,
+) -> Result<HttpResponse> {
+    if state.in_maintenance() {
+        return Ok(maintenance_unavailable(config));
+    }
+    let routed = without_excluded_providers(req, config);
+    let config: &Config = &routed;
+
+    let model = req.headers().get(MODEL_HEADER)
+        .and_then(|m| m.to_str().ok())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid X-Model header"))?
+        .to_string();
+    let cost_center = cost_center(req, config)?;
+    let trace_headers = trace_headers(req, config);
+    let timeout = timeout_override(req, config)?;
+
+    let started = Instant::now();
+    let mut attempted_provider = None;
+    let result = async {
+        if let Err(retry_after) = state.acquire_model_slot(config, &model) {
+            return Ok(model_rate_limited(&model, retry_after));
+        }
+        // The body isn't parsed on this path, so its content can't restrict the candidates
+        let (attempts, upstream_model) = resolve_attempts(config, state, &model, None).await?;
+        let provider = &attempts[0];
+        // The body can't be rewritten, so it can only name a model the provider knows by that id
+        if provider.last_resort_model.is_none() && provider.upstream_model_id(upstream_model) != model {
+            return Ok(renamed_model_in_streamed_body(&model));
+        }
+        attempted_provider = Some(provider.host());
+        if !await_provider_slot(config, state, provider, false).await {
+            return Ok(provider_throttled());
+        }
+        if !state.claim_circuit_probe(&provider.name()) {
+            eprintln!("Provider {} is half-open with no trial request left", provider.base_url);
+            return Ok(model_unavailable(&model, HALF_OPEN_RETRY_AFTER));
+        }
+        // Only the declared length can be checked before the body is relayed
+        let declared_size = req.headers().get(actix_web::http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if let (Some(size), Some(max)) = (declared_size, config.max_request_bytes_for(provider))
+            && size > max
+        {
+            return Ok(request_too_large(max));
+        }
+
+        // The client payload isn't Send, so it is relayed through a small bounded channel
+        let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Bytes, std::io::Error>>(PAYLOAD_CHANNEL_CHUNKS);
+        let observe_request_size = size_observer(config, state, REQUEST_SIZE_METRIC, &model);
+        actix_web::rt::spawn(async move {
+            let mut size = 0;
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()));
+                if let Ok(bytes) = &chunk {
+                    size += bytes.len() as u64;
+                }
+                if sender.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+            if let Some(observe) = observe_request_size {
+                observe(size);
+            }
+        });
+        let body = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        });
+
+        let content_type = req.headers().get(actix_web::http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/json");
+        let url = format!("{}/{}", provider.base_url.trim_end_matches('/'), path);
+        let key = provider.keys()[state.active_key_index(provider)];
+        let mut request_builder = provider.apply_headers(provider.client().post(&url), key)
+            .header(CONTENT_TYPE, content_type)
+            .body(reqwest::Body::wrap_stream(body));
+        for (name, value) in &trace_headers {
+            request_builder = request_builder.header(*name, value);
+        }
+
+        let sent_at = Instant::now();
+        let response = request_builder.send().await.map_err(|e| {
+            eprintln!("Error forwarding request to {}: {}", provider.base_url, e);
+            state.record_provider_outcome(config, &provider.name(), false);
+            actix_web::error::ErrorInternalServerError(format!("Failed to forward request: {}", describe_error(&e)))
+        })?;
+        // A streamed body can't be sent again, so redirects are never followed here
+        if response.status().is_redirection() && !provider.is_success_status(response.status().as_u16()) {
+            eprintln!("Provider {} redirected to '{}', which can't be followed for a streamed body", provider.base_url, redirect_location(&response));
+            state.record_provider_outcome(config, &provider.name(), false);
+            return Ok(upstream_redirect(&redirect_location(&response)));
+        }
+        state.record_provider_outcome(config, &provider.name(), !config.failover_statuses().contains(&response.status().as_u16()));
+
+        // Relay the response as it arrives too, whether or not it is an event stream
+        let actix_status = actix_web::http::StatusCode::from_u16(response.status().as_u16())
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+        let content_type = response.headers().get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(config.default_content_type(path))
+            .to_string();
+        let mut builder = HttpResponse::build(actix_status);
+        if let Some(location) = passed_location(provider, &response) {
+            builder.insert_header((actix_web::http::header::LOCATION, location));
+        }
+        let options = StreamOptions {
+            deadline: timeout.map(|timeout| timeout.secs).or(config.stream_timeout_secs)
+                .map(|secs| Instant::now() + Duration::from_secs(secs)),
+            on_end: size_observer(config, state, RESPONSE_SIZE_METRIC, &model),
+            max_bytes: config.max_response_bytes_for(provider).map(|max| max as u64),
+            // Comments only mean something in event streams
+            heartbeat: config.stream_heartbeat().filter(|_| content_type.starts_with("text/event-stream")),
+            on_first_content: content_type.starts_with("text/event-stream")
+                .then(|| first_token_observer(config, state, &model, provider, sent_at)),
+            ..StreamOptions::default()
+        };
+        Ok(builder
+            .content_type(content_type)
+            .streaming(streaming::passthrough(response.bytes_stream(), options)))
+    }.await;
+
+    record_request(req, state, model, cost_center, attempted_provider, started, &result);
+    result.map(|response| with_clamp_note(response, timeout))
+}
+
+/// With `strict_fields`, refuse a chat completions body with top-level fields outside the known schema
+/// and `extra_known_fields`, listing all of them in a structured 400
+fn check_known_fields(config: &Config, body: &Value) -> Result<()> {
+    if !config.strict_fields {
+        return Ok(());
+    }
+    let Some(object) = body.as_object() else {
+        return Ok(());
+    };
+    let unknown: Vec<&str> = object.keys()
+        .map(|key| key.as_str())
+        .filter(|key| !KNOWN_CHAT_FIELDS.contains(key) && !config.extra_known_fields.iter().any(|f| f == key))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    let response = HttpResponse::BadRequest().json(json!({
+        "error": {
+            "message": format!("Unrecognized request argument(s) supplied: {}", unknown.join(", ")),
+            "type": "invalid_request_error",
+            "param": unknown[0],
+            "code": "unknown_parameter",
+            "unknown_fields": unknown
+        }
+    }));
+    Err(InternalError::from_response("unknown request fields", response).into())
+}
+
+/// Merge allowed `X-Override-<Param>` header values into the body, parsed as JSON
+/// A malformed value is refused with a structured 400
+fn apply_param_overrides(req: &HttpRequest, config: &Config, body: &mut Value) -> Result<()> {
+    if !config.allow_param_overrides {
+        return Ok(());
+    }
+
+    for (name, value) in req.headers() {
+        // Header names are lowercase; `X-Override-Top-P` sets `top_p`
+        let Some(param) = name.as_str().strip_prefix(OVERRIDE_HEADER_PREFIX) else {
+            continue;
+        };
+        let param = param.replace('-', "_");
+        if !config.overridable_params.contains(&param) {
+            continue;
+        }
+
+        let parsed = value.to_str().ok().and_then(|text| serde_json::from_str::<Value>(text).ok());
+        let Some(parsed) = parsed else {
+            let response = HttpResponse::BadRequest().json(json!({
+                "error": {
+                    "message": format!("Invalid value in header '{}': expected a JSON value", name),
+                    "type": "invalid_request_error",
+                    "param": param,
+                    "code": "invalid_override"
+                }
+            }));
+            return Err(InternalError::from_response("invalid override header", response).into());
+        };
+        body[param.as_str()] = parsed;
+    }
+    Ok(())
+}
+
+/// Callback recording a body size in a per-model size histogram, when `size_metrics` is enabled
+fn size_observer(config: &Config, state: &AppState, metric: &'static str, model: &str) -> Option<Box<dyn FnOnce(u64)>> {
+    if !config.size_metrics {
+        return None;
+    }
+    let metrics = state.metrics.clone();
+    let model = model.to_string();
+    Some(Box::new(move |size| metrics.observe(metric, SIZE_BUCKETS, vec![("model", model)], size as f64)))
+}
+
+/// Callback recording the time from `sent_at` to the first streamed content in the time-to-first-token histogram
+fn first_token_observer(config: &Config, state: &AppState, model: &str, provider: &Provider, sent_at: Instant) -> Box<dyn FnOnce()> {
+    let metrics = state.metrics.clone();
+    let model = model.to_string();
+    let provider = provider.name();
+    let log = config.sample_log();
+    Box::new(move || {
+        let elapsed = sent_at.elapsed();
+        if log {
+            println!("First token for model '{}' from provider {} after {} ms", model, provider, elapsed.as_millis());
+        }
+        metrics.observe(TTFT_METRIC, LATENCY_BUCKETS, vec![("model", model), ("provider", provider)], elapsed.as_secs_f64());
+    })
+}
+
+/// The configuration to route the request with, leaving out the providers named in `X-Exclude-Providers`
+/// Names are comma-separated provider names; the header is ignored unless the request uses the admin API key
+fn without_excluded_providers<'a>(req: &HttpRequest, config: &'a Config) -> Cow<'a, Config> {
+    let Some(excluded) = req.headers().get(EXCLUDE_PROVIDERS_HEADER).and_then(|v| v.to_str().ok()) else {
+        return Cow::Borrowed(config);
+    };
+    if !is_admin_request(req, config) {
+        return Cow::Borrowed(config);
+    }
+
+    let excluded: Vec<&str> = excluded.split(',').map(str::trim).filter(|name| !name.is_empty()).collect();
+    println!("Excluding providers for this request: {}", excluded.join(", "));
+    Cow::Owned(config.with_providers(|provider| !excluded.contains(&provider.name().as_str())))
+}
+
+/// Whether the request is authenticated with the admin API key
+fn is_admin_request(req: &HttpRequest, config: &Config) -> bool {
+    req.headers().get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|key| config.validate_admin_key(key))
+}
+
+/// Timeout asked for with `X-Request-Timeout`
+#[derive(Debug, Clone, Copy)]
+struct TimeoutOverride {
+    secs: u64,
+    /// The client asked for more than `max_timeout_override_secs`
+    clamped: bool,
+}
+
+/// Read `X-Request-Timeout` when the client may set its timeout, refusing values that aren't a positive number of seconds with 400
+fn timeout_override(req: &HttpRequest, config: &Config) -> Result<Option<TimeoutOverride>> {
+    if !config.allow_timeout_override || (config.timeout_override_admin_only && !is_admin_request(req, config)) {
+        return Ok(None);
+    }
+    let Some(value) = req.headers().get(REQUEST_TIMEOUT_HEADER) else {
+        return Ok(None);
+    };
+    let Some(secs) = value.to_str().ok().and_then(|v| v.trim().parse::<u64>().ok()).filter(|secs| *secs > 0) else {
+        let response = HttpResponse::BadRequest().json(json!({
+            "error": {
+                "message": format!("Invalid {} header (expected a positive number of seconds)", REQUEST_TIMEOUT_HEADER),
+                "type": "invalid_request_error",
+                "code": "invalid_timeout"
+            }
+        }));
+        return Err(InternalError::from_response("invalid timeout", response).into());
+    };
+    let max = config.max_timeout_override_secs();
+    Ok(Some(TimeoutOverride { secs: secs.min(max), clamped: secs > max }))
+}
+
+/// Tell the client its requested timeout was lowered to the maximum
+fn with_clamp_note(mut response: HttpResponse, timeout: Option<TimeoutOverride>) -> HttpResponse {
+    if let Some(timeout) = timeout.filter(|timeout| timeout.clamped) {
+        response.headers_mut().insert(
+            TIMEOUT_CLAMPED_HEADER.parse::<actix_web::http::header::HeaderName>().expect("valid header name"),
+            actix_web::http::header::HeaderValue::from(timeout.secs),
+        );
+    }
+    response
+}
+
+/// W3C trace context headers for requests sent upstream, when `trace_propagation` is enabled
+/// A valid incoming `traceparent` is continued with a new span id for the gateway; otherwise a new trace starts
+fn trace_headers(req: &HttpRequest, config: &Config) -> Vec<(&'static str, String)> {
+    if !config.trace_propagation {
+        return Vec::new();
+    }
+    let span_id = format!("{:016x}", rand::random::<u64>().max(1));
+
+    let incoming = req.headers().get(TRACEPARENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_traceparent);
+    let Some((trace_id, flags)) = incoming else {
+        let trace_id = format!("{:032x}", rand::random::<u128>().max(1));
+        return vec![(TRACEPARENT_HEADER, format!("00-{}-{}-01", trace_id, span_id))];
+    };
+
+    let mut headers = vec![(TRACEPARENT_HEADER, format!("00-{}-{}-{}", trace_id, span_id, flags))];
+    if let Some(state) = req.headers().get(TRACESTATE_HEADER).and_then(|v| v.to_str().ok()) {
+        headers.push((TRACESTATE_HEADER, state.to_string()));
+    }
+    headers
+}
+
+/// Trace id and flags of a version 00 `traceparent`, None when malformed or all-zero
+fn parse_traceparent(value: &str) -> Option<(&str, &str)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [version, trace_id, parent_id, flags] = parts[..] else {
+        return None;
+    };
+    let is_hex = |part: &str, len: usize| part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    let valid = version == "00"
+        && is_hex(trace_id, 32) && trace_id.bytes().any(|b| b != b'0')
+        && is_hex(parent_id, 16) && parent_id.bytes().any(|b| b != b'0')
+        && is_hex(flags, 2);
+    valid.then_some((trace_id, flags))
+}
+
+/// Cost center of the request from `X-Cost-Center`, or None unless `cost_centers` is configured
+/// With `require_cost_center`, a missing or unknown value is refused with a structured 400;
+/// otherwise unknown values are counted as `other` so they can't grow the metric labels, and missing ones as `none`
+fn cost_center(req: &HttpRequest, config: &Config) -> Result<Option<String>> {
+    let Some(allowed) = &config.cost_centers else {
+        return Ok(None);
+    };
+    let value = req.headers().get(COST_CENTER_HEADER).and_then(|v| v.to_str().ok());
+    if let Some(value) = value.filter(|value| allowed.iter().any(|a| a == value)) {
+        return Ok(Some(value.to_string()));
+    }
+    if !config.require_cost_center {
+        return Ok(Some(value.map_or(NO_COST_CENTER, |_| OTHER_COST_CENTER).to_string()));
+    }
+
+    let message = match value {
+        Some(value) => format!("Unknown cost center '{}'", value),
+        None => format!("Missing required {} header", COST_CENTER_HEADER),
+    };
+    let response = HttpResponse::BadRequest().json(json!({
+        "error": {
+            "message": message,
+            "type": "invalid_request_error",
+            "code": "invalid_cost_center"
+        }
+    }));
+    Err(InternalError::from_response("invalid cost center", response).into())
+}
+
+/// Set the body's `user` field to the configured client identity, keeping any the client sent
+fn inject_user(req: &HttpRequest, config: &Config, body: &mut Value) {
+    let Some(source) = config.forward_user else {
+        return;
+    };
+    let Some(object) = body.as_object_mut() else {
+        return;
+    };
+    if object.contains_key("user") {
+        return;
+    }
+
+    let user = match source {
+        ForwardUser::KeyId => client_key_id(req.headers()),
+        // Only a digest of the address leaves the gateway
+        ForwardUser::ClientIp => req.peer_addr().map(|addr| {
+            let digest = Sha256::digest(addr.ip().to_string().as_bytes());
+            digest.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+        }),
+    };
+    if let Some(user) = user {
+        object.insert("user".to_string(), Value::String(user));
+    }
+}
+
+/// Add a finished request to the recent request log
+fn record_request(
+    req: &HttpRequest,
+    state: &AppState,
+    model: String,
+    cost_center: Option<String>,
+    provider: Option<String>,
+    started: Instant,
+    result: &Result<HttpResponse>,
+) {
+    let status = match result {
+        Ok(response) => response.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let latency = started.elapsed();
+    // Only requests that reached a provider say something about the model's reliability, and client errors don't
+    if provider.is_some() {
+        state.record_model_outcome(&model, status.as_u16() < 500 && status.as_u16() != 429);
+    }
+    state.metrics.increment("gateway_requests_total", vec![("model", model.clone()), ("status", status.as_u16().to_string())]);
+    state.metrics.observe("gateway_request_duration_seconds", LATENCY_BUCKETS, vec![("model", model.clone())], latency.as_secs_f64());
+    state.record_request(RequestLogEntry {
+        timestamp: unix_timestamp(),
+        model,
+        provider,
+        status: status.as_u16(),
+        latency_ms: latency.as_millis() as u64,
+        key_id: client_key_id(req.headers()),
+        cost_center,
+    });
+}
+
+/// Providers to try for `model`, in order, leaving out circuit-broken ones
+/// Unknown models and models outside the allow-list both produce a structured 404
+/// With `requires_vision`, providers declaring the model's capabilities must list `vision`
+/// Standby providers come after all the primaries
+async fn resolve_attempts<'a>(
+    config: &Config,
+    state: &AppState,
+    model: &'a str,
+    request: Option<&Value>,
+) -> Result<(Vec<Provider>, &'a str)> {
+    // Get model to provider mapping
+    let mapping_error = |e: Box<dyn std::error::Error>| actix_web::error::ErrorInternalServerError(format!("Failed to get model mapping: {}", e));
+    let mut model_mapping = config.get_model_mapping(&state.model_cache, false).await.map_err(mapping_error)?;
+
+    // A model missing from cached discovery may be new upstream, so look again before calling it unknown
+    let known = |mapping: &std::collections::HashMap<String, Vec<Provider>>| {
+        mapping.contains_key(model) || config.split_prefixed_model(model).is_some_and(|(_, id)| mapping.contains_key(id))
+    };
+    if config.model_cache_ttl().is_some() && !known(&model_mapping) {
+        println!("Model '{}' is not in the cached model mapping, discovering models again", model);
+        model_mapping = config.get_model_mapping(&state.model_cache, true).await.map_err(mapping_error)?;
+    }
+
+    // A provider-prefixed id only routes to the named provider
+    let routes = config.model_routes(model, model_mapping.get(model).map(Vec::as_slice).unwrap_or_default());
+    if routes.len() > 1 {
+        if config.reject_ambiguous_models {
+            return Err(InternalError::from_response("ambiguous model", ambiguous_model(model)).into());
+        }
+        println!("Model '{}' matches several routes, using {:?}", model, routes[0].source);
+    }
+    let (provider_name, upstream_model, source) = match routes.first() {
+        Some(route) => (route.provider, route.upstream_model, Some(route.source)),
+        None => (None, model, None),
+    };
+
+    // Models outside the allow-list look exactly like unknown ones
+    if !config.is_model_allowed(upstream_model) {
+        return Err(InternalError::from_response("model not found", model_not_found(model)).into());
+    }
+
+    // Find the providers for the requested model and order the attempts
+    let routed = |provider: &Provider| provider_name.is_none_or(|name| provider.name() == name);
+    // Only providers exposing the id through the mechanism that claimed it serve it
+    let claimed = |provider: &Provider| match source {
+        Some(RouteSource::Prefix) | None => true,
+        Some(source) => provider.route_source(upstream_model) == source,
+    };
+    let (last_resort, candidates): (Vec<Provider>, Vec<Provider>) = model_mapping.get(upstream_model)
+        .into_iter()
+        .flatten()
+        .filter(|provider| routed(provider) && claimed(provider))
+        .cloned()
+        .partition(|provider| provider.last_resort);
+    if candidates.is_empty() && last_resort.is_empty() {
+        return Err(InternalError::from_response("model not found", model_not_found(model)).into());
+    }
+    // A last-resort provider with a stand-in model covers every known model, in configuration order
+    let last_resort: Vec<Provider> = config.providers.iter()
+        .filter(|provider| {
+            provider.last_resort && routed(provider)
+                && (provider.last_resort_model.is_some() || last_resort.iter().any(|p| p.name() == provider.name()))
+                && state.circuit_open_for(&provider.name()).is_none()
+        })
+        .cloned()
+        .collect();
+
+    // Capabilities only restrict routing once they are declared for the model somewhere
+    let requires_vision = request.is_some_and(tokens::has_non_text_content);
+    let candidates = if requires_vision && candidates.iter().any(|p| p.capabilities_for(upstream_model).is_some()) {
+        let capable: Vec<Provider> = candidates.into_iter()
+            .filter(|p| p.capabilities_for(upstream_model).is_some_and(|c| c.iter().any(|c| c == "vision")))
+            .collect();
+        if capable.is_empty() {
+            return Err(InternalError::from_response("vision not supported", vision_unsupported(model)).into());
+        }
+        capable
+    } else {
+        candidates
+    };
+
+    let (healthy, broken): (Vec<Provider>, Vec<Provider>) = candidates.into_iter()
+        .partition(|provider| state.circuit_open_for(&provider.name()).is_none());
+    if healthy.is_empty() && last_resort.is_empty() {
+        // The model exists, so a client may retry once the first breaker closes
+        if config.unavailable_as_503 {
+            let retry_after = broken.iter()
+                .filter_map(|provider| state.circuit_open_for(&provider.name()))
+                .min()
+                .unwrap_or_default();
+            return Err(InternalError::from_response("model unavailable", model_unavailable(model, retry_after)).into());
+        }
+        return Err(InternalError::from_response("model not found", model_not_found(model)).into());
+    }
+
+    // Standby providers only follow once every primary has been tried
+    let (standby, mut primary): (Vec<Provider>, Vec<Provider>) = healthy.into_iter().partition(|provider| provider.standby);
+    if primary.is_empty() && !standby.is_empty() {
+        println!("No primary provider available for model '{}', promoting standby providers", model);
+    } else if primary.is_empty() && standby.is_empty() {
+        println!("No provider available for model '{}', falling back to last-resort providers", model);
+    }
+    let order = |providers: &[Provider]| routing::order_candidates(
+        config,
+        upstream_model,
+        providers,
+        |provider| state.health_factor(config, &provider.name()),
+        |provider| state.provider_latency(&provider.name()),
+    );
+    // A matching routing rule picks the primary when its provider is available; the rest follow as usual
+    let preferred = request
+        .and_then(|request| routing::preferred_provider(config, upstream_model, request))
+        .and_then(|name| primary.iter().position(|provider| provider.name() == name))
+        .map(|i| primary.remove(i));
+    let mut attempts: Vec<Provider> = preferred.into_iter().collect();
+    attempts.extend(order(&primary));
+    // The retry cap only limits the regular providers; standby and last-resort ones stay as fallbacks
+    if let Some(max_retries) = config.max_retries {
+        attempts.truncate(max_retries + 1);
+    }
+    attempts.extend(order(&standby));
+    attempts.extend(last_resort);
+    Ok((attempts, upstream_model))
+}
+
+/// Answer a request from the response cache when `response_cache_ttl_secs` is set, storing successful responses for later ones
+/// Streams always go upstream, and `Cache-Control: no-cache` skips the lookup but still refreshes the entry
+#[allow(clippy::too_many_arguments)]
+async fn forward_cached(
+    req: &HttpRequest,
+    config: &Config,
+    state: &AppState,
+    path: &str,
+    model: &str,
+    context: &RequestContext,
+    body: Value,
+    attempted_provider: &mut Option<String>,
+) -> Result<HttpResponse> {
+    let is_stream = body.get("stream").and_then(|s| s.as_bool()).unwrap_or(false);
+    let Some(ttl) = config.response_cache_ttl().filter(|_| !is_stream) else {
+        return forward_limited(req, config, state, path, model, context, body, attempted_provider).await;
+    };
+    let count = |outcome: &str| {
+        state.metrics.increment(CACHE_METRIC, vec![("model", model.to_string()), ("outcome", outcome.to_string())]);
+    };
+
+    let key = request_key(req, config, context, path, &body);
+    let no_cache = req.headers().get(actix_web::http::header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-cache")));
+    if no_cache {
+        count("bypassed");
+    } else if let Some(cached) = state.response_cache.get(&key) {
+        count("hit");
+        *attempted_provider = cached.provider.clone();
+        let mut response = cached.to_response();
+        response.headers_mut().insert(
+            CACHE_HEADER.parse::<actix_web::http::header::HeaderName>().expect("valid header name"),
+            actix_web::http::header::HeaderValue::from_static("HIT"),
+        );
+        return Ok(response);
+    } else {
+        count("miss");
+    }
+
+    let response = forward_limited(req, config, state, path, model, context, body, attempted_provider).await?;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+    let (mut response, shared) = SharedResponse::capture(response, attempted_provider.clone()).await;
+    if let Some(shared) = shared {
+        state.response_cache.insert(key, shared, ttl, config.response_cache_max_entries());
+    }
+    response.headers_mut().insert(
+        CACHE_HEADER.parse::<actix_web::http::header::HeaderName>().expect("valid header name"),
+        actix_web::http::header::HeaderValue::from_static("MISS"),
+    );
+    Ok(response)
+}
+
+/// Forward a request once the model's rate limit lets it through
+#[allow(clippy::too_many_arguments)]
+async fn forward_limited(
+    req: &HttpRequest,
+    config: &Config,
+    state: &AppState,
+    path: &str,
+    model: &str,
+    context: &RequestContext,
+    body: Value,
+    attempted_provider: &mut Option<String>,
+) -> Result<HttpResponse> {
+    match state.acquire_model_slot(config, model) {
+        Ok(()) => forward_coalesced(req, config, state, path, model, context, body, attempted_provider).await,
+        Err(retry_after) => Ok(model_rate_limited(model, retry_after)),
+    }
+}
+
+/// Key of identical requests, for coalescing and the response cache
+/// Requests only match when their endpoint, body and `cache_vary` headers are the same
+fn request_key(req: &HttpRequest, config: &Config, context: &RequestContext, path: &str, body: &Value) -> String {
+    // Templated system prompts can differ between clients, so their requests are only shared by the same client
+    let mut scope = if config.has_system_prompts() {
+        format!("{:?}\n{:?}\n", context.client, context.cost_center)
+    } else {
+        String::new()
+    };
+    for name in &config.cache_vary {
+        let value = req.headers().get(name.as_str()).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        scope.push_str(&format!("{}: {}\n", name.to_ascii_lowercase(), value));
+    }
+    format!("{:x}", Sha256::digest(format!("{}{}\n{}", scope, path, body).as_bytes()))
+}
+
+/// Forward a request, sharing the upstream call with identical requests in flight when `coalesce_requests` is enabled
+/// Streams, opted-out models and requests with `X-No-Coalesce: true` always go upstream on their own
+#[allow(clippy::too_many_arguments)]
+async fn forward_coalesced(
+    req: &HttpRequest,
+    config: &Config,
+    state: &AppState,
+    path: &str,
+    model: &str,
+    context: &RequestContext,
+    body: Value,
+    attempted_provider: &mut Option<String>,
+) -> Result<HttpResponse> {
+    if !config.coalesce_requests || body.get("stream").and_then(|s| s.as_bool()).unwrap_or(false) {
+        return forward_to_providers(config, state, path, model, context, body, attempted_provider).await;
+    }
+    let count = |outcome: &str| {
+        state.metrics.increment(COALESCE_METRIC, vec![("model", model.to_string()), ("outcome", outcome.to_string())]);
+    };
+
+    let bypass = req.headers().get(NO_COALESCE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("true"));
+    if bypass || config.no_coalesce_models.iter().any(|m| m == model) {
+        count("bypassed");
+        return forward_to_providers(config, state, path, model, context, body, attempted_provider).await;
+    }
+
+    let guard = match state.coalescer.join(request_key(req, config, context, path, &body)) {
+        Flight::Leader(guard) => guard,
+        Flight::Follower(receiver) => {
+            // A failed or cancelled leader leaves this request to go upstream itself
+            if let Ok(shared) = receiver.await {
+                count("coalesced");
+                *attempted_provider = shared.provider.clone();
+                return Ok(shared.to_response());
+            }
+            count("unique");
+            return forward_to_providers(config, state, path, model, context, body, attempted_provider).await;
+        }
+    };
+
+    count("unique");
+    let response = forward_to_providers(config, state, path, model, context, body, attempted_provider).await?;
+    let (response, shared) = SharedResponse::capture(response, attempted_provider.clone()).await;
+    if let Some(shared) = shared {
+        guard.complete(&shared);
+    }
+    Ok(response)
+}
+
+/// Try the providers serving `model` in order until one produces a response for the client
+/// Falls over to the remaining providers serving the model when an attempt fails
+async fn forward_to_providers(
+    config: &Config,
+    state: &AppState,
+    path: &str,
+    model: &str,
+    context: &RequestContext,
+    body: Value,
+    attempted_provider: &mut Option<String>,
+) -> Result<HttpResponse> {
+    let model = model.to_string();
+    let (attempts, upstream_model) = resolve_attempts(config, state, &model, Some(&body)).await?;
+
+    // Streaming requests are bounded by their total duration instead of a per-request timeout
+    // A model's `force_stream` decides how chat completions are called upstream, the client's `stream` how they are answered
+    let client_stream = body.get("stream").and_then(|s| s.as_bool()).unwrap_or(false);
+    let is_stream = match config.force_stream.get(upstream_model) {
+        Some(force) if path == "chat/completions" => *force,
+        _ => client_stream,
+    };
+
+    let mut last_failure = None;
+    // The last error response of a provider, preferred over failures the gateway made up itself
+    let mut last_upstream_error = None;
+    let mut attempt_errors = Vec::new();
+    let mut upstream_attempts = 0;
+    // `Retry-After` hint, if any, of each provider that answered 429
+    let mut rate_limits = Vec::new();
+
+    for (attempt, provider) in attempts.iter().enumerate() {
+        let is_last_attempt = attempt + 1 == attempts.len();
+        *attempted_provider = Some(provider.host());
+        let prepared = match prepare_request(config, provider, path, &model, upstream_model, context, &body) {
+            Ok(prepared) => prepared,
+            Err(rejection) => {
+                eprintln!("Request for model '{}' rejected by policy of {}", model, provider.base_url);
+                attempt_errors.push(json!({"provider": provider.host(), "error": "rejected by provider policy"}));
+                last_failure = Some(AttemptFailure::Rejected(rejection));
+                continue;
+            }
+        };
+
+        if let Some(max) = config.max_request_bytes_for(provider)
+            && serde_json::to_vec(&prepared.body).map_or(0, |body| body.len()) > max
+        {
+            eprintln!("Request for model '{}' is over the size limit of {}", model, provider.base_url);
+            attempt_errors.push(json!({"provider": provider.host(), "error": "request too large"}));
+            last_failure = Some(AttemptFailure::Rejected(request_too_large(max)));
+            continue;
+        }
+
+        // A request that can't fit the provider's context window would only burn an upstream call
+        if config.enforce_context_windows
+            && let Some(window) = config.context_window_for(provider, upstream_model)
+            && let estimate = tokens::estimate_request_tokens(&prepared.body)
+            && estimate > window
+        {
+            eprintln!("Request for model '{}' is estimated at {} tokens, over the context window of {} on {}", model, estimate, window, provider.base_url);
+            attempt_errors.push(json!({"provider": provider.host(), "error": "context window exceeded"}));
+            last_failure = Some(AttemptFailure::Rejected(context_window_exceeded(&model, window, estimate)));
+            continue;
+        }
+
+        if !await_provider_slot(config, state, provider, !is_last_attempt).await {
+            eprintln!("Provider {} is at its max_rps, skipping it", provider.base_url);
+            attempt_errors.push(json!({"provider": provider.host(), "error": "throttled"}));
+            last_failure = Some(AttemptFailure::Throttled);
+            continue;
+        }
+
+        // A recovering provider only takes its few trial requests until its breaker closes
+        if !state.claim_circuit_probe(&provider.name()) {
+            eprintln!("Provider {} is half-open with no trial request left, skipping it", provider.base_url);
+            attempt_errors.push(json!({"provider": provider.host(), "error": "circuit half-open"}));
+            last_failure = Some(AttemptFailure::Unavailable);
+            continue;
+        }
+
+        let url = format!("{}/{}", provider.base_url.trim_end_matches('/'), path);
+        let stream_deadline = match (is_stream, context.timeout_secs.or(config.stream_timeout_secs)) {
+            (true, Some(secs)) => Some(Instant::now() + Duration::from_secs(secs)),
+            _ => None,
+        };
+        let build_request = |url: &str, key: &str| {
+            let mut request_builder = provider.apply_headers(provider.with_body(provider.client().post(url), &prepared.body), key);
+            for (name, value) in &context.upstream_headers {
+                request_builder = request_builder.header(*name, value);
+            }
+            for (name, value) in &provider.prompt_cache_headers {
+                request_builder = request_builder.header(name, value);
+            }
+            match (is_stream, context.timeout_secs.or(config.request_timeout_secs)) {
+                (false, Some(secs)) => request_builder.timeout(Duration::from_secs(secs)),
+                _ => request_builder,
+            }
+        };
+
+        // Send request and return response
+        let keys = provider.keys();
+        let key_index = state.active_key_index(provider);
+        upstream_attempts += 1;
+        let sent_at = Instant::now();
+        let mut key = keys[key_index];
+        let mut sent = send_before(stream_deadline, build_request(&url, key)).await;
+
+        // During a key rotation the next key may already work where the active one is refused
+        if keys.len() > 1 && matches!(&sent, Some(Ok(response)) if response.status() == reqwest::StatusCode::UNAUTHORIZED) {
+            let next_index = (key_index + 1) % keys.len();
+            eprintln!("Provider {} rejected its active API key, retrying with the next one", provider.base_url);
+            upstream_attempts += 1;
+            key = keys[next_index];
+            sent = send_before(stream_deadline, build_request(&url, key)).await;
+            if matches!(&sent, Some(Ok(response)) if provider.is_success_status(response.status().as_u16())) {
+                println!("Provider {} now uses API key {} of {}", provider.base_url, next_index + 1, keys.len());
+                state.promote_key(provider, next_index);
+            }
+        }
+
+        // Redirects are followed here rather than by the HTTP client, so the key only goes where `redirect_auth` allows
+        for _ in 0..MAX_REDIRECTS {
+            let Some((target, keeps_key)) = sent.as_ref().and_then(|sent| sent.as_ref().ok()).and_then(|response| provider.redirect_target(response)) else {
+                break;
+            };
+            println!("Provider {} redirected to {}, following", provider.base_url, target);
+            upstream_attempts += 1;
+            sent = send_before(stream_deadline, build_request(target.as_str(), if keeps_key { key } else { "" })).await;
+        }
+
+        let Some(sent) = sent else {
+            eprintln!("Provider {} did not respond before the stream deadline", provider.base_url);
+            state.record_provider_outcome(config, &provider.name(), false);
+            attempt_errors.push(json!({"provider": provider.host(), "error": "timeout"}));
+            last_failure = Some(AttemptFailure::Timeout);
+            continue;
+        };
+
+        match sent {
+            Ok(response) => {
+                let status = response.status();
+                let failed = config.failover_statuses().contains(&status.as_u16());
+                let succeeded = provider.is_success_status(status.as_u16());
+
+                // An unfollowed redirect means the request never reached the endpoint
+                if status.is_redirection() && !succeeded {
+                    let location = redirect_location(&response);
+                    eprintln!("Provider {} redirected to '{}', failing over", provider.base_url, location);
+                    state.record_provider_outcome(config, &provider.name(), false);
+                    attempt_errors.push(json!({"provider": provider.host(), "status": status.as_u16(), "error": "redirect"}));
+                    last_failure = Some(AttemptFailure::Redirected(location));
+                    continue;
+                }
+                // Failures are recorded as they happen; other responses only once they are returned to the client
+                let record_outcome = || state.record_provider_outcome(config, &provider.name(), !failed);
+                state.record_provider_latency(config, &provider.name(), sent_at.elapsed());
+                if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    rate_limits.push(upstream_retry_after(response.headers()));
+                }
+                let all_rate_limited = config.aggregate_retry_after && rate_limits.len() == attempt + 1;
+                // In verbose mode a failing last attempt is reported together with the others
+                if failed && (!is_last_attempt || config.verbose_errors || all_rate_limited) {
+                    eprintln!("Provider {} returned {}, failing over", provider.base_url, status);
+                    state.record_provider_outcome(config, &provider.name(), false);
+                    attempt_errors.push(json!({"provider": provider.host(), "status": status.as_u16()}));
+                    let max_response_bytes = config.max_response_bytes_for(provider);
+                    last_upstream_error = Some(UpstreamError::read(response, max_response_bytes, stream_deadline).await);
+                    continue;
+                }
+
+                // Convert reqwest status to actix status
+                let actix_status = actix_web::http::StatusCode::from_u16(status.as_u16())
+                    .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+
+                let mut builder = HttpResponse::build(actix_status);
+                for header in prepared.response_headers {
+                    builder.insert_header(header);
+                }
+                if config.attempt_headers {
+                    builder.insert_header((UPSTREAM_ATTEMPTS_HEADER, upstream_attempts.to_string()));
+                    builder.insert_header((FAILOVER_USED_HEADER, (attempt > 0).to_string()));
+                }
+                // A redirect passed through as success is no use to the client without its target
+                if let Some(location) = passed_location(provider, &response) {
+                    builder.insert_header((actix_web::http::header::LOCATION, location));
+                }
+
+                // Pipe successful streams through as they arrive
+                if is_stream && client_stream && succeeded {
+                    let translate_ndjson = provider.stream_format == StreamFormat::Ndjson;
+                    let content_type = if translate_ndjson {
+                        "text/event-stream".to_string()
+                    } else {
+                        response.headers().get(CONTENT_TYPE)
+                            .and_then(|v| v.to_str().ok())
+                            .unwrap_or("text/event-stream")
+                            .to_string()
+                    };
+                    builder.content_type(content_type);
+                    // Intermediaries must not hold back or reuse an event stream
+                    builder.insert_header((actix_web::http::header::CACHE_CONTROL, "no-cache"));
+                    let options = StreamOptions {
+                        deadline: stream_deadline,
+                        rewrite_model: provider.rewrite_response_model.then(|| model.clone()),
+                        translate_ndjson,
+                        on_end: size_observer(config, state, RESPONSE_SIZE_METRIC, &model),
+                        fail_empty: config.empty_stream.is_some(),
+                        strip_events: provider.strip_sse_events,
+                        rename_events: provider.sse_event_renames.clone(),
+                        max_bytes: config.max_response_bytes_for(provider).map(|max| max as u64),
+                        heartbeat: config.stream_heartbeat(),
+                        on_first_content: Some(first_token_observer(config, state, &model, provider, sent_at)),
+                        legacy_functions: provider.legacy_functions,
+                    };
+                    if config.empty_stream != Some(EmptyStreamPolicy::Failover) {
+                        record_outcome();
+                        return Ok(builder.streaming(streaming::passthrough(response.bytes_stream(), options)));
+                    }
+
+                    // Nothing has reached the client until the first content arrives, so an empty stream can still fail over
+                    match streaming::await_first_content(response.bytes_stream(), translate_ndjson, stream_deadline).await {
+                        Some(upstream) => {
+                            record_outcome();
+                            return Ok(builder.streaming(streaming::passthrough(upstream, options)));
+                        }
+                        None if is_last_attempt => {
+                            state.record_provider_outcome(config, &provider.name(), false);
+                            return Ok(builder.streaming(streaming::passthrough(stream::empty(), options)));
+                        }
+                        None => {
+                            eprintln!("Provider {} closed its stream without any content, failing over", provider.base_url);
+                            state.record_provider_outcome(config, &provider.name(), false);
+                            attempt_errors.push(json!({"provider": provider.host(), "status": status.as_u16(), "error": "empty stream"}));
+                            last_failure = Some(AttemptFailure::InvalidResponse("Provider closed its stream without any content"));
+                            continue;
+                        }
+                    }
+                }
+
+                let response_headers = response.headers().clone();
+                let max_response_bytes = config.max_response_bytes_for(provider);
+                let read = match stream_deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, read_body(response, max_response_bytes)).await.ok(),
+                    None => Some(read_body(response, max_response_bytes).await),
+                };
+                let Some(read) = read else {
+                    eprintln!("Provider {} did not finish its stream before the deadline", provider.base_url);
+                    state.record_provider_outcome(config, &provider.name(), false);
+                    attempt_errors.push(json!({"provider": provider.host(), "error": "timeout"}));
+                    last_failure = Some(AttemptFailure::Timeout);
+                    continue;
+                };
+                let Some(mut body) = read else {
+                    let max = max_response_bytes.unwrap_or_default();
+                    eprintln!("Provider {} sent a response over its size limit of {} bytes", provider.base_url, max);
+                    state.record_provider_outcome(config, &provider.name(), false);
+                    attempt_errors.push(json!({"provider": provider.host(), "status": status.as_u16(), "error": "response too large"}));
+                    last_failure = Some(AttemptFailure::ResponseTooLarge(max));
+                    continue;
+                };
+
+                // Some providers report transient errors inside an otherwise normal response
+                if (!is_last_attempt || config.verbose_errors) && provider.matches_retry_body(&body) {
+                    eprintln!("Provider {} returned a retryable error body, failing over", provider.base_url);
+                    state.record_provider_outcome(config, &provider.name(), false);
+                    attempt_errors.push(json!({"provider": provider.host(), "status": status.as_u16(), "error": "retryable error body"}));
+                    last_upstream_error = Some(UpstreamError { status, headers: response_headers, body });
+                    continue;
+                }
+
+                let mut content_type = response_headers.get(CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or(config.default_content_type(path));
+
+                // A stream forced on a non-streaming client is buffered into one response
+                if is_stream && succeeded
+                    && let Some(completion) = streaming::collect_completion(&body, provider.stream_format == StreamFormat::Ndjson)
+                {
+                    body = Bytes::from(completion.to_string());
+                    content_type = "application/json";
+                }
+
+                // A truncated or garbled JSON body is usually fixed by trying again elsewhere
+                if (!is_last_attempt || config.verbose_errors)
+                    && succeeded
+                    && content_type.contains("json")
+                    && config.verify_json_max_bytes.is_some_and(|max| body.len() <= max)
+                    && serde_json::from_slice::<serde::de::IgnoredAny>(&body).is_err()
+                {
+                    eprintln!("Provider {} returned a malformed JSON body, failing over", provider.base_url);
+                    state.record_provider_outcome(config, &provider.name(), false);
+                    attempt_errors.push(json!({"provider": provider.host(), "status": status.as_u16(), "error": "malformed JSON body"}));
+                    last_failure = Some(AttemptFailure::InvalidResponse("Provider returned a malformed JSON body"));
+                    continue;
+                }
+
+                if succeeded {
+                    body = postprocess_response(provider, path, &model, &prepared.body, body);
+                    record_usage(state, provider, &model, context.cost_center.as_deref(), &body);
+                }
+                // A streaming client whose model was called without streaming gets the response as one event
+                if client_stream && !is_stream && succeeded
+                    && let Ok(completion) = serde_json::from_slice::<Value>(&body)
+                {
+                    body = streaming::completion_to_sse(&completion);
+                    content_type = "text/event-stream";
+                }
+                if let Some(observe) = size_observer(config, state, RESPONSE_SIZE_METRIC, &model) {
+                    observe(body.len() as u64);
+                }
+                record_outcome();
+                builder.content_type(content_type);
+                return Ok(builder.body(body));
+            }
+            Err(e) => {
+                match crate::tls::pin_mismatch(&e) {
+                    Some(mismatch) => eprintln!("Error forwarding request to {}: {}", provider.base_url, mismatch),
+                    None => eprintln!("Error forwarding request to {}: {}", provider.base_url, e),
+                }
+                state.record_provider_outcome(config, &provider.name(), false);
+                attempt_errors.push(json!({"provider": provider.host(), "error": describe_error(&e)}));
+                last_failure = Some(AttemptFailure::Connection(e));
+            }
+        }
+    }
+
+    // Every provider was rate limited, so the client can wait for the first one to free up
+    if config.aggregate_retry_after && !rate_limits.is_empty() && rate_limits.len() == attempts.len() {
+        return Ok(providers_rate_limited(&model, rate_limits.into_iter().flatten().min()));
+    }
+
+    // Every provider failed; list each attempt when verbose errors are enabled
+    if config.verbose_errors && !attempt_errors.is_empty() {
+        return Ok(HttpResponse::BadGateway().json(json!({
+            "error": {
+                "message": format!("All providers failed for model '{}'", model),
+                "type": "upstream_error",
+                "attempts": attempt_errors
+            }
+        })));
+    }
+
+    match last_upstream_error.map(AttemptFailure::Upstream).or(last_failure) {
+        Some(AttemptFailure::Upstream(error)) => Ok(error.into_response()),
+        Some(AttemptFailure::Rejected(rejection)) => Ok(rejection),
+        Some(AttemptFailure::Connection(e)) => Ok(HttpResponse::InternalServerError().json(json!({
+            "error": {
+                "message": match crate::tls::pin_mismatch(&e) {
+                    Some(mismatch) => format!("Failed to forward request: {}", mismatch),
+                    None => format!("Failed to forward request: {}", e),
+                },
+                "type": "internal_error"
+            }
+        }))),
+        Some(AttemptFailure::Timeout) => Ok(HttpResponse::GatewayTimeout().json(json!({
+            "error": {
+                "message": "Provider did not respond before the stream deadline",
+                "type": "timeout_error"
+            }
+        }))),
+        Some(AttemptFailure::Throttled) => Ok(provider_throttled()),
+        Some(AttemptFailure::Unavailable) => Ok(model_unavailable(&model, HALF_OPEN_RETRY_AFTER)),
+        Some(AttemptFailure::ResponseTooLarge(max)) => Ok(response_too_large(max)),
+        Some(AttemptFailure::Redirected(location)) => Ok(upstream_redirect(&location)),
+        Some(AttemptFailure::InvalidResponse(reason)) => Ok(invalid_upstream_response(reason)),
+        None => Ok(HttpResponse::InternalServerError().json(json!({
+            "error": {
+                "message": "Failed to forward request: no provider available",
+                "type": "internal_error"
+            }
+        }))),
+    }
+}
+
+/// Read a whole response body, or None as soon as it turns out to be larger than `max` bytes
+/// A body that fails to arrive reads as empty
+async fn read_body(response: reqwest::Response, max: Option<usize>) -> Option<Bytes> {
+    let Some(max) = max else {
+        return Some(response.bytes().await.unwrap_or_default());
+    };
+    if response.content_length().is_some_and(|length| length > max as u64) {
+        return None;
+    }
+    let mut body = Vec::new();
+    let mut chunks = response.bytes_stream();
+    while let Some(chunk) = chunks.next().await {
+        let Ok(chunk) = chunk else {
+            return Some(Bytes::new());
+        };
+        if body.len() + chunk.len() > max {
+            return None;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Some(Bytes::from(body))
+}
+
+/// `Location` of a redirect the provider lists in `success_statuses`, to pass on to the client
+fn passed_location(provider: &Provider, response: &reqwest::Response) -> Option<String> {
+    let status = response.status();
+    if !status.is_redirection() || !provider.is_success_status(status.as_u16()) {
+        return None;
+    }
+    response.headers().get(reqwest::header::LOCATION)?.to_str().ok().map(|location| location.to_string())
+}
+
+/// Send a request, giving up with None once the streaming deadline, if any, has passed
+async fn send_before(deadline: Option<Instant>, request_builder: reqwest::RequestBuilder) -> Option<reqwest::Result<reqwest::Response>> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, request_builder.send()).await.ok(),
+        None => Some(request_builder.send().await),
+    }
+}
+
+/// Wait for a request slot under the provider's `max_rps`
+/// Returns false when the request should skip the provider instead, or can never be sent to it
+async fn await_provider_slot(config: &Config, state: &AppState, provider: &Provider, can_fail_over: bool) -> bool {
+    loop {
+        match state.acquire_provider_slot(provider) {
+            Ok(()) => return true,
+            // A zero rate never frees up a slot
+            Err(wait) if wait == Duration::MAX => return false,
+            Err(_) if can_fail_over && config.max_rps_mode == MaxRpsMode::Failover => return false,
+            Err(wait) => tokio::time::sleep(wait).await,
+        }
+    }
+}
+
+/// Structured 429 for a request no provider had the capacity for
+fn provider_throttled() -> HttpResponse {
+    HttpResponse::TooManyRequests().json(json!({
+        "error": {
+            "message": "Provider request rate limit reached",
+            "type": "rate_limit_error",
+            "code": "rate_limit_exceeded"
+        }
+    }))
+}
+
+/// Structured 400 for a prefixed or aliased model sent on the streamed-body path, where the body isn't rewritten
+fn renamed_model_in_streamed_body(model: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": {
+            "message": format!("Model '{}' is prefixed or aliased, which requests with an X-Model header don't support; send it without the header", model),
+            "type": "invalid_request_error",
+            "param": "model",
+            "code": "unsupported_model"
+        }
+    }))
+}
+
+/// Structured 413 for a request body over the size limit of the provider it was meant for
+fn request_too_large(max: usize) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(json!({
+        "error": {
+            "message": format!("Request body exceeds the size limit of {} bytes", max),
+            "type": "invalid_request_error",
+            "code": "request_too_large"
+        }
+    }))
+}
+
+/// Structured 400 for a request estimated not to fit the model's context window
+fn context_window_exceeded(model: &str, window: u64, estimate: u64) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": {
+            "message": format!(
+                "Request exceeds the context window of model '{}': about {} tokens estimated, limit is {}",
+                model, estimate, window
+            ),
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": "context_length_exceeded"
+        }
+    }))
+}
+
+/// Structured 502 for a provider response over its size limit
+fn response_too_large(max: usize) -> HttpResponse {
+    HttpResponse::BadGateway().json(json!({
+        "error": {
+            "message": format!("Provider response exceeds the size limit of {} bytes", max),
+            "type": "upstream_error",
+            "code": "response_too_large"
+        }
+    }))
+}
+
+/// Structured 502 for a successful provider response that turned out unusable
+fn invalid_upstream_response(reason: &str) -> HttpResponse {
+    HttpResponse::BadGateway().json(json!({
+        "error": {
+            "message": reason,
+            "type": "upstream_error",
+            "code": "invalid_upstream_response"
+        }
+    }))
+}
+
+/// Structured 502 for a provider redirect that wasn't followed
+fn upstream_redirect(location: &str) -> HttpResponse {
+    HttpResponse::BadGateway().json(json!({
+        "error": {
+            "message": format!("Provider redirected the request to '{}'", location),
+            "type": "upstream_error",
+            "code": "upstream_redirect"
+        }
+    }))
+}
+
+/// Location a provider response redirects to, as sent
+fn redirect_location(response: &reqwest::Response) -> String {
+    response.headers().get(reqwest::header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Structured 404 for a model the gateway doesn't route
+fn model_not_found(model: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "error": {
+            "message": format!("Model '{}' not found", model),
+            "type": "invalid_request_error",
+            "param": "model",
+            "code": "model_not_found"
+        }
+    }))
+}
+
+fn ambiguous_model(model: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": {
+            "message": format!("Model '{}' matches several routes; use an unambiguous model id", model),
+            "type": "invalid_request_error",
+            "param": "model",
+            "code": "ambiguous_model"
+        }
+    }))
+}
+
+fn vision_unsupported(model: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": {
+            "message": format!("No provider serving model '{}' supports image or other non-text input", model),
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": "unsupported_content"
+        }
+    }))
+}
+
+/// Structured 503 for every routable endpoint while maintenance mode is on
+pub fn maintenance_unavailable(config: &Config) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header(("Retry-After", config.maintenance_retry_after_secs().to_string()))
+        .json(json!({
+            "error": {
+                "message": "The gateway is down for maintenance, please retry later",
+                "type": "service_unavailable",
+                "code": "maintenance"
+            }
+        }))
+}
+
+/// Structured 503 for a known model whose providers are all circuit-broken
+fn model_unavailable(model: &str, retry_after: Duration) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header(("Retry-After", retry_after_secs(retry_after)))
+        .json(json!({
+            "error": {
+                "message": format!("Model '{}' is temporarily unavailable", model),
+                "type": "service_unavailable",
+                "param": "model",
+                "code": "model_unavailable"
+            }
+        }))
+}
+
+/// Structured 429 for a model whose gateway-wide rate limit is exhausted
+fn model_rate_limited(model: &str, retry_after: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", retry_after_secs(retry_after)))
+        .json(json!({
+            "error": {
+                "message": format!("Rate limit exceeded for model '{}'", model),
+                "type": "rate_limit_error",
+                "param": "model",
+                "code": "rate_limit_exceeded"
+            }
+        }))
+}
+
+/// Structured 429 for a model whose providers all answered 429, with the soonest of their hints if any
+fn providers_rate_limited(model: &str, retry_after: Option<Duration>) -> HttpResponse {
+    let mut builder = HttpResponse::TooManyRequests();
+    if let Some(retry_after) = retry_after {
+        builder.insert_header(("Retry-After", retry_after_secs(retry_after)));
+    }
+    builder.json(json!({
+        "error": {
+            "message": format!("All providers for model '{}' are rate limited", model),
+            "type": "rate_limit_error",
+            "param": "model",
+            "code": "rate_limit_exceeded"
+        }
+    }))
+}
+
+/// Wait asked for by an upstream `Retry-After` header, in seconds or as an HTTP date
+fn upstream_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date: actix_web::http::header::HttpDate = value.parse().ok()?;
+    Some(std::time::SystemTime::from(date).duration_since(std::time::SystemTime::now()).unwrap_or_default())
+}
+
+/// `Retry-After` value for a wait, rounded up so clients never retry too early
+fn retry_after_secs(wait: Duration) -> String {
+    (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).to_string()
+}
+
+/// Short description of a connection error that doesn't include the request URL
+fn describe_error(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        "timeout"
+    } else if crate::tls::pin_mismatch(error).is_some() {
+        "certificate pin mismatch"
+    } else if error.is_connect() {
+        "connection failed"
+    } else {
+        "request failed"
+    }
+}
+
+/// Apply the provider's opt-in post-processing to a successful non-streaming response
+/// Bodies that aren't JSON objects, or need no change, are returned unchanged
+fn postprocess_response(provider: &Provider, path: &str, model: &str, request: &Value, body: Bytes) -> Bytes {
+    // Other endpoints such as embeddings only get their model rewritten
+    let is_chat = path == "chat/completions";
+    if !provider.rewrite_response_model && !(is_chat && (provider.estimate_missing_usage || provider.legacy_functions)) {
+        return body;
+    }
+    let mut json = match serde_json::from_slice::<Value>(&body) {
+        Ok(json) if json.is_object() => json,
+        _ => return body,
+    };
+    let mut changed = false;
+
+    // Replace the `model` echoed by the provider with the name the client requested
+    if provider.rewrite_response_model && json.get("model").is_some() {
+        json["model"] = json!(model);
+        changed = true;
+    }
+
+    // Clients sent `tools`, so they expect `tool_calls` back
+    if is_chat && provider.legacy_functions {
+        changed |= functions::from_legacy_response(&mut json);
+    }
+
+    // Keep downstream accounting working when the provider omits usage
+    if is_chat && provider.estimate_missing_usage && json.get("usage").is_none_or(|usage| usage.is_null()) {
+        json["usage"] = tokens::estimate_usage(request, &json);
+        changed = true;
+    }
+
+    if changed {
+        Bytes::from(json.to_string())
+    } else {
+        body
+    }
+}
+
+/// Add the token usage reported in a response to the usage counters, read at the provider's `usage_path`
+/// Usage is labelled with the request's cost center when `cost_centers` is configured
+fn record_usage(state: &AppState, provider: &Provider, model: &str, cost_center: Option<&str>, body: &[u8]) {
+    let Ok(json) = serde_json::from_slice::<Value>(body) else {
+        return;
+    };
+    for (kind, path) in [("prompt", &provider.usage_path.prompt), ("completion", &provider.usage_path.completion)] {
+        if let Some(tokens) = tokens::value_at_path(&json, path).and_then(|v| v.as_u64()) {
+            let mut labels = vec![("model", model.to_string()), ("provider", provider.name()), ("kind", kind.to_string())];
+            if let Some(cost_center) = cost_center {
+                labels.push(("cost_center", cost_center.to_string()));
+            }
+            state.metrics.add("gateway_tokens_total", labels, tokens);
+        }
+    }
+}
+
+/// Apply the provider's parameter policies to a copy of the request body
+/// Returns the response to send instead when a policy rejects the request
+fn prepare_request(
+    config: &Config,
+    provider: &Provider,
+    path: &str,
+    model: &str,
+    upstream_model: &str,
+    context: &RequestContext,
+    body: &Value,
+) -> std::result::Result<PreparedRequest, HttpResponse> {
+    let mut prepared = PreparedRequest {
+        body: body.clone(),
+        response_headers: Vec::new(),
+    };
+    // Other endpoints such as embeddings only get their model rewritten
+    let is_chat = path == "chat/completions";
+
+    // Providers only know their own id for a prefixed or aliased model, and a last-resort provider may stand in with its own
+    let sent_model = provider.last_resort_model.as_deref().unwrap_or_else(|| provider.upstream_model_id(upstream_model));
+    if sent_model != model {
+        prepared.body["model"] = json!(sent_model);
+    }
+
+    // Templated system prompt ahead of the client's own messages
+    let system_prompt = config.system_prompts.get(upstream_model).or(provider.system_prompt.as_ref());
+    if let (Some(template), Some(messages)) = (system_prompt, prepared.body.get_mut("messages").and_then(|m| m.as_array_mut())) {
+        let variables = PromptVariables {
+            model,
+            cost_center: context.cost_center.as_deref(),
+            client: context.client.as_deref(),
+        };
+        messages.insert(0, json!({"role": "system", "content": template::render(template, &variables)}));
+    }
+
+    // Fill in the provider's defaults for parameters the client didn't set
+    if is_chat && let Some(fields) = prepared.body.as_object_mut() {
+        for (key, value) in &provider.default_params {
+            if !fields.contains_key(key) {
+                fields.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    // Handle `n > 1` for providers that only return a single choice
+    if is_chat && let Some(policy) = provider.unsupported_n {
+        let requested_n = body.get("n").and_then(|n| n.as_u64()).unwrap_or(1);
+        if requested_n > 1 {
+            match policy {
+                UnsupportedNPolicy::Downgrade => {
+                    prepared.body["n"] = json!(1);
+                    prepared.response_headers.push((N_DOWNGRADED_HEADER, requested_n.to_string()));
+                }
+                UnsupportedNPolicy::Reject => {
+                    return Err(HttpResponse::BadRequest().json(json!({
+                        "error": {
+                            "message": format!("Model '{}' does not support n > 1 (requested n = {})", model, requested_n),
+                            "type": "invalid_request_error",
+                            "param": "n",
+                            "code": "unsupported_parameter"
+                        }
+                    })));
+                }
+            }
+        }
+    }
+
+    // Newer fields a provider doesn't understand yet
+    if provider.newer_fields != NewerFieldsPolicy::Pass
+        && let Some(fields) = prepared.body.as_object_mut()
+    {
+        for field in NEWER_FIELDS {
+            if !fields.contains_key(*field) {
+                continue;
+            }
+            if provider.newer_fields == NewerFieldsPolicy::Reject {
+                return Err(HttpResponse::BadRequest().json(json!({
+                    "error": {
+                        "message": format!("Model '{}' does not support the '{}' parameter", model, field),
+                        "type": "invalid_request_error",
+                        "param": field,
+                        "code": "unsupported_parameter"
+                    }
+                })));
+            }
+            fields.remove(*field);
+        }
+    }
+
+    // Force JSON mode for configured models and providers
+    if is_chat && (config.json_mode_models.iter().any(|m| m == upstream_model) || provider.force_json_mode) {
+        let client_format = body.get("response_format").is_some_and(|f| !f.is_null());
+        if !(client_format && config.json_mode_respect_client) {
+            prepared.body["response_format"] = json!({"type": "json_object"});
+        }
+    }
+
+    // Models with `force_stream` are called the same way whatever the client asked for
+    if is_chat
+        && let Some(force) = config.force_stream.get(upstream_model)
+        && let Some(fields) = prepared.body.as_object_mut()
+    {
+        if *force {
+            fields.insert("stream".to_string(), json!(true));
+        } else {
+            fields.remove("stream");
+            fields.remove("stream_options");
+        }
+    }
+
+    // Requests sharing a system prompt share a cache key, unless the client picked its own
+    if is_chat
+        && provider.prompt_cache_key
+        && prepared.body.get("prompt_cache_key").is_none()
+        && let Some(key) = prompt_cache_key(&prepared.body)
+    {
+        prepared.body["prompt_cache_key"] = json!(key);
+    }
+
+    // Older providers only understand the legacy function-calling fields
+    if is_chat && provider.legacy_functions {
+        functions::to_legacy_request(&mut prepared.body);
+    }
+
+    // The model was only needed for routing; some providers pick it from the endpoint path instead
+    if provider.strip_model_field
+        && let Some(fields) = prepared.body.as_object_mut()
+    {
+        fields.remove("model");
+    }
+
+    Ok(prepared)
+}
+
+/// Cache key for the stable prefix of a chat request: its leading system and developer messages
+fn prompt_cache_key(body: &Value) -> Option<String> {
+    let prefix: Vec<&Value> = body.get("messages")?.as_array()?.iter()
+        .take_while(|m| matches!(m.get("role").and_then(|r| r.as_str()), Some("system" | "developer")))
+        .collect();
+    if prefix.is_empty() {
+        return None;
+    }
+    let digest = Sha256::digest(serde_json::to_vec(&prefix).ok()?);
+    Some(digest.iter().take(16).map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{chat_request, chunk, error, model_list, ok_completion, sse, Gateway, MockProvider, CLIENT_KEY};
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).expect("test config should parse")
+    }
+
+    /// Body the provider receives for `request`, with gateway-wide `settings` and `provider` settings for the one provider serving `m1`
+    async fn forwarded_body(settings: &str, provider: &str, request: Value) -> Value {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            {}
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            {}
+        "#, settings, upstream.url, provider)).await;
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        upstream.last().json()
+    }
+
+    fn names(attempts: &[Provider]) -> Vec<String> {
+        attempts.iter().map(|provider| provider.name()).collect()
+    }
+
+    const STANDBY_CONFIG: &str = r#"
+        circuit_breaker_threshold = 1
+        [[providers]]
+        name = "primary"
+        base_url = "http://primary/v1"
+        api_key = "k"
+        models = ["m1"]
+        [[providers]]
+        name = "standby"
+        base_url = "http://standby/v1"
+        api_key = "k"
+        models = ["m1"]
+        standby = true
+    "#;
+
+    #[actix_web::test]
+    async fn standby_providers_follow_the_primaries() {
+        let config = config(STANDBY_CONFIG);
+        let state = AppState::new(&config);
+        let (attempts, _) = resolve_attempts(&config, &state, "m1", None).await.unwrap();
+        assert_eq!(names(&attempts), ["primary", "standby"]);
+    }
+
+    #[actix_web::test]
+    async fn standby_providers_are_promoted_once_the_primaries_are_down() {
+        let config = config(STANDBY_CONFIG);
+        let state = AppState::new(&config);
+        state.record_provider_outcome(&config, "primary", false);
+        let (attempts, _) = resolve_attempts(&config, &state, "m1", None).await.unwrap();
+        assert_eq!(names(&attempts), ["standby"]);
+    }
+
+    #[actix_web::test]
+    async fn unsupported_n_is_downgraded() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "one")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            unsupported_n = "downgrade"
+        "#, upstream.url)).await;
+
+        let mut request = chat_request("m1");
+        request["n"] = 3.into();
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-n-downgraded-from"], "3");
+        assert_eq!(upstream.last().json()["n"], 1);
+    }
+
+    #[actix_web::test]
+    async fn unsupported_n_is_rejected() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "one")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            unsupported_n = "reject"
+        "#, upstream.url)).await;
+
+        let mut request = chat_request("m1");
+        request["n"] = 2.into();
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["param"], "n");
+        assert_eq!(upstream.hits("/v1/chat/completions"), 0);
+
+        // A single choice is forwarded untouched
+        let mut request = chat_request("m1");
+        request["n"] = 1.into();
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn verbose_errors_list_every_attempt() {
+        let first = MockProvider::start(|_, _| error(503, "down")).await;
+        let second = MockProvider::start(|_, _| error(500, "broken")).await;
+        let gateway = Gateway::start(&format!(r#"
+            verbose_errors = true
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, first.url, second.url)).await;
+
+        let response = gateway.post("/v1/chat/completions", chat_request("m1")).send().await.unwrap();
+        assert_eq!(response.status(), 502);
+        let body: Value = response.json().await.unwrap();
+        let attempts = body["error"]["attempts"].as_array().unwrap();
+        let statuses: Vec<&Value> = attempts.iter().map(|attempt| &attempt["status"]).collect();
+        assert_eq!(statuses, [503, 500]);
+        assert_eq!(attempts[0]["provider"], "127.0.0.1");
+    }
+
+    #[actix_web::test]
+    async fn strip_model_field_removes_model_from_the_forwarded_body() {
+        let stripped = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let kept = MockProvider::start(|_, _| ok_completion("m2", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            strip_model_field = true
+            [[providers]]
+            base_url = "{}"
+            models = ["m2"]
+        "#, stripped.url, kept.url)).await;
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        let forwarded = stripped.last().json();
+        assert!(forwarded.get("model").is_none());
+        assert_eq!(forwarded["messages"][0]["content"], "hi");
+
+        assert_eq!(gateway.chat("m2").await.status(), 200);
+        assert_eq!(kept.last().json()["model"], "m2");
+    }
+
+    #[actix_web::test]
+    async fn json_mode_is_injected_for_listed_models() {
+        let forwarded = forwarded_body(r#"json_mode_models = ["m1"]"#, "", chat_request("m1")).await;
+        assert_eq!(forwarded["response_format"], json!({"type": "json_object"}));
+
+        let forwarded = forwarded_body("", "force_json_mode = true", chat_request("m1")).await;
+        assert_eq!(forwarded["response_format"], json!({"type": "json_object"}));
+
+        let forwarded = forwarded_body("", "", chat_request("m1")).await;
+        assert!(forwarded.get("response_format").is_none());
+    }
+
+    #[actix_web::test]
+    async fn json_mode_overrides_or_respects_the_client_format() {
+        let mut request = chat_request("m1");
+        request["response_format"] = json!({"type": "text"});
+
+        let forwarded = forwarded_body(r#"json_mode_models = ["m1"]"#, "", request.clone()).await;
+        assert_eq!(forwarded["response_format"], json!({"type": "json_object"}));
+
+        let settings = "json_mode_models = [\"m1\"]\njson_mode_respect_client = true";
+        let forwarded = forwarded_body(settings, "", request).await;
+        assert_eq!(forwarded["response_format"], json!({"type": "text"}));
+    }
+
+    #[actix_web::test]
+    async fn default_params_only_fill_missing_keys() {
+        let mut request = chat_request("m1");
+        request["temperature"] = json!(0.2);
+        let forwarded = forwarded_body("", "default_params = { temperature = 0.7, top_p = 0.9 }", request).await;
+        assert_eq!(forwarded["temperature"], 0.2);
+        assert_eq!(forwarded["top_p"], 0.9);
+    }
+
+    #[actix_web::test]
+    async fn matching_error_bodies_fail_over() {
+        let loading = MockProvider::start(|_, count| match count {
+            0 => actix_web::HttpResponse::Ok().json(json!({"status": "model is loading"})),
+            _ => ok_completion("m1", "warm"),
+        }).await;
+        let backup = MockProvider::start(|_, _| ok_completion("m1", "backup")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            retry_on_body = ["model is loading"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, loading.url, backup.url)).await;
+
+        let content = |body: Value| body["choices"][0]["message"]["content"].clone();
+        let first: Value = gateway.chat("m1").await.json().await.unwrap();
+        assert_eq!(content(first), "backup");
+        let second: Value = gateway.chat("m1").await.json().await.unwrap();
+        assert_eq!(content(second), "warm");
+        assert_eq!(backup.hits("/v1/chat/completions"), 1);
+    }
+
+    #[actix_web::test]
+    async fn model_rate_limits_are_shared_by_every_key() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            server_api_key = "client"
+            admin_api_key = "admin"
+            model_rate_limits = {{ "m1" = 2 }}
+            [[providers]]
+            base_url = "{}"
+            models = ["m1", "m2"]
+        "#, upstream.url)).await;
+
+        let send = |key: &'static str, model: &'static str| gateway.post("/v1/chat/completions", chat_request(model))
+            .header("authorization", format!("Bearer {}", key))
+            .send();
+        assert_eq!(send("client", "m1").await.unwrap().status(), 200);
+        assert_eq!(send("admin", "m1").await.unwrap().status(), 200);
+        let limited = send("admin", "m1").await.unwrap();
+        assert_eq!(limited.status(), 429);
+        assert!(limited.headers().contains_key("retry-after"));
+        assert_eq!(send("client", "m1").await.unwrap().status(), 429);
+        // Other models have budgets of their own
+        assert_eq!(send("client", "m2").await.unwrap().status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn forward_user_is_only_injected_when_absent() {
+        let forwarded = forwarded_body(r#"forward_user = "key_id""#, "", chat_request("m1")).await;
+        // The test client's key is `client-key`
+        assert_eq!(forwarded["user"], "clie...-key");
+
+        let mut request = chat_request("m1");
+        request["user"] = json!("end-user-42");
+        let forwarded = forwarded_body(r#"forward_user = "key_id""#, "", request).await;
+        assert_eq!(forwarded["user"], "end-user-42");
+
+        let forwarded = forwarded_body("", "", chat_request("m1")).await;
+        assert!(forwarded.get("user").is_none());
+    }
+
+    #[actix_web::test]
+    async fn unavailable_models_answer_503_and_unknown_ones_404() {
+        let upstream = MockProvider::start(|_, _| error(503, "down")).await;
+        let gateway = Gateway::start(&format!(r#"
+            circuit_breaker_threshold = 1
+            unavailable_as_503 = true
+            [[providers]]
+            name = "only"
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        // The failed attempt opens the breaker
+        assert_eq!(gateway.chat("m1").await.status(), 503);
+        let unavailable = gateway.chat("m1").await;
+        assert_eq!(unavailable.status(), 503);
+        assert!(unavailable.headers().contains_key("retry-after"));
+        assert_eq!(upstream.hits("/v1/chat/completions"), 1);
+
+        assert_eq!(gateway.chat("unknown").await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn unavailable_models_answer_404_by_default() {
+        let upstream = MockProvider::start(|_, _| error(503, "down")).await;
+        let gateway = Gateway::start(&format!(r#"
+            circuit_breaker_threshold = 1
+            [[providers]]
+            name = "only"
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        assert_eq!(gateway.chat("m1").await.status(), 503);
+        let body: Value = gateway.chat("m1").await.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "model_not_found");
+    }
+
+    #[actix_web::test]
+    async fn form_encoded_providers_get_form_fields() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            body_encoding = "form"
+        "#, upstream.url)).await;
+
+        let mut request = chat_request("m1");
+        request["temperature"] = json!(0.5);
+        assert_eq!(gateway.post("/v1/chat/completions", request).send().await.unwrap().status(), 200);
+        let forwarded = upstream.last();
+        assert_eq!(forwarded.header("content-type"), Some("application/x-www-form-urlencoded"));
+        let body = String::from_utf8(forwarded.body.to_vec()).unwrap();
+        let fields: std::collections::HashMap<String, String> = reqwest::Url::parse(&format!("http://form/?{}", body))
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        assert_eq!(fields["model"], "m1");
+        assert_eq!(fields["temperature"], "0.5");
+        let messages: Value = serde_json::from_str(&fields["messages"]).unwrap();
+        assert_eq!(messages, json!([{"role": "user", "content": "hi"}]));
+    }
+
+    #[actix_web::test]
+    async fn override_headers_replace_allowed_params() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            allow_param_overrides = true
+            overridable_params = ["temperature", "top_p"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        let mut request = chat_request("m1");
+        request["temperature"] = json!(1.0);
+        let response = gateway.post("/v1/chat/completions", request)
+            .header("x-override-temperature", "0.2")
+            .header("x-override-top-p", "0.9")
+            .header("x-override-max-tokens", "5")
+            .send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let forwarded = upstream.last().json();
+        assert_eq!(forwarded["temperature"], 0.2);
+        assert_eq!(forwarded["top_p"], 0.9);
+        assert!(forwarded.get("max_tokens").is_none());
+
+        let response = gateway.post("/v1/chat/completions", chat_request("m1"))
+            .header("x-override-temperature", "warm")
+            .send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "invalid_override");
+        assert_eq!(upstream.hits("/v1/chat/completions"), 1);
+    }
+
+    #[actix_web::test]
+    async fn requests_over_max_rps_fail_over() {
+        let strict = MockProvider::start(|_, _| ok_completion("m1", "strict")).await;
+        let backup = MockProvider::start(|_, _| ok_completion("m1", "backup")).await;
+        let gateway = Gateway::start(&format!(r#"
+            max_rps_mode = "failover"
+            [[providers]]
+            name = "strict"
+            base_url = "{}"
+            models = ["m1"]
+            max_rps = 1
+            [[providers]]
+            name = "backup"
+            base_url = "{}"
+            models = ["m1"]
+        "#, strict.url, backup.url)).await;
+
+        for _ in 0..3 {
+            assert_eq!(gateway.chat("m1").await.status(), 200);
+        }
+        assert_eq!(strict.hits("/v1/chat/completions"), 1);
+        assert_eq!(backup.hits("/v1/chat/completions"), 2);
+    }
+
+    #[actix_web::test]
+    async fn requests_over_max_rps_are_delayed() {
+        let strict = MockProvider::start(|_, _| ok_completion("m1", "strict")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            max_rps = 2
+        "#, strict.url)).await;
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(gateway.chat("m1").await.status(), 200);
+        }
+        // The burst covers two requests; the third waits for the bucket to refill
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        assert_eq!(strict.hits("/v1/chat/completions"), 3);
+    }
+
+    #[actix_web::test]
+    async fn rejected_keys_rotate_to_the_next_one() {
+        let upstream = MockProvider::start(|request, _| match request.header("authorization") {
+            Some("Bearer sk-new") => ok_completion("m1", "hi"),
+            _ => error(401, "invalid key"),
+        }).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            api_keys = ["sk-old", "sk-new"]
+        "#, upstream.url)).await;
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        assert_eq!(upstream.hits("/v1/chat/completions"), 2);
+
+        // The accepted key is now the active one
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        assert_eq!(upstream.hits("/v1/chat/completions"), 3);
+        assert_eq!(upstream.last().header("authorization"), Some("Bearer sk-new"));
+    }
+
+    #[test]
+    fn usage_is_read_at_each_provider_path() {
+        let config = config(r#"
+            [[providers]]
+            name = "standard"
+            base_url = "http://standard/v1"
+            [[providers]]
+            name = "nested"
+            base_url = "http://nested/v1"
+            usage_path = { prompt = "meta.0.input_tokens", completion = "meta.0.output_tokens" }
+        "#);
+        let state = AppState::new(&config);
+        let standard = json!({"usage": {"prompt_tokens": 3, "completion_tokens": 5, "total_tokens": 8}});
+        let nested = json!({"meta": [{"input_tokens": 7, "output_tokens": 11}]});
+        record_usage(&state, &config.providers[0], "m1", None, standard.to_string().as_bytes());
+        record_usage(&state, &config.providers[1], "m1", None, nested.to_string().as_bytes());
+
+        let metrics = state.metrics.render(&[]);
+        for line in [
+            r#"gateway_tokens_total{model="m1",provider="standard",kind="prompt"} 3"#,
+            r#"gateway_tokens_total{model="m1",provider="standard",kind="completion"} 5"#,
+            r#"gateway_tokens_total{model="m1",provider="nested",kind="prompt"} 7"#,
+            r#"gateway_tokens_total{model="m1",provider="nested",kind="completion"} 11"#,
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing {} in {}", line, metrics);
+        }
+    }
+
+    #[actix_web::test]
+    async fn binary_responses_keep_their_content_type() {
+        const BINARY: &[u8] = &[0, 159, 146, 150, 255];
+        let typed = MockProvider::start(|_, _| HttpResponse::Ok().content_type("application/octet-stream").body(BINARY)).await;
+        let untyped = MockProvider::start(|_, _| HttpResponse::Ok().body(BINARY)).await;
+        let gateway = Gateway::start(&format!(r#"
+            default_content_types = {{ "embeddings" = "application/x-embedding" }}
+            [[providers]]
+            base_url = "{}"
+            models = ["typed"]
+            [[providers]]
+            base_url = "{}"
+            models = ["untyped"]
+        "#, typed.url, untyped.url)).await;
+
+        for (model, content_type) in [("typed", "application/octet-stream"), ("untyped", "application/x-embedding")] {
+            let response = gateway.post("/v1/embeddings", json!({"model": model, "input": "hi"})).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["content-type"], content_type);
+            assert_eq!(response.bytes().await.unwrap(), BINARY);
+        }
+    }
+
+    #[actix_web::test]
+    async fn no_coalesce_header_gets_its_own_upstream_call() {
+        let upstream = MockProvider::start_delayed(Duration::from_millis(300), |_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            coalesce_requests = true
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        let (first, second) = futures_util::join!(gateway.chat("m1"), gateway.chat("m1"));
+        assert_eq!([first.status(), second.status()], [200, 200]);
+        assert_eq!(upstream.hits("/v1/chat/completions"), 1);
+
+        let bypassing = gateway.post("/v1/chat/completions", chat_request("m1")).header("x-no-coalesce", "true").send();
+        let (first, second) = futures_util::join!(gateway.chat("m1"), bypassing);
+        assert_eq!([first.status(), second.unwrap().status()], [200, 200]);
+        assert_eq!(upstream.hits("/v1/chat/completions"), 3);
+
+        let metrics = gateway.get("/metrics").send().await.unwrap().text().await.unwrap();
+        for (outcome, count) in [("unique", 2), ("coalesced", 1), ("bypassed", 1)] {
+            let line = format!(r#"gateway_coalesce_total{{model="m1",outcome="{}"}} {}"#, outcome, count);
+            assert!(metrics.lines().any(|l| l == line), "missing {} in {}", line, metrics);
+        }
+    }
+
+    #[actix_web::test]
+    async fn image_requests_skip_text_only_providers() {
+        let text = MockProvider::start(|_, _| ok_completion("m1", "text")).await;
+        let vision = MockProvider::start(|_, _| ok_completion("m1", "vision")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            model_capabilities = {{ "m1" = ["text"] }}
+            [[providers]]
+            base_url = "{}"
+            models = [{{ id = "m1", capabilities = ["text", "vision"] }}]
+        "#, text.url, vision.url)).await;
+
+        let image_request = json!({"model": "m1", "messages": [{"role": "user", "content": [
+            {"type": "text", "text": "what is this?"},
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}
+        ]}]});
+        let response = gateway.post("/v1/chat/completions", image_request.clone()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(text.hits("/v1/chat/completions"), 0);
+        assert_eq!(vision.hits("/v1/chat/completions"), 1);
+
+        // Text-only requests go to the primary as usual
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        assert_eq!(text.hits("/v1/chat/completions"), 1);
+
+        let text_only = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            model_capabilities = {{ "m1" = ["text"] }}
+        "#, text.url)).await;
+        let response = text_only.post("/v1/chat/completions", image_request).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "unsupported_content");
+        assert_eq!(text.hits("/v1/chat/completions"), 1);
+    }
+
+    #[actix_web::test]
+    async fn empty_streams_fail_over_to_the_next_provider() {
+        let empty = MockProvider::start(|_, _| sse(&[])).await;
+        let streaming = MockProvider::start(|_, _| sse(&[chunk("m1", "hello")])).await;
+        let gateway = Gateway::start(&format!(r#"
+            empty_stream = "failover"
+            circuit_breaker_threshold = 1
+            health_weighting = true
+            [[providers]]
+            name = "empty"
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            name = "streaming"
+            base_url = "{}"
+            models = ["m1"]
+        "#, empty.url, streaming.url)).await;
+
+        let mut request = chat_request("m1");
+        request["stream"] = true.into();
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let events = response.text().await.unwrap();
+        assert!(events.contains("hello"), "unexpected stream {}", events);
+        assert!(events.ends_with("data: [DONE]\n\n"));
+        assert_eq!(empty.hits("/v1/chat/completions"), 1);
+        assert_eq!(streaming.hits("/v1/chat/completions"), 1);
+        assert_failed_once(&gateway, "empty", "streaming").await;
+    }
+
+    #[actix_web::test]
+    async fn cost_centers_label_the_usage_metrics() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            cost_centers = ["search"]
+            require_cost_center = true
+            [[providers]]
+            name = "p1"
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        let response = gateway.post("/v1/chat/completions", chat_request("m1")).header("x-cost-center", "search").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let metrics = gateway.get("/metrics").send().await.unwrap().text().await.unwrap();
+        let line = r#"gateway_tokens_total{model="m1",provider="p1",kind="prompt",cost_center="search"} 1"#;
+        assert!(metrics.lines().any(|l| l == line), "missing {} in {}", line, metrics);
+
+        let response = gateway.post("/v1/chat/completions", chat_request("m1")).header("x-cost-center", "ads").send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "invalid_cost_center");
+        assert_eq!(upstream.hits("/v1/chat/completions"), 1);
+    }
+
+    #[actix_web::test]
+    async fn strict_fields_refuse_misspelled_parameters() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            strict_fields = true
+            extra_known_fields = ["top_k"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        let mut request = chat_request("m1");
+        request["temprature"] = 0.5.into();
+        request["top_k"] = 40.into();
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "unknown_parameter");
+        assert_eq!(body["error"]["unknown_fields"], json!(["temprature"]));
+        assert_eq!(upstream.hits("/v1/chat/completions"), 0);
+
+        let mut request = chat_request("m1");
+        request["temperature"] = 0.5.into();
+        request["top_k"] = 40.into();
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn excluded_providers_are_skipped_for_admin_requests() {
+        let first = MockProvider::start(|_, _| ok_completion("m1", "first")).await;
+        let second = MockProvider::start(|_, _| ok_completion("m1", "second")).await;
+        let gateway = Gateway::start(&format!(r#"
+            admin_api_key = "admin"
+            [[providers]]
+            name = "first"
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            name = "second"
+            base_url = "{}"
+            models = ["m1"]
+        "#, first.url, second.url)).await;
+        let excluding = |key: &str| gateway.post("/v1/chat/completions", chat_request("m1"))
+            .header("authorization", format!("Bearer {}", key))
+            .header("x-exclude-providers", "first, other")
+            .send();
+
+        assert_eq!(excluding("admin").await.unwrap().status(), 200);
+        assert_eq!(first.hits("/v1/chat/completions"), 0);
+        assert_eq!(second.hits("/v1/chat/completions"), 1);
+
+        // Other keys can't steer routing
+        assert_eq!(excluding(CLIENT_KEY).await.unwrap().status(), 200);
+        assert_eq!(first.hits("/v1/chat/completions"), 1);
+    }
+
+    /// Gateway where `meta/llama-3` is both a prefixed id of provider `meta` and a model discovered from `other`
+    async fn ambiguous_gateway(meta: &MockProvider, other: &MockProvider, settings: &str) -> Gateway {
+        Gateway::start(&format!(r#"
+            prefixed_model_ids = true
+            {}
+            [[providers]]
+            name = "meta"
+            base_url = "{}"
+            models = ["llama-3"]
+            [[providers]]
+            name = "other"
+            base_url = "{}"
+        "#, settings, meta.url, other.url)).await
+    }
+
+    #[actix_web::test]
+    async fn route_precedence_decides_ambiguous_models() {
+        let meta = MockProvider::start(|_, _| ok_completion("llama-3", "meta")).await;
+        let other = MockProvider::start(|request, _| match request.path.as_str() {
+            "/v1/models" => model_list(&[json!({"id": "meta/llama-3", "object": "model"})]),
+            _ => ok_completion("meta/llama-3", "other"),
+        }).await;
+
+        let gateway = ambiguous_gateway(&meta, &other, "").await;
+        assert_eq!(gateway.chat("meta/llama-3").await.status(), 200);
+        assert_eq!(meta.last().json()["model"], "llama-3");
+        assert_eq!(other.hits("/v1/chat/completions"), 0);
+
+        let gateway = ambiguous_gateway(&meta, &other, r#"route_precedence = ["discovered"]"#).await;
+        assert_eq!(gateway.chat("meta/llama-3").await.status(), 200);
+        assert_eq!(other.last().json()["model"], "meta/llama-3");
+        assert_eq!(meta.hits("/v1/chat/completions"), 1);
+
+        let gateway = ambiguous_gateway(&meta, &other, "reject_ambiguous_models = true").await;
+        let response = gateway.chat("meta/llama-3").await;
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "ambiguous_model");
+        assert_eq!(gateway.chat("meta/llama-3x").await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn aliases_colliding_with_discovered_ids_follow_route_precedence() {
+        let aliased = MockProvider::start(|_, _| ok_completion("m1", "aliased")).await;
+        let discovering = MockProvider::start(|request, _| match request.path.as_str() {
+            "/v1/models" => model_list(&[json!({"id": "fast", "object": "model"})]),
+            _ => ok_completion("fast", "discovered"),
+        }).await;
+        let config = |settings: &str| format!(r#"
+            {}
+            [[providers]]
+            name = "aliased"
+            base_url = "{}"
+            models = ["m1", "m2"]
+            model_aliases = {{ "fast" = "m1" }}
+            [[providers]]
+            name = "discovering"
+            base_url = "{}"
+        "#, settings, aliased.url, discovering.url);
+
+        let gateway = Gateway::start(&config("")).await;
+        assert_eq!(gateway.chat("fast").await.status(), 200);
+        assert_eq!(aliased.last().json()["model"], "m1");
+        assert_eq!(discovering.hits("/v1/chat/completions"), 0);
+
+        let gateway = Gateway::start(&config(r#"route_precedence = ["discovered"]"#)).await;
+        assert_eq!(gateway.chat("fast").await.status(), 200);
+        assert_eq!(discovering.last().json()["model"], "fast");
+        assert_eq!(aliased.hits("/v1/chat/completions"), 1);
+
+        let gateway = Gateway::start(&config("reject_ambiguous_models = true")).await;
+        let response = gateway.chat("fast").await;
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "ambiguous_model");
+        assert_eq!(gateway.chat("m2").await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn traceparent_is_started_or_continued_upstream() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            trace_propagation = true
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        let started = upstream.last().header("traceparent").unwrap().to_string();
+        let (trace_id, flags) = parse_traceparent(&started).unwrap();
+        assert_eq!(flags, "01");
+
+        let incoming = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+        let response = gateway.post("/v1/chat/completions", chat_request("m1"))
+            .header("traceparent", incoming)
+            .header("tracestate", "vendor=1")
+            .send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let forwarded = upstream.last();
+        let continued = forwarded.header("traceparent").unwrap();
+        assert!(continued.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(continued.ends_with("-00"));
+        assert_ne!(continued, incoming);
+        assert_ne!(parse_traceparent(continued).unwrap().0, trace_id);
+        assert_eq!(forwarded.header("tracestate"), Some("vendor=1"));
+    }
+
+    #[actix_web::test]
+    async fn malformed_json_fails_over_once() {
+        // Deliberately spaced so re-serializing would change it
+        const VALID: &str = r#"{"id": "chatcmpl-1",   "choices": []}"#;
+        let upstream = MockProvider::start(|_, count| {
+            let body = if count == 0 { r#"{"id": "chatcmpl-1", "choi"# } else { VALID };
+            HttpResponse::Ok().content_type("application/json").body(body)
+        }).await;
+        let gateway = Gateway::start(&format!(r#"
+            verify_json_max_bytes = 1024
+            circuit_breaker_threshold = 1
+            health_weighting = true
+            [[providers]]
+            name = "first"
+            base_url = "{0}"
+            models = ["m1"]
+            [[providers]]
+            name = "second"
+            base_url = "{0}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        let response = gateway.chat("m1").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), VALID);
+        assert_eq!(upstream.hits("/v1/chat/completions"), 2);
+        assert_failed_once(&gateway, "first", "second").await;
+    }
+
+    /// Check that the attempt on `failed` counted as a failure for its breaker and health, and the one on `served` didn't
+    async fn assert_failed_once(gateway: &Gateway, failed: &str, served: &str) {
+        let metrics = gateway.get("/metrics").send().await.unwrap().text().await.unwrap();
+        for line in [
+            format!(r#"gateway_circuit_breaker_state{{provider="{}",state="open"}} 1"#, failed),
+            format!(r#"gateway_circuit_breaker_state{{provider="{}",state="closed"}} 1"#, served),
+            format!(r#"gateway_provider_effective_weight{{provider="{}"}} 0.9"#, failed),
+            format!(r#"gateway_provider_effective_weight{{provider="{}"}} 1"#, served),
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing {} in {}", line, metrics);
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_soonest_retry_after_is_returned() {
+        let rate_limited = |secs: &'static str| move |_: &crate::testing::Recorded, _| {
+            let mut response = error(429, "slow down");
+            response.headers_mut().insert(actix_web::http::header::RETRY_AFTER, secs.parse().unwrap());
+            response
+        };
+        let soon = MockProvider::start(rate_limited("5")).await;
+        let later = MockProvider::start(rate_limited("30")).await;
+        let gateway = Gateway::start(&format!(r#"
+            aggregate_retry_after = true
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, soon.url, later.url)).await;
+
+        let response = gateway.chat("m1").await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()["retry-after"], "5");
+        assert_eq!(later.hits("/v1/chat/completions"), 1);
+    }
+
+    #[actix_web::test]
+    async fn provider_request_limits_skip_to_providers_that_fit() {
+        let small = MockProvider::start(|_, _| ok_completion("m1", "small")).await;
+        let large = MockProvider::start(|_, _| ok_completion("m1", "large")).await;
+        let providers = format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            max_request_bytes = 200
+        "#, small.url);
+        let gateway = Gateway::start(&format!(r#"
+            {}
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, providers, large.url)).await;
+        let mut big_request = chat_request("m1");
+        big_request["messages"][0]["content"] = "x".repeat(500).into();
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        assert_eq!(small.hits("/v1/chat/completions"), 1);
+        let response = gateway.post("/v1/chat/completions", big_request.clone()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(small.hits("/v1/chat/completions"), 1);
+        assert_eq!(large.hits("/v1/chat/completions"), 1);
+
+        let gateway = Gateway::start(&providers).await;
+        let response = gateway.post("/v1/chat/completions", big_request).send().await.unwrap();
+        assert_eq!(response.status(), 413);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "request_too_large");
+        assert_eq!(small.hits("/v1/chat/completions"), 1);
+    }
+
+    #[actix_web::test]
+    async fn provider_response_limits_fail_over() {
+        let verbose = MockProvider::start(|_, _| ok_completion("m1", &"x".repeat(500))).await;
+        let terse = MockProvider::start(|_, _| ok_completion("m1", "terse")).await;
+        let providers = format!(r#"
+            max_response_bytes = 100000
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            max_response_bytes = 300
+        "#, verbose.url);
+        let gateway = Gateway::start(&format!(r#"
+            {}
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, providers, terse.url)).await;
+
+        let response = gateway.chat("m1").await;
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "terse");
+        assert_eq!(verbose.hits("/v1/chat/completions"), 1);
+
+        let gateway = Gateway::start(&providers).await;
+        let response = gateway.chat("m1").await;
+        assert_eq!(response.status(), 502);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "response_too_large");
+    }
+
+    #[actix_web::test]
+    async fn last_resort_providers_are_only_tried_after_every_other() {
+        let local = MockProvider::start(|_, _| ok_completion("small", "local")).await;
+        let cloud = MockProvider::start(|_, count| match count {
+            0 => ok_completion("m1", "cloud"),
+            _ => error(503, "down"),
+        }).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            name = "local"
+            base_url = "{}"
+            models = ["m1", "small"]
+            last_resort = true
+            last_resort_model = "small"
+            [[providers]]
+            name = "cloud"
+            base_url = "{}"
+            models = ["m1"]
+        "#, local.url, cloud.url)).await;
+
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+        assert_eq!(local.hits("/v1/chat/completions"), 0);
+
+        let response = gateway.chat("m1").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(cloud.hits("/v1/chat/completions"), 2);
+        assert_eq!(local.hits("/v1/chat/completions"), 1);
+        assert_eq!(local.last().json()["model"], "small");
+    }
+
+    #[actix_web::test]
+    async fn listed_success_statuses_pass_through_untouched() {
+        let upstream = MockProvider::start(|request, _| match request.path.as_str() {
+            "/v1/chat/completions" => HttpResponse::NonAuthoritativeInformation().json(crate::testing::completion("m1", "hi")),
+            _ => HttpResponse::Found().insert_header(("location", "/v1/elsewhere")).finish(),
+        }).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1", "moved"]
+            success_statuses = [302]
+        "#, upstream.url)).await;
+
+        let response = gateway.chat("m1").await;
+        assert_eq!(response.status(), 203);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body, crate::testing::completion("m1", "hi"));
+
+        let response = gateway.post("/v1/embeddings", json!({"model": "moved", "input": "hi"})).send().await.unwrap();
+        assert_eq!(response.status(), 302);
+        assert_eq!(response.headers()["location"], "/v1/elsewhere");
+        assert_eq!(upstream.hits("/v1/elsewhere"), 0);
+    }
+
+    #[actix_web::test]
+    async fn system_prompt_templates_are_rendered_and_otherwise_absent() {
+        let body = forwarded_body(r#"system_prompts = { "m1" = "Serving {{model}}." }"#, "", chat_request("m1")).await;
+        assert_eq!(body["messages"], json!([
+            {"role": "system", "content": "Serving m1."},
+            {"role": "user", "content": "hi"}
+        ]));
+
+        let body = forwarded_body("", "", chat_request("m1")).await;
+        assert_eq!(body["messages"], chat_request("m1")["messages"]);
+    }
+
+    #[actix_web::test]
+    async fn request_timeout_header_overrides_the_timeout_up_to_the_max() {
+        let upstream = MockProvider::start_delayed(Duration::from_millis(1200), |_, _| ok_completion("m1", "slow")).await;
+        let gateway = Gateway::start(&format!(r#"
+            request_timeout_secs = 1
+            allow_timeout_override = true
+            max_timeout_override_secs = 2
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+        let with_timeout = |value: &str| gateway.post("/v1/chat/completions", chat_request("m1")).header("x-request-timeout", value).send();
+
+        assert!(gateway.chat("m1").await.status().is_server_error());
+
+        let response = with_timeout("2").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("x-request-timeout-clamped").is_none());
+
+        let response = with_timeout("100").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-request-timeout-clamped"], "2");
+
+        let response = with_timeout("soon").await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "invalid_timeout");
+    }
+
+    #[actix_web::test]
+    async fn streams_record_their_time_to_first_token() {
+        let upstream = MockProvider::start_delayed(Duration::from_millis(100), |_, _| sse(&[chunk("m1", "hello")])).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            name = "p1"
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+
+        let mut request = chat_request("m1");
+        request["stream"] = true.into();
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert!(response.text().await.unwrap().contains("hello"));
+
+        let metrics = gateway.get("/metrics").send().await.unwrap().text().await.unwrap();
+        let count = format!(r#"{}_count{{model="m1",provider="p1"}} 1"#, TTFT_METRIC);
+        assert!(metrics.lines().any(|l| l == count), "missing {} in {}", count, metrics);
+        let sum = metrics.lines()
+            .find_map(|l| l.strip_prefix(&format!(r#"{}_sum{{model="m1",provider="p1"}} "#, TTFT_METRIC)))
+            .and_then(|sum| sum.parse::<f64>().ok())
+            .unwrap();
+        assert!(sum >= 0.1, "{}", sum);
+    }
+
+    #[actix_web::test]
+    async fn vary_headers_keep_cache_entries_apart() {
+        let upstream = MockProvider::start(|_, count| ok_completion("m1", &format!("answer {}", count))).await;
+        let gateway = Gateway::start(&format!(r#"
+            response_cache_ttl_secs = 300
+            cache_vary = ["X-Tenant"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, upstream.url)).await;
+        let as_tenant = |tenant: &str| gateway.post("/v1/chat/completions", chat_request("m1")).header("x-tenant", tenant).send();
+        let answer = |body: Value| body["choices"][0]["message"]["content"].clone();
+
+        let response = as_tenant("a").await.unwrap();
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(answer(response.json().await.unwrap()), "answer 0");
+
+        let response = as_tenant("b").await.unwrap();
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(answer(response.json().await.unwrap()), "answer 1");
+
+        let response = as_tenant("a").await.unwrap();
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(answer(response.json().await.unwrap()), "answer 0");
+        assert_eq!(upstream.hits("/v1/chat/completions"), 2);
+    }
+
+    #[actix_web::test]
+    async fn redirects_are_only_followed_when_enabled_and_keep_the_key_on_the_same_host() {
+        let elsewhere = MockProvider::start(|_, _| ok_completion("m1", "elsewhere")).await;
+        let target = format!("{}/chat/completions", elsewhere.url);
+        let upstream = MockProvider::start(move |request, _| match request.path.as_str() {
+            "/v1/chat/completions" => HttpResponse::Found().insert_header(("location", "/v1/moved")).finish(),
+            "/v1/embeddings" => HttpResponse::TemporaryRedirect().insert_header(("location", target.as_str())).finish(),
+            _ => ok_completion("m1", "moved"),
+        }).await;
+        let config = |settings: &str| format!(r#"
+            [[providers]]
+            base_url = "{}"
+            api_key = "sk-1"
+            models = ["m1"]
+            {}
+        "#, upstream.url, settings);
+
+        let response = Gateway::start(&config("")).await.chat("m1").await;
+        assert_eq!(response.status(), 502);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "upstream_redirect");
+        assert_eq!(upstream.hits("/v1/moved"), 0);
+
+        let following = Gateway::start(&config("follow_redirects = true\nredirect_auth = \"same_host\"")).await;
+        assert_eq!(following.chat("m1").await.status(), 200);
+        assert_eq!(upstream.last().path, "/v1/moved");
+        assert_eq!(upstream.last().header("authorization"), Some("Bearer sk-1"));
+
+        // Another port is another host, which doesn't get the key
+        let response = following.post("/v1/embeddings", json!({"model": "m1", "input": "hi"})).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(elsewhere.hits("/v1/chat/completions"), 1);
+        assert!(elsewhere.last().header("authorization").is_none_or(|auth| !auth.contains("sk-1")));
+    }
+
+    #[actix_web::test]
+    async fn requests_over_the_context_window_are_refused() {
+        let small = MockProvider::start(|_, _| ok_completion("m1", "small")).await;
+        let large = MockProvider::start(|_, _| ok_completion("m1", "large")).await;
+        let small_provider = format!(r#"
+            enforce_context_windows = true
+            context_windows = {{ "m1" = 1000 }}
+            [[providers]]
+            base_url = "{}"
+            models = [{{ id = "m1", context_window = 50 }}]
+        "#, small.url);
+        let mut request = chat_request("m1");
+        request["messages"][0]["content"] = "word ".repeat(80).into();
+        request["max_tokens"] = 20.into();
+
+        let gateway = Gateway::start(&format!(r#"
+            {}
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, small_provider, large.url)).await;
+        let response = gateway.post("/v1/chat/completions", request.clone()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(small.hits("/v1/chat/completions"), 0);
+        assert_eq!(large.hits("/v1/chat/completions"), 1);
+
+        let gateway = Gateway::start(&small_provider).await;
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "context_length_exceeded");
+        assert!(body["error"]["message"].as_str().unwrap().ends_with("limit is 50"));
+
+        // A request that fits goes through
+        assert_eq!(gateway.chat("m1").await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn force_stream_converts_between_streams_and_completions() {
+        let streaming = MockProvider::start(|_, _| sse(&[chunk("on", "hel"), chunk("on", "lo")])).await;
+        let buffered = MockProvider::start(|_, _| ok_completion("off", "hello")).await;
+        let gateway = Gateway::start(&format!(r#"
+            force_stream = {{ "on" = true, "off" = false }}
+            [[providers]]
+            base_url = "{}"
+            models = ["on"]
+            [[providers]]
+            base_url = "{}"
+            models = ["off"]
+        "#, streaming.url, buffered.url)).await;
+
+        let response = gateway.chat("on").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(streaming.last().json()["stream"], true);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["choices"][0]["message"]["content"], "hello");
+
+        let mut request = chat_request("off");
+        request["stream"] = true.into();
+        request["stream_options"] = json!({"include_usage": true});
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let forwarded = buffered.last().json();
+        assert!(forwarded.get("stream").is_none() && forwarded.get("stream_options").is_none());
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/event-stream"));
+        let events = response.text().await.unwrap();
+        let first: Value = serde_json::from_str(events.strip_prefix("data: ").unwrap().split("\n\n").next().unwrap()).unwrap();
+        assert_eq!(first["object"], "chat.completion.chunk");
+        assert_eq!(first["choices"][0]["delta"]["content"], "hello");
+        assert!(events.ends_with("data: [DONE]\n\n"));
+    }
+
+    #[actix_web::test]
+    async fn newer_fields_are_passed_stripped_or_rejected() {
+        let mut request = chat_request("m1");
+        request["store"] = true.into();
+        request["metadata"] = json!({"team": "search"});
+
+        let body = forwarded_body("", "", request.clone()).await;
+        assert_eq!((&body["store"], &body["metadata"]), (&request["store"], &request["metadata"]));
+
+        let body = forwarded_body("", r#"newer_fields = "strip""#, request.clone()).await;
+        assert!(body.get("store").is_none() && body.get("metadata").is_none());
+        assert_eq!(body["messages"], request["messages"]);
+
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            newer_fields = "reject"
+        "#, upstream.url)).await;
+        let response = gateway.post("/v1/chat/completions", request).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "unsupported_parameter");
+        assert_eq!(upstream.hits("/v1/chat/completions"), 0);
+    }
+
+    #[actix_web::test]
+    async fn prompt_cache_hints_are_attached_upstream() {
+        let upstream = MockProvider::start(|_, _| ok_completion("m1", "hi")).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            prompt_cache_headers = {{ "anthropic-beta" = "prompt-caching-2024-07-31" }}
+            prompt_cache_key = true
+        "#, upstream.url)).await;
+        let with_system = |system: &str, question: &str| json!({"model": "m1", "messages": [
+            {"role": "system", "content": system},
+            {"role": "user", "content": question}
+        ]});
+        let key_for = |request: Value| {
+            let (gateway, upstream) = (&gateway, &upstream);
+            async move {
+                assert_eq!(gateway.post("/v1/chat/completions", request).send().await.unwrap().status(), 200);
+                upstream.last().json()["prompt_cache_key"].clone()
+            }
+        };
+
+        let first = key_for(with_system("be brief", "one")).await;
+        assert!(first.is_string());
+        assert_eq!(upstream.last().header("anthropic-beta"), Some("prompt-caching-2024-07-31"));
+        assert_eq!(key_for(with_system("be brief", "two")).await, first);
+        assert_ne!(key_for(with_system("be verbose", "one")).await, first);
+        assert!(key_for(chat_request("m1")).await.is_null());
+
+        let mut own_key = with_system("be brief", "one");
+        own_key["prompt_cache_key"] = "mine".into();
+        assert_eq!(key_for(own_key).await, "mine");
+    }
+
+    #[actix_web::test]
+    async fn failover_tries_each_provider_and_returns_the_last_error() {
+        let down = MockProvider::start(|_, _| error(503, "a is down")).await;
+        let busy = MockProvider::start(|_, _| error(429, "b is busy")).await;
+        let healthy = MockProvider::start(|_, _| ok_completion("m1", "c")).await;
+        let config = |extra: &str| format!(r#"
+            {}
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+        "#, extra, down.url, busy.url, healthy.url);
+
+        let response = Gateway::start(&config("")).await.chat("m1").await;
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "c");
+
+        let response = Gateway::start(&config("max_retries = 1")).await.chat("m1").await;
+        assert_eq!(response.status(), 429);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["message"], "b is busy");
+        assert_eq!(healthy.hits("/v1/chat/completions"), 1);
+
+        // A later provider failing without an answer leaves the last error response in place
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            models = ["m1"]
+            [[providers]]
+            base_url = "http://127.0.0.1:1/v1"
+            models = ["m1"]
+        "#, down.url)).await;
+        let response = gateway.chat("m1").await;
+        assert_eq!(response.status(), 503);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["message"], "a is down");
+    }
+
+    #[actix_web::test]
+    async fn embeddings_are_passed_through_without_chat_rewrites() {
+        let embeddings = json!({"object": "list", "model": "e1", "data": [
+            {"object": "embedding", "index": 0, "embedding": [0.1, 0.2]},
+            {"object": "embedding", "index": 1, "embedding": [0.3, 0.4]}
+        ]});
+        let answer = embeddings.clone();
+        let upstream = MockProvider::start(move |_, _| actix_web::HttpResponse::Ok().json(&answer)).await;
+        let gateway = Gateway::start(&format!(r#"
+            [[providers]]
+            base_url = "{}"
+            api_key = "sk-upstream"
+            models = ["e1"]
+            default_params = {{ temperature = 0.7 }}
+            force_json_mode = true
+            prompt_cache_key = true
+        "#, upstream.url)).await;
+
+        for input in [json!("one text"), json!(["one", "two"])] {
+            let request = json!({"model": "e1", "input": input});
+            let response = gateway.post("/v1/embeddings", request.clone()).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.json::<Value>().await.unwrap(), embeddings);
+
+            let forwarded = upstream.last();
+            assert_eq!(forwarded.path, "/v1/embeddings");
+            assert_eq!(forwarded.header("authorization"), Some("Bearer sk-upstream"));
+            assert_eq!(forwarded.json(), request);
+        }
+        assert_eq!(upstream.hits("/v1/chat/completions"), 0);
+    }
+
+    #[actix_web::test]
+    async fn prefixed_and_aliased_models_are_forwarded_by_their_upstream_id() {
+        let forwarded = forwarded_body("", r#"alias_prefix = "or""#, chat_request("or/m1")).await;
+        assert_eq!(forwarded["model"], "m1");
+
+        let forwarded = forwarded_body("", r#"model_aliases = { "fast" = "m1" }"#, chat_request("fast")).await;
+        assert_eq!(forwarded["model"], "m1");
+    }
+
+    #[test]
+    fn logged_request_bodies_leave_out_redacted_fields() {
+        let mut body = chat_request("m1");
+        body["messages"][0]["content"] = "secret-message".into();
+        body["metadata"] = json!({"user": "secret-user", "team": "search"});
+
+        let line = request_body_log_line(&config("providers = []"), "chat/completions", &body);
+        assert!(line.starts_with("Request body for chat/completions: "), "{}", line);
+        assert!(!line.contains("secret-message") && line.contains("secret-user"), "{}", line);
+
+        let config = config(r#"
+            log_redact_fields = ["metadata.user"]
+            providers = []
+        "#);
+        let line = request_body_log_line(&config, "chat/completions", &body);
+        assert!(!line.contains("secret-user") && line.contains("search"), "{}", line);
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Token bucket allowing bursts up to its capacity and refilling continuously
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Bucket allowing `per_minute` requests per minute, starting full
+    pub fn per_minute(per_minute: u32) -> Self {
+        Self::new(per_minute as f64, per_minute as f64 / 60.0)
+    }
+
+    /// Bucket allowing `per_second` requests per second, with bursts of at least one request
+    pub fn per_second(per_second: f64) -> Self {
+        Self::new(per_second.max(1.0), per_second)
+    }
+
+    pub fn new(capacity: f64, refill_per_sec: f64) -> Self {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take one token, or return how long until one is available
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.refill_per_sec <= 0.0 {
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec))
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+/// Number of events over the last second
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    events: VecDeque<Instant>,
+}
+
+impl RateMeter {
+    pub fn record(&mut self) {
+        let now = Instant::now();
+        self.expire(now);
+        self.events.push_back(now);
+    }
+
+    /// Events recorded during the last second
+    pub fn per_second(&mut self) -> usize {
+        self.expire(Instant::now());
+        self.events.len()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.events.front().is_some_and(|event| now.duration_since(*event) >= Duration::from_secs(1)) {
+            self.events.pop_front();
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::{Arc, RwLock};
+
+use crate::config::Config;
+use crate::state::AppState;
+
+/// Configuration serving requests, replaced as a whole when a reloaded file passes validation
+/// Requests keep the configuration they started with until they finish
+pub struct LiveConfig {
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        LiveConfig { current: RwLock::new(Arc::new(config)) }
+    }
+
+    /// Configuration to handle a new request with
+    pub fn current(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Load, validate and optionally probe the configuration at `path`, then swap it in
+    /// On any failure the configuration in use stays active and the error names what failed
+    pub async fn reload(&self, path: &str, state: &AppState) -> Result<(), String> {
+        let mut config = Config::load_from(path).map_err(|e| e.to_string())?;
+        if config.reload_probe_providers {
+            let unreachable = config.unreachable_providers().await;
+            if !unreachable.is_empty() {
+                return Err(format!("Providers failed the reload probe:\n  - {}", unreachable.join("\n  - ")));
+            }
+        }
+
+        // Providers may have been renamed or pointed elsewhere
+        state.forget_removed_providers(&config);
+        let mut current = self.current.write().unwrap();
+        config.inherit_start_time(&current);
+        *current = Arc::new(config);
+        drop(current);
+        state.model_cache.clear();
+        Ok(())
+    }
+}
+
+/// Reload the configuration file every time the process receives SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_sighup(live: actix_web::web::Data<LiveConfig>, state: actix_web::web::Data<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            eprintln!("Warning: Cannot listen for SIGHUP, configuration reload is unavailable: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        println!("Received SIGHUP, reloading {}", crate::config::CONFIG_PATH);
+        match live.reload(crate::config::CONFIG_PATH, &state).await {
+            Ok(()) => println!("Configuration reloaded"),
+            Err(e) => eprintln!("Error: Configuration reload failed, keeping the current configuration: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = r#"
+        [[providers]]
+        name = "alpha"
+        base_url = "http://alpha/v1"
+        api_key = "k"
+    "#;
+
+    /// Write `content` to a file of its own in the temp directory
+    fn config_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("reload-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn live(path: &str) -> (LiveConfig, AppState) {
+        let config = Config::load_from(path).unwrap();
+        let state = AppState::new(&config);
+        (LiveConfig::new(config), state)
+    }
+
+    #[actix_web::test]
+    async fn valid_reload_replaces_the_config() {
+        let path = config_file("valid", VALID);
+        let (live, state) = live(&path);
+        std::fs::write(&path, VALID.replace("alpha", "beta")).unwrap();
+        live.reload(&path, &state).await.unwrap();
+        assert_eq!(live.current().providers[0].name(), "beta");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn invalid_reload_keeps_the_old_config() {
+        let path = config_file("invalid", VALID);
+        let (live, state) = live(&path);
+        // The second provider is invalid, so the valid first one must not be applied either
+        std::fs::write(&path, format!("{}{}", VALID.replace("alpha", "beta"), r#"
+            [[providers]]
+            base_url = "ftp://gamma"
+            api_key = "k"
+        "#)).unwrap();
+        let error = live.reload(&path, &state).await.unwrap_err();
+        assert!(error.contains("provider 2 has unsupported base_url"));
+        assert_eq!(live.current().providers.len(), 1);
+        assert_eq!(live.current().providers[0].name(), "alpha");
+
+        std::fs::write(&path, "providers = [").unwrap();
+        assert!(live.reload(&path, &state).await.is_err());
+        assert_eq!(live.current().providers[0].name(), "alpha");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn unreachable_providers_fail_the_reload_probe() {
+        let path = config_file("probe", VALID);
+        let (live, state) = live(&path);
+        std::fs::write(&path, r#"
+            reload_probe_providers = true
+            [[providers]]
+            name = "down"
+            base_url = "http://127.0.0.1:1/v1"
+            api_key = "k"
+        "#).unwrap();
+        let error = live.reload(&path, &state).await.unwrap_err();
+        assert!(error.contains("provider down at http://127.0.0.1:1/v1 is unreachable"));
+        assert_eq!(live.current().providers[0].name(), "alpha");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn reloads_keep_the_state_of_remaining_providers_only() {
+        let two = format!("circuit_breaker_threshold = 1\n{}{}", VALID, VALID.replace("alpha", "beta"));
+        let path = config_file("state", &two);
+        let (live, state) = live(&path);
+        let config = live.current();
+        state.set_provider_enabled("alpha", false);
+        state.record_provider_outcome(&config, "alpha", false);
+        state.set_provider_enabled("beta", false);
+        state.record_provider_outcome(&config, "beta", false);
+
+        // beta is renamed to gamma, so nothing of its state may stick to the old name
+        std::fs::write(&path, two.replace("beta", "gamma")).unwrap();
+        live.reload(&path, &state).await.unwrap();
+        assert!(!state.is_provider_enabled("alpha"));
+        assert!(state.circuit_open_for("alpha").is_some());
+        assert!(state.is_provider_enabled("gamma"));
+        assert!(state.circuit_open_for("gamma").is_none());
+
+        std::fs::write(&path, &two).unwrap();
+        live.reload(&path, &state).await.unwrap();
+        assert!(state.is_provider_enabled("beta"));
+        assert!(state.circuit_open_for("beta").is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde_json::Value;
+use crate::config::{Config, Provider, RoutingMode, RoutingRule};
+use crate::tokens;
+
+/// Order the providers serving a model into the sequence of attempts for one request
+/// The first entry is the primary, picked according to the routing mode; the rest are ordered by `failover_priority`
+/// `health` scales each provider's weight, 1.0 for a fully healthy provider
+/// `latency` is the provider's recent response latency, used by blend routing
+pub fn order_candidates(
+    config: &Config,
+    model: &str,
+    candidates: &[Provider],
+    health: impl Fn(&Provider) -> f64,
+    latency: impl Fn(&Provider) -> Option<Duration>,
+) -> Vec<Provider> {
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+
+    let primary_index = match config.routing {
+        RoutingMode::Priority => select_weighted(candidates, &health),
+        RoutingMode::Cost => select_cheapest(model, candidates).unwrap_or_else(|| select_weighted(candidates, &health)),
+        RoutingMode::Blend => select_blended(candidates, config.blend_weights(), &health, &latency),
+    };
+    let mut ordered = vec![candidates[primary_index].clone()];
+
+    // Remaining providers keep configuration order among equal failover priorities
+    let mut remaining: Vec<(usize, &Provider)> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != primary_index)
+        .collect();
+    remaining.sort_by_key(|(i, provider)| (provider.failover_priority.unwrap_or(u32::MAX), *i));
+    ordered.extend(remaining.into_iter().map(|(_, provider)| provider.clone()));
+
+    ordered
+}
+
+/// Provider named by the first routing rule matching a request for the model, if any
+pub fn preferred_provider<'a>(config: &'a Config, model: &str, request: &Value) -> Option<&'a str> {
+    // Only estimate tokens when some rule needs them
+    let mut estimate = None;
+    config.routing_rules.iter()
+        .find(|rule| rule_matches(rule, model, request, &mut estimate))
+        .map(|rule| rule.provider.as_str())
+}
+
+fn rule_matches(rule: &RoutingRule, model: &str, request: &Value, estimate: &mut Option<u64>) -> bool {
+    if rule.model.as_deref().is_some_and(|m| m != model) {
+        return false;
+    }
+    if rule.has_images.is_some_and(|images| images != tokens::has_non_text_content(request)) {
+        return false;
+    }
+    let has_tools = request.get("tools").and_then(|t| t.as_array()).is_some_and(|t| !t.is_empty());
+    if rule.has_tools.is_some_and(|tools| tools != has_tools) {
+        return false;
+    }
+    if rule.min_tokens.is_some() || rule.max_tokens.is_some() {
+        let estimate = *estimate.get_or_insert_with(|| tokens::estimate_request_tokens(request));
+        if rule.min_tokens.is_some_and(|min| estimate < min) || rule.max_tokens.is_some_and(|max| estimate >= max) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Pick the index of the cheapest provider with a known price for the model
+/// Ties go to the higher priority provider; None when no price is known
+fn select_cheapest(model: &str, candidates: &[Provider]) -> Option<usize> {
+    candidates.iter()
+        .enumerate()
+        .filter_map(|(i, provider)| provider.price_for(model).map(|price| (i, price)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+}
+
+/// Pick the index of the provider with the best blend of priority and latency, scaled by health
+/// Priority scores `1 / rank` and latency `1 / (1 + seconds)`, both at most 1.0;
+/// providers not timed yet score 1.0 on latency so they get a chance to be measured
+/// Ties go to the higher priority provider
+fn select_blended(
+    candidates: &[Provider],
+    (priority_weight, latency_weight): (f64, f64),
+    health: &impl Fn(&Provider) -> f64,
+    latency: &impl Fn(&Provider) -> Option<Duration>,
+) -> usize {
+    let mut best = (0, f64::NEG_INFINITY);
+    for (i, provider) in candidates.iter().enumerate() {
+        let priority_score = 1.0 / (i + 1) as f64;
+        let latency_score = latency(provider).map_or(1.0, |latency| 1.0 / (1.0 + latency.as_secs_f64()));
+        let score = (priority_weight * priority_score + latency_weight * latency_score) * health(provider);
+        if score > best.1 {
+            best = (i, score);
+        }
+    }
+    best.0
+}
+
+/// Pick the index of the primary provider by weight, scaled by each provider's health
+/// Without any configured weight the highest priority (first) provider is the primary
+fn select_weighted(candidates: &[Provider], health: &impl Fn(&Provider) -> f64) -> usize {
+    if candidates.iter().all(|p| p.weight.is_none()) {
+        return 0;
+    }
+
+    // Providers without an explicit weight count as weight 1
+    let weights: Vec<f64> = candidates.iter().map(|p| p.weight.unwrap_or(1) as f64 * health(p)).collect();
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return 0;
+    }
+
+    let mut roll = rand::rng().random_range(0.0..total);
+    for (i, weight) in weights.iter().enumerate() {
+        if roll < *weight {
+            return i;
+        }
+        roll -= weight;
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).expect("test config should parse")
+    }
+
+    fn order(config: &Config, model: &str) -> Vec<String> {
+        order_candidates(config, model, &config.providers, |_| 1.0, |_| None)
+            .iter()
+            .map(|provider| provider.name())
+            .collect()
+    }
+
+    #[test]
+    fn failover_priority_orders_the_attempts_after_the_primary() {
+        let config = config(r#"
+            [[providers]]
+            name = "a"
+            base_url = "http://a/v1"
+            [[providers]]
+            name = "b"
+            base_url = "http://b/v1"
+            failover_priority = 3
+            [[providers]]
+            name = "c"
+            base_url = "http://c/v1"
+            failover_priority = 1
+        "#);
+        assert_eq!(order(&config, "m"), ["a", "c", "b"]);
+    }
+
+    #[test]
+    fn weights_pick_the_primary_independently_of_failover_priority() {
+        let config = config(r#"
+            [[providers]]
+            name = "a"
+            base_url = "http://a/v1"
+            weight = 0
+            failover_priority = 2
+            [[providers]]
+            name = "b"
+            base_url = "http://b/v1"
+            weight = 0
+            [[providers]]
+            name = "c"
+            base_url = "http://c/v1"
+            weight = 5
+            failover_priority = 9
+        "#);
+        for _ in 0..20 {
+            assert_eq!(order(&config, "m"), ["c", "a", "b"]);
+        }
+    }
+
+    #[test]
+    fn cost_routing_picks_the_cheapest_priced_provider() {
+        let prices = |a: &str, b: &str| config(&format!(r#"
+            routing = "cost"
+            [[providers]]
+            name = "a"
+            base_url = "http://a/v1"
+            model_prices = {{ "m" = {} }}
+            [[providers]]
+            name = "b"
+            base_url = "http://b/v1"
+            models = [{{ id = "m", price = {} }}]
+            [[providers]]
+            name = "c"
+            base_url = "http://c/v1"
+        "#, a, b));
+        assert_eq!(order(&prices("0.59", "0.79"), "m"), ["a", "b", "c"]);
+        assert_eq!(order(&prices("0.99", "0.79"), "m"), ["b", "a", "c"]);
+        // Without any price the highest priority provider stays the primary
+        assert_eq!(order(&prices("0.99", "0.79"), "other"), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn blend_routing_weighs_priority_against_latency() {
+        let blend = |priority: f64, latency: f64| config(&format!(r#"
+            routing = "blend"
+            blend_priority_weight = {:?}
+            blend_latency_weight = {:?}
+            [[providers]]
+            name = "a"
+            base_url = "http://a/v1"
+            [[providers]]
+            name = "b"
+            base_url = "http://b/v1"
+            [[providers]]
+            name = "c"
+            base_url = "http://c/v1"
+        "#, priority, latency));
+        let primary = |config: &Config, c_latency: Option<Duration>| {
+            let latency = |provider: &Provider| match provider.name().as_str() {
+                "a" => Some(Duration::from_secs(3)),
+                "b" => Some(Duration::from_millis(250)),
+                _ => c_latency,
+            };
+            order_candidates(config, "m", &config.providers, |_| 1.0, latency)[0].name()
+        };
+        let c_latency = Some(Duration::from_secs(1));
+
+        // a scores 1 + 0.25, b 0.5 + 0.8 and c 0.33 + 0.5
+        assert_eq!(primary(&blend(1.0, 1.0), c_latency), "b");
+        // a scores 3 + 0.25 against b's 1.5 + 0.8
+        assert_eq!(primary(&blend(3.0, 1.0), c_latency), "a");
+        assert_eq!(primary(&blend(1.0, 0.0), c_latency), "a");
+        // An untimed provider scores full marks on latency: 0.33 + 1
+        assert_eq!(primary(&blend(1.0, 1.0), None), "c");
+    }
+
+    #[test]
+    fn routing_rules_pick_the_provider_by_images_and_size() {
+        let config = config(r#"
+            [[providers]]
+            name = "default"
+            base_url = "http://default/v1"
+            [[providers]]
+            name = "vision"
+            base_url = "http://vision/v1"
+            [[providers]]
+            name = "large"
+            base_url = "http://large/v1"
+
+            [[routing_rules]]
+            has_images = true
+            provider = "vision"
+
+            [[routing_rules]]
+            model = "m"
+            min_tokens = 1000
+            provider = "large"
+        "#);
+        let text = |max_tokens: u64| json!({"model": "m", "max_tokens": max_tokens, "messages": [
+            {"role": "user", "content": "hi"}
+        ]});
+        let image = json!({"model": "m", "messages": [{"role": "user", "content": [
+            {"type": "text", "text": "what is this?"},
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}
+        ]}]});
+
+        assert_eq!(preferred_provider(&config, "m", &image), Some("vision"));
+        assert_eq!(preferred_provider(&config, "m", &text(2000)), Some("large"));
+        assert_eq!(preferred_provider(&config, "other", &text(2000)), None);
+        assert_eq!(preferred_provider(&config, "m", &text(10)), None);
+    }
+}
@@ -0,0 +1,394 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use crate::circuit::{BreakerPolicy, BreakerState, CircuitBreaker};
+use crate::cache::{ModelListCache, ResponseCache};
+use crate::coalesce::Coalescer;
+use crate::stats::OutcomeWindow;
+use crate::config::{Config, Provider, RoutingMode};
+use crate::metrics::Metrics;
+use crate::ratelimit::{RateMeter, TokenBucket};
+
+/// Default number of recent requests kept for `/admin/requests`
+const DEFAULT_REQUEST_LOG_SIZE: usize = 100;
+
+/// Default length in seconds of the window model success rates are computed over
+const DEFAULT_MODEL_STATS_WINDOW_SECS: u64 = 300;
+
+/// Share of each new latency sample in a provider's smoothed latency
+const LATENCY_DECAY: f64 = 0.2;
+
+/// Smallest share of its weight a failing provider keeps, so it still sees traffic to recover with
+const MIN_HEALTH_FACTOR: f64 = 0.05;
+
+/// Runtime state shared by all workers
+pub struct AppState {
+    /// Most recent proxied requests, oldest first
+    request_log: Mutex<VecDeque<RequestLogEntry>>,
+    request_log_size: usize,
+    /// Names of providers taken out of rotation through the admin endpoints
+    disabled_providers: Mutex<HashSet<String>>,
+    /// Request budget of each model with a configured rate limit, shared by all clients
+    model_buckets: Mutex<HashMap<String, TokenBucket>>,
+    /// Request budget of each provider with `max_rps`, by provider name
+    provider_buckets: Mutex<HashMap<String, TokenBucket>>,
+    /// Requests sent to each provider over the last second, by provider name
+    provider_rates: Mutex<HashMap<String, RateMeter>>,
+    /// Index of the key in use for each provider whose primary key was rotated out, by provider name
+    active_keys: Mutex<HashMap<String, usize>>,
+    /// Circuit breaker of each provider that has been attempted, by provider name
+    circuit_breakers: Mutex<HashMap<String, CircuitBreaker>>,
+    /// Circuit breaker settings, None when the breaker is disabled
+    circuit_policy: Option<BreakerPolicy>,
+    /// Smoothed recent error rate of each provider, by provider name, when health weighting is on
+    provider_error_rates: Mutex<HashMap<String, f64>>,
+    /// Smoothed recent response latency of each provider in seconds, by provider name, when blend routing is on
+    provider_latencies: Mutex<HashMap<String, f64>>,
+    /// Whether routable endpoints answer 503, toggled through the admin endpoint
+    maintenance: AtomicBool,
+    /// Requests in flight from each client IP, when `max_connections_per_ip` is set
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    /// Counters and histograms exported by `/metrics`, shared with streams that outlive their handler
+    pub metrics: Arc<Metrics>,
+    /// Identical requests in flight, when `coalesce_requests` is enabled
+    pub coalescer: Coalescer,
+    /// Responses kept for identical requests, when `response_cache_ttl_secs` is set
+    pub response_cache: ResponseCache,
+    /// Models last discovered from each provider, reused for `model_cache_ttl_secs`
+    pub model_cache: ModelListCache,
+    /// Recent request outcomes of each model, by the model name clients used
+    model_outcomes: Mutex<HashMap<String, OutcomeWindow>>,
+    model_stats_window: Duration,
+}
+
+/// Request slot of a client IP, released when dropped
+pub struct ConnectionGuard {
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(active) = connections.get_mut(&self.ip) {
+            *active -= 1;
+            if *active == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Summary of one proxied request; never includes request or response bodies
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestLogEntry {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    pub model: String,
+    /// Host of the last provider attempted, if any
+    pub provider: Option<String>,
+    pub status: u16,
+    pub latency_ms: u64,
+    /// Redacted id of the client's API key
+    pub key_id: Option<String>,
+    /// Team the request is attributed to, when `cost_centers` is configured
+    pub cost_center: Option<String>,
+}
+
+impl AppState {
+    pub fn new(config: &Config) -> Self {
+        let request_log_size = config.request_log_size.unwrap_or(DEFAULT_REQUEST_LOG_SIZE);
+        AppState {
+            request_log: Mutex::new(VecDeque::with_capacity(request_log_size)),
+            request_log_size,
+            disabled_providers: Mutex::new(HashSet::new()),
+            model_buckets: Mutex::new(HashMap::new()),
+            provider_buckets: Mutex::new(HashMap::new()),
+            provider_rates: Mutex::new(HashMap::new()),
+            active_keys: Mutex::new(HashMap::new()),
+            circuit_breakers: Mutex::new(HashMap::new()),
+            circuit_policy: config.circuit_breaker_policy(),
+            provider_error_rates: Mutex::new(HashMap::new()),
+            provider_latencies: Mutex::new(HashMap::new()),
+            maintenance: AtomicBool::new(config.maintenance_mode),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::new(Metrics::default()),
+            coalescer: Coalescer::default(),
+            response_cache: ResponseCache::default(),
+            model_cache: ModelListCache::default(),
+            model_outcomes: Mutex::new(HashMap::new()),
+            model_stats_window: Duration::from_secs(config.model_stats_window_secs.unwrap_or(DEFAULT_MODEL_STATS_WINDOW_SECS)),
+        }
+    }
+
+    /// Append a request to the ring buffer, dropping the oldest entry when full
+    pub fn record_request(&self, entry: RequestLogEntry) {
+        if self.request_log_size == 0 {
+            return;
+        }
+
+        let mut log = self.request_log.lock().unwrap();
+        if log.len() == self.request_log_size {
+            log.pop_front();
+        }
+        log.push_back(entry);
+    }
+
+    /// Take a provider out of, or back into, routing and the model catalog
+    pub fn set_provider_enabled(&self, name: &str, enabled: bool) {
+        let mut disabled = self.disabled_providers.lock().unwrap();
+        if enabled {
+            disabled.remove(name);
+        } else {
+            disabled.insert(name.to_string());
+        }
+    }
+
+    pub fn is_provider_enabled(&self, name: &str) -> bool {
+        !self.disabled_providers.lock().unwrap().contains(name)
+    }
+
+    /// The configuration to route with, leaving out providers disabled at runtime
+    pub fn routable_config<'a>(&self, config: &'a Config) -> Cow<'a, Config> {
+        let disabled = self.disabled_providers.lock().unwrap();
+        if disabled.is_empty() {
+            return Cow::Borrowed(config);
+        }
+        Cow::Owned(config.with_providers(|provider| !disabled.contains(&provider.name())))
+    }
+
+    /// Drop the runtime state of providers a reloaded configuration no longer names
+    /// Rate limit buckets are dropped for every provider, so a changed `max_rps` applies right away
+    pub fn forget_removed_providers(&self, config: &Config) {
+        let names: HashSet<String> = config.providers.iter().map(|provider| provider.name()).collect();
+        self.disabled_providers.lock().unwrap().retain(|name| names.contains(name));
+        self.provider_buckets.lock().unwrap().clear();
+        self.provider_rates.lock().unwrap().retain(|name, _| names.contains(name));
+        self.active_keys.lock().unwrap().retain(|name, _| names.contains(name));
+        self.circuit_breakers.lock().unwrap().retain(|name, _| names.contains(name));
+        self.provider_error_rates.lock().unwrap().retain(|name, _| names.contains(name));
+        self.provider_latencies.lock().unwrap().retain(|name, _| names.contains(name));
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maintenance(&self, enabled: bool) {
+        self.maintenance.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Take one of the `max` concurrent request slots of a client IP
+    /// None when the client already has `max` requests in flight
+    pub fn acquire_connection(&self, ip: IpAddr, max: usize) -> Option<ConnectionGuard> {
+        let mut connections = self.connections.lock().unwrap();
+        let active = connections.entry(ip).or_default();
+        if *active >= max {
+            return None;
+        }
+        *active += 1;
+        Some(ConnectionGuard { connections: self.connections.clone(), ip })
+    }
+
+    /// Take a request slot under the model's configured per-minute limit
+    /// Returns how long until a slot frees up when the limit is exhausted
+    pub fn acquire_model_slot(&self, config: &Config, model: &str) -> Result<(), Duration> {
+        let Some(limit) = config.model_rate_limits.get(model) else {
+            return Ok(());
+        };
+        let mut buckets = self.model_buckets.lock().unwrap();
+        buckets.entry(model.to_string())
+            .or_insert_with(|| TokenBucket::per_minute(*limit))
+            .try_acquire()
+    }
+
+    /// Take a request slot under the provider's `max_rps`, counting the request towards its current rate
+    /// Returns how long until a slot frees up when the provider is at its limit
+    pub fn acquire_provider_slot(&self, provider: &Provider) -> Result<(), Duration> {
+        let name = provider.name();
+        if let Some(max_rps) = provider.max_rps {
+            let mut buckets = self.provider_buckets.lock().unwrap();
+            buckets.entry(name.clone())
+                .or_insert_with(|| TokenBucket::per_second(max_rps))
+                .try_acquire()?;
+        }
+        self.provider_rates.lock().unwrap().entry(name).or_default().record();
+        Ok(())
+    }
+
+    /// Requests sent to the provider over the last second
+    pub fn provider_rate(&self, name: &str) -> usize {
+        self.provider_rates.lock().unwrap().get_mut(name).map_or(0, |meter| meter.per_second())
+    }
+
+    /// Index into the provider's keys of the one to authorize with
+    pub fn active_key_index(&self, provider: &Provider) -> usize {
+        let index = self.active_keys.lock().unwrap().get(&provider.name()).copied().unwrap_or(0);
+        index % provider.keys().len()
+    }
+
+    /// Make a key the one to authorize with, after it succeeded where the active key got a 401
+    pub fn promote_key(&self, provider: &Provider, index: usize) {
+        self.active_keys.lock().unwrap().insert(provider.name(), index);
+    }
+
+    /// Feed the outcome of an attempt into the provider's error rate and circuit breaker, when configured
+    pub fn record_provider_outcome(&self, config: &Config, provider: &str, success: bool) {
+        if config.health_weighting {
+            let decay = config.health_decay();
+            let mut rates = self.provider_error_rates.lock().unwrap();
+            let rate = rates.entry(provider.to_string()).or_insert(0.0);
+            let outcome = if success { 0.0 } else { 1.0 };
+            *rate = decay * outcome + (1.0 - decay) * *rate;
+        }
+
+        let Some(policy) = &self.circuit_policy else {
+            return;
+        };
+        let mut breakers = self.circuit_breakers.lock().unwrap();
+        let breaker = breakers.entry(provider.to_string()).or_default();
+        let before = breaker.state(policy);
+        if success {
+            breaker.record_success(policy);
+        } else {
+            breaker.record_failure(policy);
+        }
+        let after = breaker.state(policy);
+        if before != after {
+            println!("Circuit breaker of provider {} is now {}", provider, after.as_str());
+        }
+    }
+
+    /// Let an attempt through to the provider, taking a trial request slot when its breaker is half-open
+    /// Returns false when the half-open breaker has no slot left
+    pub fn claim_circuit_probe(&self, provider: &str) -> bool {
+        let Some(policy) = &self.circuit_policy else {
+            return true;
+        };
+        self.circuit_breakers.lock().unwrap().get_mut(provider).is_none_or(|breaker| breaker.claim_probe(policy))
+    }
+
+    /// Where the provider's circuit breaker stands, None when the breaker is disabled
+    pub fn circuit_state(&self, provider: &str) -> Option<BreakerState> {
+        let policy = self.circuit_policy.as_ref()?;
+        Some(self.circuit_breakers.lock().unwrap().get(provider).map_or(BreakerState::Closed, |breaker| breaker.state(policy)))
+    }
+
+    /// Feed the time a provider took to respond into its smoothed latency, when blend routing is on
+    pub fn record_provider_latency(&self, config: &Config, provider: &str, latency: Duration) {
+        if config.routing != RoutingMode::Blend {
+            return;
+        }
+        let mut latencies = self.provider_latencies.lock().unwrap();
+        let sample = latency.as_secs_f64();
+        latencies.entry(provider.to_string())
+            .and_modify(|smoothed| *smoothed = LATENCY_DECAY * sample + (1.0 - LATENCY_DECAY) * *smoothed)
+            .or_insert(sample);
+    }
+
+    /// Smoothed recent latency of the provider, None before any response was timed
+    pub fn provider_latency(&self, provider: &str) -> Option<Duration> {
+        self.provider_latencies.lock().unwrap().get(provider).map(|secs| Duration::from_secs_f64(*secs))
+    }
+
+    /// Share of its configured weight the provider currently gets, 1.0 when healthy
+    pub fn health_factor(&self, config: &Config, provider: &str) -> f64 {
+        if !config.health_weighting {
+            return 1.0;
+        }
+        let rate = self.provider_error_rates.lock().unwrap().get(provider).copied().unwrap_or(0.0);
+        (1.0 - rate).max(MIN_HEALTH_FACTOR)
+    }
+
+    /// How long the provider's circuit breaker stays open, None when it can take requests
+    pub fn circuit_open_for(&self, provider: &str) -> Option<Duration> {
+        let policy = self.circuit_policy.as_ref()?;
+        self.circuit_breakers.lock().unwrap().get(provider).and_then(|breaker| breaker.open_for(policy))
+    }
+
+    /// Count a finished request towards its model's success rate
+    pub fn record_model_outcome(&self, model: &str, success: bool) {
+        let mut outcomes = self.model_outcomes.lock().unwrap();
+        outcomes.entry(model.to_string()).or_default().record(success, self.model_stats_window);
+    }
+
+    /// Successes and failures of each model within the stats window, by model name
+    /// Models without requests in the window are dropped
+    pub fn model_outcomes(&self) -> Vec<(String, u64, u64)> {
+        let mut outcomes = self.model_outcomes.lock().unwrap();
+        let mut counts = Vec::new();
+        outcomes.retain(|model, window| {
+            let (successes, failures) = window.counts(self.model_stats_window);
+            if successes + failures == 0 {
+                return false;
+            }
+            counts.push((model.clone(), successes, failures));
+            true
+        });
+        counts.sort();
+        counts
+    }
+
+    pub fn model_stats_window(&self) -> Duration {
+        self.model_stats_window
+    }
+
+    /// Recent requests, newest first
+    pub fn recent_requests(&self) -> Vec<RequestLogEntry> {
+        self.request_log.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// Current time as a Unix timestamp in seconds
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).expect("test config should parse")
+    }
+
+    #[test]
+    fn health_factor_decays_on_errors_and_recovers() {
+        let config = config(r#"
+            health_weighting = true
+            health_decay = 0.5
+            providers = []
+        "#);
+        let state = AppState::new(&config);
+        assert_eq!(state.health_factor(&config, "a"), 1.0);
+
+        state.record_provider_outcome(&config, "a", false);
+        assert_eq!(state.health_factor(&config, "a"), 0.5);
+        state.record_provider_outcome(&config, "a", false);
+        assert_eq!(state.health_factor(&config, "a"), 0.25);
+        state.record_provider_outcome(&config, "a", true);
+        assert_eq!(state.health_factor(&config, "a"), 0.625);
+        assert_eq!(state.health_factor(&config, "b"), 1.0);
+
+        for _ in 0..20 {
+            state.record_provider_outcome(&config, "a", false);
+        }
+        assert_eq!(state.health_factor(&config, "a"), MIN_HEALTH_FACTOR);
+    }
+
+    #[test]
+    fn health_factor_is_flat_without_health_weighting() {
+        let config = config("providers = []");
+        let state = AppState::new(&config);
+        state.record_provider_outcome(&config, "a", false);
+        assert_eq!(state.health_factor(&config, "a"), 1.0);
+    }
+}
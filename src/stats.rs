@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Width of the buckets outcomes are counted in
+const BUCKET_WIDTH: Duration = Duration::from_secs(1);
+
+/// Successes and failures counted during one bucket
+struct Bucket {
+    start: Instant,
+    successes: u64,
+    failures: u64,
+}
+
+/// Request outcomes over a sliding window, counted in one-second buckets
+#[derive(Default)]
+pub struct OutcomeWindow {
+    buckets: VecDeque<Bucket>,
+}
+
+impl OutcomeWindow {
+    pub fn record(&mut self, success: bool, window: Duration) {
+        self.prune(window);
+        let now = Instant::now();
+        if self.buckets.back().is_none_or(|bucket| now.duration_since(bucket.start) >= BUCKET_WIDTH) {
+            self.buckets.push_back(Bucket { start: now, successes: 0, failures: 0 });
+        }
+        let bucket = self.buckets.back_mut().expect("a bucket was just ensured");
+        if success {
+            bucket.successes += 1;
+        } else {
+            bucket.failures += 1;
+        }
+    }
+
+    /// Successes and failures within the window
+    pub fn counts(&mut self, window: Duration) -> (u64, u64) {
+        self.prune(window);
+        self.buckets.iter().fold((0, 0), |(successes, failures), bucket| {
+            (successes + bucket.successes, failures + bucket.failures)
+        })
+    }
+
+    /// Drop buckets that started before the window
+    fn prune(&mut self, window: Duration) {
+        while self.buckets.front().is_some_and(|bucket| bucket.start.elapsed() > window) {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_are_counted_until_they_leave_the_window() {
+        let window = Duration::from_millis(100);
+        let mut outcomes = OutcomeWindow::default();
+        for success in [true, false, true, true] {
+            outcomes.record(success, window);
+        }
+        assert_eq!(outcomes.counts(window), (3, 1));
+        // The same outcomes are still inside a longer window
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(outcomes.counts(Duration::from_secs(60)), (3, 1));
+        assert_eq!(outcomes.counts(window), (0, 0));
+    }
+}
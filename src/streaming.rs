@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+
+use crate::functions;
+
+type UpstreamStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>>>>;
+
+/// SSE comment sent to keep idle connections open
+const HEARTBEAT: &[u8] = b": keep-alive\n\n";
+
+/// Adjustments applied to a streamed response on its way to the client
+#[derive(Default)]
+pub struct StreamOptions {
+    /// Passing this instant ends the stream with a terminal SSE error event
+    pub deadline: Option<Instant>,
+    /// Rewrite the `model` field of every JSON data frame to this name
+    pub rewrite_model: Option<String>,
+    /// The upstream streams newline-delimited JSON, to be framed as SSE events
+    pub translate_ndjson: bool,
+    /// Called with the number of bytes sent to the client once the stream ends or is dropped
+    pub on_end: Option<Box<dyn FnOnce(u64)>>,
+    /// End a stream that closes without any content with a terminal SSE error event instead of `[DONE]`
+    pub fail_empty: bool,
+    /// Drop SSE `event:` lines so only `data:` frames reach the client
+    pub strip_events: bool,
+    /// New names of SSE events, by upstream event name
+    pub rename_events: HashMap<String, String>,
+    /// Bytes the upstream may send before the stream is ended with a terminal SSE error event
+    pub max_bytes: Option<u64>,
+    /// Send an SSE comment after this long without upstream data, between events only
+    pub heartbeat: Option<Duration>,
+    /// Called once the first content line arrives from the upstream
+    pub on_first_content: Option<Box<dyn FnOnce()>>,
+    /// Rewrite legacy `function_call` deltas into `tool_calls` deltas
+    pub legacy_functions: bool,
+}
+
+impl StreamOptions {
+    /// Whether the stream has to be split into lines instead of forwarded chunk by chunk
+    fn transforms_lines(&self) -> bool {
+        self.rewrite_model.is_some() || self.translate_ndjson || self.fail_empty || self.legacy_functions
+            || self.strip_events || !self.rename_events.is_empty()
+    }
+}
+
+/// State carried between chunks of a streamed response
+struct PassthroughState {
+    upstream: UpstreamStream,
+    options: StreamOptions,
+    /// Bytes of an incomplete line held back until the rest of it arrives
+    pending: Vec<u8>,
+    finished: bool,
+    bytes_sent: u64,
+    /// Last bytes sent to the client, to tell whether they end an event
+    tail: Vec<u8>,
+    /// Whether the `[DONE]` marker has been sent, after which nothing else may follow
+    done_sent: bool,
+    /// Bytes received from the upstream so far
+    bytes_received: u64,
+    /// Whether any content line has been seen, as opposed to only blank lines, comments or `[DONE]`
+    saw_content: bool,
+}
+
+impl PassthroughState {
+    /// Count bytes on their way to the client
+    fn emit(&mut self, bytes: Bytes) -> Bytes {
+        self.bytes_sent += bytes.len() as u64;
+        self.tail.extend_from_slice(&bytes[bytes.len().saturating_sub(4)..]);
+        self.tail.drain(..self.tail.len().saturating_sub(4));
+        if bytes.windows(12).any(|window| window == b"data: [DONE]") {
+            self.done_sent = true;
+        }
+        bytes
+    }
+
+    /// Whether a heartbeat can be sent now without landing inside an event or after `[DONE]`
+    fn can_heartbeat(&self) -> bool {
+        !self.done_sent && self.at_event_boundary()
+    }
+
+    /// Whether everything sent so far ends with a complete event
+    fn at_event_boundary(&self) -> bool {
+        self.tail.is_empty() || self.tail.ends_with(b"\n\n") || self.tail.ends_with(b"\r\n\r\n")
+    }
+
+    /// Split off the complete lines of `chunk` and apply the line transforms to them
+    fn transform_chunk(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        let complete = match self.pending.iter().rposition(|b| *b == b'\n') {
+            Some(end) => self.pending.drain(..=end).collect::<Vec<u8>>(),
+            None => return Bytes::new(),
+        };
+        self.transform_lines(&complete)
+    }
+
+    /// Apply the line transforms to whatever is left once the upstream has finished
+    fn flush(&mut self) -> Bytes {
+        let rest = std::mem::take(&mut self.pending);
+        let mut output = self.transform_lines(&rest).to_vec();
+        if self.options.fail_empty && !self.saw_content {
+            eprintln!("Upstream stream ended without producing any content");
+            output.extend_from_slice(&sse_error_event("Upstream stream ended without producing any content", "upstream_error"));
+        } else if self.options.translate_ndjson {
+            // NDJSON has no end marker of its own
+            output.extend_from_slice(b"data: [DONE]\n\n");
+        }
+        Bytes::from(output)
+    }
+
+    fn transform_lines(&mut self, bytes: &[u8]) -> Bytes {
+        let text = String::from_utf8_lossy(bytes);
+        let mut output = String::with_capacity(text.len());
+        for line in text.split_inclusive('\n') {
+            let (content, ending) = match line.strip_suffix('\n') {
+                Some(content) => (content.trim_end_matches('\r'), "\n"),
+                None => (line, ""),
+            };
+            if is_content_line(content, self.options.translate_ndjson) {
+                self.saw_content = true;
+            }
+            // An empty stream ends with the error event instead of its own end marker
+            if self.options.fail_empty && !self.saw_content && is_done_line(content) {
+                continue;
+            }
+
+            // Each NDJSON line becomes one SSE event; blank lines carry nothing
+            if self.options.translate_ndjson {
+                if !content.trim().is_empty() {
+                    let payload = self.rewrite_payload(content).unwrap_or_else(|| content.to_string());
+                    output.push_str(&format!("data: {}\n\n", payload));
+                }
+                continue;
+            }
+
+            if let Some(event) = content.strip_prefix("event:") {
+                if self.options.strip_events {
+                    continue;
+                }
+                if let Some(renamed) = self.options.rename_events.get(event.trim()) {
+                    output.push_str(&format!("event: {}{}", renamed, ending));
+                    continue;
+                }
+            }
+
+            match self.rewrite_data_line(content) {
+                Some(rewritten) => output.push_str(&rewritten),
+                None => output.push_str(content),
+            }
+            output.push_str(ending);
+        }
+        Bytes::from(output)
+    }
+
+    /// Rewrite a `data:` line carrying a JSON chunk, or None to keep the line unchanged
+    fn rewrite_data_line(&self, line: &str) -> Option<String> {
+        let payload = line.strip_prefix("data:")?.trim_start();
+        self.rewrite_payload(payload).map(|chunk| format!("data: {}", chunk))
+    }
+
+    /// Rewrite the `model` field and legacy function calls of a JSON chunk, or None when there is nothing to rewrite
+    fn rewrite_payload(&self, payload: &str) -> Option<String> {
+        if self.options.rewrite_model.is_none() && !self.options.legacy_functions {
+            return None;
+        }
+        let mut chunk: Value = serde_json::from_str(payload).ok()?;
+        let mut changed = false;
+        if let Some(model) = self.options.rewrite_model.as_deref()
+            && chunk.get("model").is_some()
+        {
+            chunk["model"] = json!(model);
+            changed = true;
+        }
+        if self.options.legacy_functions {
+            changed |= functions::from_legacy_chunk(&mut chunk);
+        }
+        changed.then(|| chunk.to_string())
+    }
+}
+
+impl Drop for PassthroughState {
+    fn drop(&mut self) {
+        // Clients that disconnect early drop the stream without reaching its end
+        if let Some(on_end) = self.options.on_end.take() {
+            on_end(self.bytes_sent);
+        }
+    }
+}
+
+/// Pass an upstream streaming body through to the client, applying `options` along the way
+pub fn passthrough(
+    upstream: impl Stream<Item = reqwest::Result<Bytes>> + 'static,
+    options: StreamOptions,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let state = PassthroughState {
+        upstream: Box::pin(upstream),
+        options,
+        pending: Vec::new(),
+        finished: false,
+        bytes_sent: 0,
+        tail: Vec::new(),
+        done_sent: false,
+        bytes_received: 0,
+        saw_content: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if state.finished {
+                return None;
+            }
+
+            // Quiet periods are bounded by the next heartbeat as well as the deadline
+            let heartbeat_at = state.options.heartbeat.map(|interval| Instant::now() + interval);
+            let wait_until = match (state.options.deadline, heartbeat_at) {
+                (Some(deadline), Some(heartbeat_at)) => Some(deadline.min(heartbeat_at)),
+                (deadline, heartbeat_at) => deadline.or(heartbeat_at),
+            };
+            let next = match wait_until {
+                Some(wait_until) => match tokio::time::timeout_at(wait_until, state.upstream.next()).await {
+                    Ok(next) => next,
+                    Err(_) if state.options.deadline.is_none_or(|deadline| Instant::now() < deadline) => {
+                        if state.can_heartbeat() {
+                            let heartbeat = state.emit(Bytes::from_static(HEARTBEAT));
+                            return Some((Ok(heartbeat), state));
+                        }
+                        continue;
+                    }
+                    Err(_) => {
+                        eprintln!("Stream exceeded the configured duration, ending it");
+                        state.finished = true;
+                        let event = state.emit(sse_error_event("Stream exceeded the configured duration", "timeout_error"));
+                        return Some((Ok(event), state));
+                    }
+                },
+                None => state.upstream.next().await,
+            };
+
+            if let Some(Ok(chunk)) = &next {
+                state.bytes_received += chunk.len() as u64;
+                let ndjson = state.options.translate_ndjson;
+                if let Some(on_first_content) = state.options.on_first_content.take_if(|_| has_content_line(chunk, ndjson)) {
+                    on_first_content();
+                }
+                if state.options.max_bytes.is_some_and(|max| state.bytes_received > max) {
+                    eprintln!("Stream exceeded the provider's response size limit, ending it");
+                    state.finished = true;
+                    let event = state.emit(sse_error_event("Response exceeded the provider's size limit", "upstream_error"));
+                    return Some((Ok(event), state));
+                }
+            }
+
+            match next {
+                Some(Ok(chunk)) if !state.options.transforms_lines() => {
+                    let chunk = state.emit(chunk);
+                    return Some((Ok(chunk), state));
+                }
+                Some(Ok(chunk)) => {
+                    let transformed = state.transform_chunk(&chunk);
+                    // Wait for more data while only part of a line has arrived
+                    if !transformed.is_empty() {
+                        let transformed = state.emit(transformed);
+                        return Some((Ok(transformed), state));
+                    }
+                }
+                Some(Err(e)) => {
+                    eprintln!("Upstream stream failed mid-way, ending it: {}", e);
+                    state.finished = true;
+                    if state.done_sent {
+                        return None;
+                    }
+                    // Whatever part of a line was held back or already sent is cut off by a blank line first
+                    state.pending.clear();
+                    let mut event = if state.at_event_boundary() { Vec::new() } else { b"\n\n".to_vec() };
+                    event.extend_from_slice(&sse_error_event("Upstream connection was lost mid-stream", "upstream_error"));
+                    let event = state.emit(Bytes::from(event));
+                    return Some((Ok(event), state));
+                }
+                None => {
+                    state.finished = true;
+                    let rest = state.flush();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    let rest = state.emit(rest);
+                    return Some((Ok(rest), state));
+                }
+            }
+        }
+    })
+}
+
+/// Wait until the upstream produces its first content line, before anything is sent to the client
+/// Returns the stream with the chunks read so far put back in front, or None if it closed empty
+/// Errors and the deadline end the wait early and are left for the passthrough to report
+pub async fn await_first_content(
+    upstream: impl Stream<Item = reqwest::Result<Bytes>> + 'static,
+    ndjson: bool,
+    deadline: Option<Instant>,
+) -> Option<UpstreamStream> {
+    let mut upstream: UpstreamStream = Box::pin(upstream);
+    let mut buffered = Vec::new();
+    let mut seen = Vec::new();
+    loop {
+        let next = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, upstream.next()).await {
+                Ok(next) => next,
+                Err(_) => break,
+            },
+            None => upstream.next().await,
+        };
+        match next {
+            Some(Ok(chunk)) => {
+                seen.extend_from_slice(&chunk);
+                buffered.push(Ok(chunk));
+                // Only complete lines tell whether content arrived
+                let complete = seen.iter().rposition(|b| *b == b'\n').map_or(&seen[..0], |end| &seen[..end]);
+                if String::from_utf8_lossy(complete).lines().any(|line| is_content_line(line.trim_end_matches('\r'), ndjson)) {
+                    break;
+                }
+            }
+            Some(Err(e)) => {
+                buffered.push(Err(e));
+                break;
+            }
+            None if String::from_utf8_lossy(&seen).lines().any(|line| is_content_line(line.trim_end_matches('\r'), ndjson)) => break,
+            None => return None,
+        }
+    }
+    Some(Box::pin(stream::iter(buffered).chain(upstream)))
+}
+
+/// Assemble a buffered stream of completion chunks into the single response a non-streaming client expects
+/// Content, tool call arguments and legacy function call arguments are concatenated per choice
+/// Returns None when the body holds no JSON chunk at all
+pub fn collect_completion(body: &[u8], ndjson: bool) -> Option<Value> {
+    let text = String::from_utf8_lossy(body);
+    let chunks: Vec<Value> = text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| is_content_line(line, ndjson))
+        .filter_map(|line| serde_json::from_str(line.strip_prefix("data:").unwrap_or(line).trim()).ok())
+        .collect();
+    let first = chunks.first()?;
+
+    let mut completion = serde_json::Map::new();
+    for field in ["id", "created", "model", "system_fingerprint"] {
+        if let Some(value) = first.get(field) {
+            completion.insert(field.to_string(), value.clone());
+        }
+    }
+    let mut choices: Vec<Value> = Vec::new();
+    let mut is_text = false;
+    for chunk in &chunks {
+        if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+            completion.insert("usage".to_string(), usage.clone());
+        }
+        for delta_choice in chunk.get("choices").and_then(|c| c.as_array()).into_iter().flatten() {
+            let index = delta_choice.get("index").and_then(|i| i.as_u64()).unwrap_or(0) as usize;
+            while choices.len() <= index {
+                choices.push(json!({"index": choices.len(), "message": {"role": "assistant", "content": null}, "finish_reason": null}));
+            }
+            let choice = &mut choices[index];
+            if let Some(reason) = delta_choice.get("finish_reason").filter(|r| !r.is_null()) {
+                choice["finish_reason"] = reason.clone();
+            }
+            // Legacy completions stream `text` instead of a delta
+            if let Some(text) = delta_choice.get("text").and_then(|t| t.as_str()) {
+                is_text = true;
+                append_text(&mut choice["text"], text);
+            }
+            let Some(delta) = delta_choice.get("delta") else {
+                continue;
+            };
+            let message = &mut choice["message"];
+            if let Some(role) = delta.get("role").filter(|r| r.is_string()) {
+                message["role"] = role.clone();
+            }
+            if let Some(content) = delta.get("content").and_then(|c| c.as_str()) {
+                append_text(&mut message["content"], content);
+            }
+            if let Some(call) = delta.get("function_call") {
+                merge_call(&mut message["function_call"], call);
+            }
+            for call in delta.get("tool_calls").and_then(|c| c.as_array()).into_iter().flatten() {
+                let call_index = call.get("index").and_then(|i| i.as_u64()).unwrap_or(0) as usize;
+                if !message["tool_calls"].is_array() {
+                    message["tool_calls"] = json!([]);
+                }
+                let calls = message["tool_calls"].as_array_mut().expect("tool_calls is an array");
+                while calls.len() <= call_index {
+                    calls.push(json!({"id": null, "type": "function", "function": {"name": null, "arguments": null}}));
+                }
+                let merged = &mut calls[call_index];
+                for field in ["id", "type"] {
+                    if let Some(value) = call.get(field).filter(|v| !v.is_null()) {
+                        merged[field] = value.clone();
+                    }
+                }
+                if let Some(function) = call.get("function") {
+                    merge_call(&mut merged["function"], function);
+                }
+            }
+        }
+    }
+    if is_text {
+        for choice in &mut choices {
+            if let Some(fields) = choice.as_object_mut() {
+                fields.remove("message");
+            }
+        }
+    }
+    completion.insert("object".to_string(), json!(if is_text { "text_completion" } else { "chat.completion" }));
+    completion.insert("choices".to_string(), Value::Array(choices));
+    Some(Value::Object(completion))
+}
+
+/// Frame a complete response as the event stream a streaming client expects, ending with `[DONE]`
+pub fn completion_to_sse(completion: &Value) -> Bytes {
+    let mut chunk = completion.clone();
+    if chunk.get("object").and_then(|o| o.as_str()) == Some("chat.completion") {
+        chunk["object"] = json!("chat.completion.chunk");
+    }
+    for choice in chunk.get_mut("choices").and_then(|c| c.as_array_mut()).into_iter().flatten() {
+        let Some(fields) = choice.as_object_mut() else {
+            continue;
+        };
+        let Some(mut delta) = fields.remove("message") else {
+            continue;
+        };
+        // Streamed tool calls are told apart by their index
+        for (i, call) in delta.get_mut("tool_calls").and_then(|c| c.as_array_mut()).into_iter().flatten().enumerate() {
+            call["index"] = json!(i);
+        }
+        fields.insert("delta".to_string(), delta);
+    }
+    Bytes::from(format!("data: {}\n\ndata: [DONE]\n\n", chunk))
+}
+
+/// Append a streamed piece of text to a string that may not exist yet
+fn append_text(target: &mut Value, text: &str) {
+    match target.as_str() {
+        Some(existing) => *target = json!(format!("{}{}", existing, text)),
+        None => *target = json!(text),
+    }
+}
+
+/// Merge a streamed function call delta, whose name comes once and whose arguments come in pieces
+fn merge_call(target: &mut Value, delta: &Value) {
+    if !target.is_object() {
+        *target = json!({"name": null, "arguments": null});
+    }
+    if let Some(name) = delta.get("name").filter(|n| n.is_string()) {
+        target["name"] = name.clone();
+    }
+    if let Some(arguments) = delta.get("arguments").and_then(|a| a.as_str()) {
+        append_text(&mut target["arguments"], arguments);
+    }
+}
+
+/// Whether a line carries stream content rather than framing: a `data:` frame other than `[DONE]`,
+/// or any non-blank NDJSON line
+fn is_content_line(line: &str, ndjson: bool) -> bool {
+    if ndjson {
+        return !line.trim().is_empty();
+    }
+    line.strip_prefix("data:").is_some_and(|payload| !payload.trim().is_empty() && !is_done_line(line))
+}
+
+/// Whether a chunk holds a content line, even one whose end is still to come
+fn has_content_line(chunk: &[u8], ndjson: bool) -> bool {
+    String::from_utf8_lossy(chunk).lines().any(|line| is_content_line(line.trim_end_matches('\r'), ndjson))
+}
+
+fn is_done_line(line: &str) -> bool {
+    line.strip_prefix("data:").is_some_and(|payload| payload.trim() == "[DONE]")
+}
+
+/// Format an OpenAI-style error object as an SSE data frame
+pub fn sse_error_event(message: &str, error_type: &str) -> Bytes {
+    let error = json!({
+        "error": {
+            "message": message,
+            "type": error_type
+        }
+    });
+    Bytes::from(format!("data: {}\n\n", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Everything `passthrough` sends the client for an upstream sending `chunks`
+    async fn relay(chunks: &[&str], options: StreamOptions) -> String {
+        let chunks: Vec<reqwest::Result<Bytes>> = chunks.iter().map(|chunk| Ok(Bytes::from(chunk.to_string()))).collect();
+        let output: Vec<_> = passthrough(stream::iter(chunks), options).collect().await;
+        output.into_iter().map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap()).collect()
+    }
+
+    #[actix_web::test]
+    async fn ndjson_lines_become_sse_events() {
+        let options = StreamOptions { translate_ndjson: true, ..Default::default() };
+        let output = relay(&["{\"id\":1}\n{\"i", "d\":2}\n"], options).await;
+        assert_eq!(output, "data: {\"id\":1}\n\ndata: {\"id\":2}\n\ndata: [DONE]\n\n");
+    }
+
+    #[actix_web::test]
+    async fn empty_streams_end_with_an_error_event() {
+        let options = || StreamOptions { fail_empty: true, ..Default::default() };
+        let output = relay(&[": keep-alive\n\n", "data: [DONE]\n\n"], options()).await;
+        assert!(output.contains("upstream_error"), "unexpected stream {}", output);
+        assert!(!output.contains("[DONE]"));
+
+        let output = relay(&["data: {\"id\":1}\n\n", "data: [DONE]\n\n"], options()).await;
+        assert_eq!(output, "data: {\"id\":1}\n\ndata: [DONE]\n\n");
+    }
+
+    #[actix_web::test]
+    async fn named_events_are_stripped_or_renamed() {
+        let upstream = ["event: completion\ndata: {\"id\":1}\n\n", "event: ping\ndata: {}\n\n", "data: [DONE]\n\n"];
+
+        let options = StreamOptions { strip_events: true, ..Default::default() };
+        let output = relay(&upstream, options).await;
+        assert_eq!(output, "data: {\"id\":1}\n\ndata: {}\n\ndata: [DONE]\n\n");
+
+        let rename_events = HashMap::from([("completion".to_string(), "message".to_string())]);
+        let options = StreamOptions { rename_events, ..Default::default() };
+        let output = relay(&upstream, options).await;
+        assert_eq!(output, "event: message\ndata: {\"id\":1}\n\nevent: ping\ndata: {}\n\ndata: [DONE]\n\n");
+    }
+
+    /// Everything `passthrough` sends the client for an upstream sending each chunk after its delay
+    async fn relay_slowly(chunks: &[(u64, &str)], options: StreamOptions) -> String {
+        let chunks: Vec<(u64, String)> = chunks.iter().map(|(delay, chunk)| (*delay, chunk.to_string())).collect();
+        let upstream = stream::iter(chunks).then(|(delay, chunk)| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Ok(Bytes::from(chunk))
+        });
+        let output: Vec<_> = passthrough(upstream, options).collect().await;
+        output.into_iter().map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap()).collect()
+    }
+
+    #[actix_web::test]
+    async fn heartbeats_fill_quiet_periods_between_events() {
+        let options = || StreamOptions { heartbeat: Some(Duration::from_millis(40)), ..Default::default() };
+        let output = relay_slowly(&[(0, "data: {\"id\":1}\n\n"), (150, "data: {\"id\":2}\n\n"), (0, "data: [DONE]\n\n")], options()).await;
+        let heartbeats = output.matches(": keep-alive\n\n").count();
+        assert!(heartbeats >= 2, "unexpected stream {}", output);
+        let expected = format!("data: {{\"id\":1}}\n\n{}data: {{\"id\":2}}\n\ndata: [DONE]\n\n", ": keep-alive\n\n".repeat(heartbeats));
+        assert_eq!(output, expected);
+
+        // A quiet period inside an event gets no heartbeat
+        let output = relay_slowly(&[(0, "data: {\"id\":1}\n"), (150, "\ndata: [DONE]\n\n")], options()).await;
+        assert_eq!(output, "data: {\"id\":1}\n\ndata: [DONE]\n\n");
+    }
+
+    #[actix_web::test]
+    async fn upstream_drops_end_with_an_error_event() {
+        let dropped = reqwest::get("http://127.0.0.1:1").await.unwrap_err();
+        let upstream = vec![Ok(Bytes::from("data: {\"id\":1}\n\ndata: {\"i")), Err(dropped)];
+        let output: Vec<_> = passthrough(stream::iter(upstream), StreamOptions::default()).collect().await;
+        let output: String = output.into_iter().map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap()).collect();
+
+        let error = String::from_utf8(sse_error_event("Upstream connection was lost mid-stream", "upstream_error").to_vec()).unwrap();
+        // The partial line already relayed is cut off before the error event
+        assert_eq!(output, format!("data: {{\"id\":1}}\n\ndata: {{\"i\n\n{}", error));
+        assert!(!output.contains("[DONE]"));
+    }
+}
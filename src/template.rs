@@ -0,0 +1,98 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Variables that system prompt templates may use, as `{{name}}`
+pub const VARIABLES: &[&str] = &["date", "model", "cost_center", "client"];
+
+/// Values substituted into a system prompt template for one request
+pub struct PromptVariables<'a> {
+    /// Model the client asked for
+    pub model: &'a str,
+    /// Cost center of the request, when `cost_centers` is configured
+    pub cost_center: Option<&'a str>,
+    /// Redacted id of the client's API key
+    pub client: Option<&'a str>,
+}
+
+impl PromptVariables<'_> {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "date" => Some(utc_date()),
+            "model" => Some(self.model.to_string()),
+            "cost_center" => Some(self.cost_center.unwrap_or_default().to_string()),
+            "client" => Some(self.client.unwrap_or_default().to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Replace every `{{name}}` in `template` with its value
+/// Substituted values are never scanned again, and unknown names are left as written
+pub fn render(template: &str, variables: &PromptVariables) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}").and_then(|end| variables.get(after[..end].trim()).map(|value| (end, value))) {
+            Some((end, value)) => {
+                output.push_str(&value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                output.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Names between `{{` and `}}` in `template` that aren't known variables
+pub fn unknown_variables(template: &str) -> Vec<&str> {
+    template.split("{{")
+        .skip(1)
+        .filter_map(|part| part.split_once("}}").map(|(name, _)| name.trim()))
+        .filter(|name| !VARIABLES.contains(name))
+        .collect()
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`
+fn utc_date() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64 / 86_400;
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_are_substituted_once() {
+        let variables = PromptVariables { model: "m1", cost_center: Some("search"), client: Some("{{model}}") };
+        assert_eq!(render("{{model}} for {{ cost_center }} ({{client}})", &variables), "m1 for search ({{model}})");
+        assert_eq!(render("{{unknown}} {{model", &variables), "{{unknown}} {{model");
+
+        let variables = PromptVariables { model: "m1", cost_center: None, client: None };
+        assert_eq!(render("team [{{cost_center}}]", &variables), "team []");
+        let date = render("{{date}}", &variables);
+        assert_eq!(date.len(), 10, "{}", date);
+        assert!(date.chars().enumerate().all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() }), "{}", date);
+    }
+
+    #[test]
+    fn unknown_variables_are_reported() {
+        assert_eq!(unknown_variables("{{date}} {{ tenant }} {{model}} {{user}}"), ["tenant", "user"]);
+        assert!(unknown_variables("no variables").is_empty());
+    }
+}
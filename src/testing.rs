@@ -0,0 +1,197 @@
+//! Mock providers and a gateway wired like `main`, for the tests of each module
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::web::Bytes;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::reload::LiveConfig;
+use crate::state::AppState;
+
+/// API key the gateway's test client sends, accepted while no `server_api_key` is configured
+pub const CLIENT_KEY: &str = "client-key";
+
+/// A request received by a mock provider
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub path: String,
+    /// Header names are lowercase; repeated headers appear once per value
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl Recorded {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("mock provider received a JSON body")
+    }
+}
+
+type Respond = dyn Fn(&Recorded, usize) -> HttpResponse + Send + Sync;
+
+/// Upstream provider answering every request with `respond`, given the request and how many came before it
+pub struct MockProvider {
+    /// Base URL to configure the provider with, ending in `/v1`
+    pub url: String,
+    requests: Arc<Mutex<Vec<Recorded>>>,
+}
+
+impl MockProvider {
+    pub async fn start(respond: impl Fn(&Recorded, usize) -> HttpResponse + Send + Sync + 'static) -> Self {
+        Self::start_delayed(Duration::ZERO, respond).await
+    }
+
+    /// Like `start`, waiting `delay` before each response
+    pub async fn start_delayed(delay: Duration, respond: impl Fn(&Recorded, usize) -> HttpResponse + Send + Sync + 'static) -> Self {
+        let requests: Arc<Mutex<Vec<Recorded>>> = Arc::default();
+        let respond: Arc<Respond> = Arc::new(respond);
+        let recorded = requests.clone();
+        let server = HttpServer::new(move || {
+            let requests = recorded.clone();
+            let respond = respond.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, body: Bytes| {
+                let request = Recorded {
+                    path: req.path().to_string(),
+                    headers: req.headers().iter()
+                        .map(|(name, value)| (name.as_str().to_string(), value.to_str().unwrap_or_default().to_string()))
+                        .collect(),
+                    body,
+                };
+                let count = {
+                    let mut requests = requests.lock().unwrap();
+                    requests.push(request.clone());
+                    requests.len() - 1
+                };
+                let response = respond(&request, count);
+                async move {
+                    if !delay.is_zero() {
+                        tokio::time::sleep(delay).await;
+                    }
+                    response
+                }
+            }))
+        })
+        .workers(1)
+        .disable_signals()
+        .shutdown_timeout(0)
+        .bind(("127.0.0.1", 0))
+        .expect("mock provider should bind");
+        let url = format!("http://{}/v1", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        MockProvider { url, requests }
+    }
+
+    /// Requests received for `path`, e.g. `/v1/chat/completions`
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests.lock().unwrap().iter().filter(|request| request.path == path).count()
+    }
+
+    /// The last request received
+    pub fn last(&self) -> Recorded {
+        self.requests.lock().unwrap().last().cloned().expect("mock provider received a request")
+    }
+}
+
+/// Gateway serving `config` on a local port, with its live configuration and runtime state
+pub struct Gateway {
+    pub url: String,
+    client: reqwest::Client,
+}
+
+impl Gateway {
+    pub async fn start(config: &str) -> Self {
+        let config = Config::from_toml(config).expect("test config should be valid");
+        let json_body_limit = config.json_body_limit();
+        let stream_request_bodies = config.stream_request_bodies;
+        let state = web::Data::new(AppState::new(&config));
+        let live = web::Data::new(LiveConfig::new(config));
+        let (server_live, server_state) = (live.clone(), state.clone());
+        let server = HttpServer::new(move || crate::app(server_live.clone(), server_state.clone(), json_body_limit, stream_request_bodies))
+            .workers(1)
+            .disable_signals()
+            .shutdown_timeout(0)
+            .bind(("127.0.0.1", 0))
+            .expect("gateway should bind");
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::AUTHORIZATION, format!("Bearer {}", CLIENT_KEY).parse().unwrap());
+        // Redirects are part of what's under test, so the client leaves them to the test
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        Gateway { url, client }
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.get(format!("{}{}", self.url, path))
+    }
+
+    pub fn post(&self, path: &str, body: Value) -> reqwest::RequestBuilder {
+        self.client.post(format!("{}{}", self.url, path)).json(&body)
+    }
+
+    /// Send a one-message chat completion for `model`
+    pub async fn chat(&self, model: &str) -> reqwest::Response {
+        self.post("/v1/chat/completions", chat_request(model)).send().await.expect("gateway should answer")
+    }
+}
+
+/// Chat completion request for `model` with a single user message
+pub fn chat_request(model: &str) -> Value {
+    json!({"model": model, "messages": [{"role": "user", "content": "hi"}]})
+}
+
+/// Chat completion response from `model` with `content` and its usage
+pub fn completion(model: &str, content: &str) -> Value {
+    json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 1,
+        "model": model,
+        "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+        "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+    })
+}
+
+/// `200` with a chat completion from `model`
+pub fn ok_completion(model: &str, content: &str) -> HttpResponse {
+    HttpResponse::Ok().json(completion(model, content))
+}
+
+/// OpenAI-style error response with `status`
+pub fn error(status: u16, message: &str) -> HttpResponse {
+    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap())
+        .json(json!({"error": {"message": message, "type": "server_error"}}))
+}
+
+/// `/models` listing of `models`, each given as its JSON object
+pub fn model_list(models: &[Value]) -> HttpResponse {
+    HttpResponse::Ok().json(json!({"object": "list", "data": models}))
+}
+
+/// Server-sent events carrying `chunks`, ended with `[DONE]`
+pub fn sse(chunks: &[Value]) -> HttpResponse {
+    let mut body: String = chunks.iter().map(|chunk| format!("data: {}\n\n", chunk)).collect();
+    body.push_str("data: [DONE]\n\n");
+    HttpResponse::Ok().content_type("text/event-stream").body(body)
+}
+
+/// Streamed chat completion chunk from `model` with a `content` delta
+pub fn chunk(model: &str, content: &str) -> Value {
+    json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion.chunk",
+        "created": 1,
+        "model": model,
+        "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]
+    })
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::pki_types::pem::PemObject;
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+/// Start of the handshake error raised when a provider's certificate matches none of its pins
+const PIN_MISMATCH: &str = "certificate pin mismatch";
+
+/// Decode a `cert_pins` entry, the base64 SHA-256 of a certificate's SPKI with an optional `sha256/` prefix
+pub fn parse_pin(pin: &str) -> Option<[u8; 32]> {
+    let encoded = pin.strip_prefix("sha256/").unwrap_or(pin);
+    base64::engine::general_purpose::STANDARD.decode(encoded).ok()?.try_into().ok()
+}
+
+/// Rustls client configuration that only accepts server certificates whose SPKI hash is pinned
+/// The chain is still verified against the bundled roots plus `ca_cert_path`, unless `skip_chain` is set
+pub fn pinned_config(
+    provider_name: &str,
+    pins: &[String],
+    ca_pem: Option<&[u8]>,
+    skip_chain: bool,
+) -> Result<rustls::ClientConfig, Box<dyn std::error::Error>> {
+    let crypto = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = pinned_verifier(provider_name, pins, ca_pem, skip_chain, crypto.clone())?;
+    Ok(rustls::ClientConfig::builder_with_provider(crypto)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+fn pinned_verifier(
+    provider_name: &str,
+    pins: &[String],
+    ca_pem: Option<&[u8]>,
+    skip_chain: bool,
+    crypto: Arc<rustls::crypto::CryptoProvider>,
+) -> Result<PinnedVerifier, Box<dyn std::error::Error>> {
+    let pins = pins.iter()
+        .map(|pin| parse_pin(pin).ok_or_else(|| format!("Invalid cert_pins entry '{}' of provider {}", pin, provider_name)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(pem) = ca_pem {
+        for certificate in CertificateDer::pem_slice_iter(pem) {
+            roots.add(certificate?)?;
+        }
+    }
+
+    Ok(PinnedVerifier {
+        provider_name: provider_name.to_string(),
+        pins,
+        inner: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), crypto).build()?,
+        skip_chain,
+    })
+}
+
+/// The pin mismatch behind a failed request, if that is why it failed
+pub fn pin_mismatch(error: &reqwest::Error) -> Option<String> {
+    let mut source: Option<&dyn std::error::Error> = Some(error);
+    while let Some(error) = source {
+        let message = error.to_string();
+        if let Some(start) = message.find(PIN_MISMATCH) {
+            return Some(message[start..].to_string());
+        }
+        source = error.source();
+    }
+    None
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    provider_name: String,
+    pins: Vec<[u8; 32]>,
+    inner: Arc<WebPkiServerVerifier>,
+    skip_chain: bool,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.skip_chain {
+            self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        let certificate = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|e| rustls::Error::General(format!("invalid certificate from provider {}: {}", self.provider_name, e)))?;
+        let hash: [u8; 32] = Sha256::digest(certificate.subject_public_key_info().as_ref()).into();
+        if self.pins.contains(&hash) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "{} for provider {} (server key sha256/{})",
+                PIN_MISMATCH, self.provider_name, base64::engine::general_purpose::STANDARD.encode(hash)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed certificate for `localhost` whose SPKI hash is `SERVER_PIN`
+    const SERVER_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBgDCCASWgAwIBAgIUMgtweCifCuXUxDKkoYHMzDqqzkYwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxNDA3Mzg0NloYDzIxMjYwOTIw
+MDczODQ2WjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAARCqSk4gsDqQdt3lABmikFVF9kw+FxRMzXvDPLKrfxsbUSat+t5R/Ik
+eGxCWldAoSqxC/SfeC2Fq/HDNBKG9Rqjo1MwUTAdBgNVHQ4EFgQUGmCpqPrJeqQZ
+ur+TcnJnzEetGVAwHwYDVR0jBBgwFoAUGmCpqPrJeqQZur+TcnJnzEetGVAwDwYD
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEA5CHbS9+eG8Af+zGd3Eot
+kMZc6aux311AdNx+Z1izj/ECIQCA87OoPfFMafDztzXet9G0h6Uq+i9X3c/VLbmv
+2i8wxg==
+-----END CERTIFICATE-----
+";
+    const SERVER_PIN: &str = "sha256/1BKihHCOMl/b5JS/EYI7sVQT3MbJyg80zzQHLkQU/GM=";
+
+    /// Check the server certificate against `pins`, skipping the chain since it is self-signed
+    fn verify(pins: &[&str]) -> Result<ServerCertVerified, rustls::Error> {
+        let pins: Vec<String> = pins.iter().map(|pin| pin.to_string()).collect();
+        let crypto = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = pinned_verifier("p1", &pins, None, true, crypto).unwrap();
+        let certificate = CertificateDer::from_pem_slice(SERVER_CERT.as_bytes()).unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        verifier.verify_server_cert(&certificate, &[], &server_name, &[], UnixTime::now())
+    }
+
+    #[test]
+    fn certificates_must_match_a_pin() {
+        let other_pin = "sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let error = verify(&[other_pin]).unwrap_err().to_string();
+        assert!(error.contains(PIN_MISMATCH), "{}", error);
+        // The error names the key that was seen, for updating the pins after a rotation
+        assert!(error.contains(SERVER_PIN), "{}", error);
+
+        assert!(verify(&[other_pin, SERVER_PIN]).is_ok());
+    }
+
+    #[test]
+    fn malformed_pins_are_rejected() {
+        assert!(parse_pin("sha256/not-base64").is_none());
+        assert!(parse_pin("c2hvcnQ=").is_none());
+        let pins = vec!["sha256/short".to_string()];
+        let crypto = Arc::new(rustls::crypto::ring::default_provider());
+        assert!(pinned_verifier("p1", &pins, None, true, crypto).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use serde_json::{json, Value};
+
+/// Rough number of characters per token for English text with common tokenizers
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Estimate the token count of a piece of text
+pub fn estimate_text_tokens(text: &str) -> u64 {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+/// Estimate the prompt tokens of a chat completion request from its messages
+pub fn estimate_prompt_tokens(request: &Value) -> u64 {
+    request.get("messages")
+        .and_then(|m| m.as_array())
+        .map(|messages| {
+            messages.iter()
+                .filter_map(|message| message.get("content"))
+                .map(estimate_content_tokens)
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Estimate the tokens a chat completion request needs: its prompt plus the completion tokens it asks for at most
+pub fn estimate_request_tokens(request: &Value) -> u64 {
+    let completion = ["max_completion_tokens", "max_tokens"].iter()
+        .find_map(|field| request.get(*field).and_then(|v| v.as_u64()))
+        .unwrap_or(0);
+    estimate_prompt_tokens(request) + completion
+}
+
+/// Estimate the completion tokens of a chat completion (or legacy completion) response
+pub fn estimate_completion_tokens(response: &Value) -> u64 {
+    response.get("choices")
+        .and_then(|c| c.as_array())
+        .map(|choices| {
+            choices.iter()
+                .map(|choice| {
+                    let message = choice.get("message").and_then(|m| m.get("content"));
+                    let text = choice.get("text");
+                    message.or(text).map(estimate_content_tokens).unwrap_or(0)
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Look up a dot-path such as `usage.prompt_tokens` or `meta.0.tokens` in a JSON value
+pub fn value_at_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |current, segment| match current {
+        Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+        _ => current.get(segment),
+    })
+}
+
+/// Build a synthetic `usage` object for a response that lacks one
+pub fn estimate_usage(request: &Value, response: &Value) -> Value {
+    let prompt_tokens = estimate_prompt_tokens(request);
+    let completion_tokens = estimate_completion_tokens(response);
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+        "estimated": true
+    })
+}
+
+/// Whether any message carries content parts other than text, such as images
+pub fn has_non_text_content(request: &Value) -> bool {
+    request.get("messages")
+        .and_then(|m| m.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message.get("content").and_then(|c| c.as_array()))
+        .flatten()
+        .any(|part| part.get("type").and_then(|t| t.as_str()).is_some_and(|t| t != "text"))
+}
+
+/// Message content is either a string or an array of content parts; only text parts count
+fn estimate_content_tokens(content: &Value) -> u64 {
+    match content {
+        Value::String(text) => estimate_text_tokens(text),
+        Value::Array(parts) => parts.iter()
+            .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+            .map(estimate_text_tokens)
+            .sum(),
+        _ => 0,
+    }
+}